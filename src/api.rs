@@ -1,8 +1,26 @@
+use crate::config::ForwardingConfig;
+use crate::forwardings::{ForwardingError, Forwardings};
+use crate::health::{HealthReport, Status};
+use crate::sink::Sinks;
 use axum::{
-    http::{header::CONTENT_TYPE, HeaderMap, HeaderValue},
-    routing::get,
-    Router,
+    extract::{Path, State},
+    http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, StatusCode},
+    routing::{get, post},
+    Json, Router,
 };
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Clone)]
+struct ApiState {
+    forwardings: Arc<Forwardings>,
+    sinks: Sinks,
+    manage_forwardings: bool,
+}
+
+pub static DEFAULT_PORT: u16 = 8080;
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
 
 async fn root() -> &'static str {
     "mqtt-kafka-forwarding-service"
@@ -12,6 +30,22 @@ async fn health() -> &'static str {
     "OK"
 }
 
+async fn live() -> (StatusCode, Json<HealthReport>) {
+    health_response(crate::health::liveness())
+}
+
+async fn ready(State(state): State<ApiState>) -> (StatusCode, Json<HealthReport>) {
+    health_response(crate::health::readiness(&state.sinks).await)
+}
+
+fn health_response(report: HealthReport) -> (StatusCode, Json<HealthReport>) {
+    let status = match report.status {
+        Status::Up => StatusCode::OK,
+        Status::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(report))
+}
+
 async fn metrics() -> (HeaderMap, String) {
     let mut headers = HeaderMap::new();
     headers.insert(
@@ -22,15 +56,149 @@ async fn metrics() -> (HeaderMap, String) {
     (headers, metrics)
 }
 
-pub async fn api() {
+async fn list_forwardings(State(state): State<ApiState>) -> Json<Vec<ForwardingConfig>> {
+    Json(state.forwardings.list().await)
+}
+
+async fn get_forwarding(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> ApiResult<Json<ForwardingConfig>> {
+    state
+        .forwardings
+        .get(&name)
+        .await
+        .map(Json)
+        .ok_or_else(|| error_response(ForwardingError::NotFound(name)))
+}
+
+async fn create_forwarding(
+    State(state): State<ApiState>,
+    Json(config): Json<ForwardingConfig>,
+) -> ApiResult<StatusCode> {
+    check_manageable(&state)?;
+    log::info!("Creating forwarding {} via API", config.name);
+    state
+        .forwardings
+        .create(config)
+        .await
+        .map_err(error_response)?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn update_forwarding(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+    Json(config): Json<ForwardingConfig>,
+) -> ApiResult<StatusCode> {
+    check_manageable(&state)?;
+    log::info!("Updating forwarding {} via API", name);
+    state
+        .forwardings
+        .update(&name, config)
+        .await
+        .map_err(error_response)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_forwarding(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> ApiResult<StatusCode> {
+    check_manageable(&state)?;
+    log::info!("Deleting forwarding {} via API", name);
+    state
+        .forwardings
+        .delete(&name)
+        .await
+        .map_err(error_response)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn pause_forwarding(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> ApiResult<StatusCode> {
+    check_manageable(&state)?;
+    log::info!("Pausing forwarding {} via API", name);
+    state
+        .forwardings
+        .set_paused(&name, true)
+        .await
+        .map_err(error_response)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn resume_forwarding(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> ApiResult<StatusCode> {
+    check_manageable(&state)?;
+    log::info!("Resuming forwarding {} via API", name);
+    state
+        .forwardings
+        .set_paused(&name, false)
+        .await
+        .map_err(error_response)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn check_manageable(state: &ApiState) -> ApiResult<()> {
+    if state.manage_forwardings {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::FORBIDDEN,
+            "Managing forwardings via the API is disabled".to_string(),
+        ))
+    }
+}
+
+fn error_response(err: ForwardingError) -> (StatusCode, String) {
+    let status = match err {
+        ForwardingError::NotFound(_) => StatusCode::NOT_FOUND,
+        ForwardingError::AlreadyExists(_) => StatusCode::CONFLICT,
+        ForwardingError::Invalid(_) => StatusCode::BAD_REQUEST,
+        ForwardingError::Persist(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        ForwardingError::Subscribe(_) => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, err.to_string())
+}
+
+pub async fn api(
+    forwardings: Arc<Forwardings>,
+    sinks: Sinks,
+    address: SocketAddr,
+    manage_forwardings: bool,
+) {
+    let state = ApiState {
+        forwardings,
+        sinks,
+        manage_forwardings,
+    };
     let app = Router::new()
         .route("/", get(root))
         .route("/health", get(health))
-        .route("/metrics", get(metrics));
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+        .route("/metrics", get(metrics))
+        .route(
+            "/forwardings",
+            get(list_forwardings).post(create_forwarding),
+        )
+        .route(
+            "/forwardings/{name}",
+            get(get_forwarding)
+                .put(update_forwarding)
+                .delete(delete_forwarding),
+        )
+        .route("/forwardings/{name}/pause", post(pause_forwarding))
+        .route("/forwardings/{name}/resume", post(resume_forwarding))
+        .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080")
+    let listener = tokio::net::TcpListener::bind(address)
         .await
-        .expect("Could not listen on port 8080");
+        .unwrap_or_else(|err| panic!("Could not listen on {address}: {err}"));
     axum::serve(listener, app)
         .await
         .expect("Failed to start http api");
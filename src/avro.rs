@@ -0,0 +1,135 @@
+use crate::config::AvroConfig;
+use crate::schema_registry::{RegisteredSchema, SchemaRegistry};
+use apache_avro::types::Value;
+use apache_avro::{to_avro_datum, Schema};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// First byte of the Confluent wire format, followed by the schema id and the Avro datum
+static MAGIC_BYTE: u8 = 0;
+// A subject whose schema could not be fetched is retried after this delay, doubled with each failure
+static INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+static MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+enum CachedSchema {
+    Fetched(Arc<RegisteredSchema>),
+    Failed {
+        error: String,
+        backoff: Duration,
+        retry_at: Instant,
+    },
+}
+
+// Locked while the schema is fetched, so concurrent messages wait for a single request
+type SchemaSlot = Arc<tokio::sync::Mutex<Option<CachedSchema>>>;
+
+// Converts JSON payloads to Avro in the Confluent wire format
+#[derive(Debug)]
+pub struct AvroEncoder {
+    registry: Arc<SchemaRegistry>,
+    subject: Option<String>,
+    // Read from `schema_file`, used instead of the latest version of the subject
+    schema: Option<(String, Schema)>,
+    register: bool,
+    // By subject, schemas are only fetched once
+    schemas: Mutex<HashMap<String, SchemaSlot>>,
+}
+
+impl AvroEncoder {
+    pub fn new(
+        config: Option<&AvroConfig>,
+        registry: Arc<SchemaRegistry>,
+    ) -> Result<AvroEncoder, String> {
+        let schema = match config.and_then(|config| config.schema_file.as_ref()) {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|err| format!("Could not read schema file {path}: {err}"))?;
+                let schema = Schema::parse_str(&contents)
+                    .map_err(|err| format!("Invalid schema in {path}: {err}"))?;
+                Some((contents, schema))
+            }
+            None => None,
+        };
+        Ok(AvroEncoder {
+            registry,
+            subject: config.and_then(|config| config.subject.clone()),
+            schema,
+            register: config.and_then(|config| config.register).unwrap_or(false),
+            schemas: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn encode(&self, payload: &[u8], kafka_topic: &str) -> Result<Vec<u8>, String> {
+        let json = serde_json::from_slice::<serde_json::Value>(payload)
+            .map_err(|err| format!("Payload is not valid JSON: {err}"))?;
+        let subject = self
+            .subject
+            .clone()
+            .unwrap_or_else(|| format!("{kafka_topic}-value"));
+        let registered = self.schema(&subject).await?;
+        let value = Value::from(json)
+            .resolve(&registered.schema)
+            .map_err(|err| format!("Payload does not match the schema of {subject}: {err}"))?;
+        let datum = to_avro_datum(&registered.schema, value)
+            .map_err(|err| format!("Could not encode payload as Avro: {err}"))?;
+        let mut encoded = Vec::with_capacity(datum.len() + 5);
+        encoded.push(MAGIC_BYTE);
+        encoded.extend_from_slice(&registered.id.to_be_bytes());
+        encoded.extend_from_slice(&datum);
+        Ok(encoded)
+    }
+
+    async fn schema(&self, subject: &str) -> Result<Arc<RegisteredSchema>, String> {
+        let slot = self
+            .schemas
+            .lock()
+            .expect("Avro schema lock poisoned")
+            .entry(subject.to_string())
+            .or_default()
+            .clone();
+        let mut cached = slot.lock().await;
+        let backoff = match cached.as_ref() {
+            Some(CachedSchema::Fetched(registered)) => return Ok(registered.clone()),
+            Some(CachedSchema::Failed {
+                error, retry_at, ..
+            }) if Instant::now() < *retry_at => return Err(error.clone()),
+            Some(CachedSchema::Failed { backoff, .. }) => (*backoff * 2).min(MAX_BACKOFF),
+            None => INITIAL_BACKOFF,
+        };
+        let fetched = match self.schema.as_ref() {
+            Some((contents, schema)) => self
+                .registry
+                .id(subject, contents, self.register)
+                .await
+                .map(|id| RegisteredSchema {
+                    id,
+                    schema: schema.clone(),
+                }),
+            None => self.registry.latest(subject).await,
+        };
+        match fetched {
+            Ok(registered) => {
+                log::info!("Using schema {} for subject {}", registered.id, subject);
+                let registered = Arc::new(registered);
+                *cached = Some(CachedSchema::Fetched(registered.clone()));
+                Ok(registered)
+            }
+            Err(error) => {
+                log::warn!(
+                    "Could not get schema for subject {}, retrying in {:?}: {}",
+                    subject,
+                    backoff,
+                    error
+                );
+                *cached = Some(CachedSchema::Failed {
+                    error: error.clone(),
+                    backoff,
+                    retry_at: Instant::now() + backoff,
+                });
+                Err(error)
+            }
+        }
+    }
+}
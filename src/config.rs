@@ -1,13 +1,12 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::prelude::*;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct MqttConfig {
     pub host: String,
     pub port: u16,
     pub client_id: String,
+    pub protocol_version: Option<u8>,
     pub credentials: Option<MqttCredentials>,
     pub tls: Option<MqttTlsConfig>,
 }
@@ -31,11 +30,19 @@ pub struct MqttCredentials {
     pub password: String,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct KafkaConfig {
     pub bootstrap_server: String,
     pub port: u16,
     pub config: Option<HashMap<String, String>>,
+    pub transactions: Option<TransactionConfig>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct TransactionConfig {
+    pub transactional_id: String,
+    pub max_batch_size: Option<usize>,
+    pub max_batch_delay_ms: Option<u64>,
 }
 
 impl KafkaConfig {
@@ -44,39 +51,465 @@ impl KafkaConfig {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct ForwardingConfig {
     pub name: String,
     pub mqtt: MqttSource,
     pub kafka: KafkaDest,
+    pub sink: Option<String>,
+    pub key: Option<KeyConfig>,
+    pub transforms: Option<Vec<TransformConfig>>,
+    pub on_transform_error: Option<ErrorPolicy>,
+    pub input_format: Option<PayloadFormat>,
+    pub output_format: Option<PayloadFormat>,
+    pub avro: Option<AvroConfig>,
+    pub protobuf: Option<ProtobufConfig>,
+    pub on_format_error: Option<ErrorPolicy>,
     pub wrap_as_json: Option<bool>,
+    pub envelope: Option<EnvelopeConfig>,
+    pub mqtt_headers: Option<bool>,
+    pub paused: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct KeyConfig {
+    #[serde(flatten)]
+    pub strategy: KeyStrategyConfig,
+    pub fallback: Option<KeyFallback>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum KeyStrategyConfig {
+    Topic,
+    Segment { template: String },
+    JsonPointer { pointer: String },
+    Fixed { value: String },
+    None,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyFallback {
+    Topic,
+    None,
+    Drop,
+    DeadLetter,
+}
+
+// Fields are addressed with JSON pointers (RFC 6901)
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransformConfig {
+    Add {
+        field: String,
+        value: serde_json::Value,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    Drop {
+        field: String,
+    },
+    Topic {
+        field: String,
+    },
+    Segment {
+        field: String,
+        template: String,
+    },
+    Timestamp {
+        field: String,
+        format: Option<TimestampFormat>,
+    },
+    Flatten {
+        field: Option<String>,
+        separator: Option<String>,
+    },
+    Cast {
+        field: String,
+        to: CastType,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    Millis,
+    Seconds,
+    Rfc3339,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CastType {
+    String,
+    Integer,
+    Float,
+    Boolean,
+}
+
+// What to do with a message whose transforms or format conversion failed
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPolicy {
+    Drop,
+    PassThrough,
+    DeadLetter,
+}
+
+// Format payloads are received in or converted to before they are sent, they are sent unchanged if not set
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadFormat {
+    Json,
+    Cbor,
+    Msgpack,
+    // Output only
+    Avro,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AvroConfig {
+    // Defaults to `<kafka topic>-value`
+    pub subject: Option<String>,
+    // Schema to use instead of the latest version of the subject
+    pub schema_file: Option<String>,
+    pub register: Option<bool>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ProtobufConfig {
+    // Path to a serialized FileDescriptorSet, e.g. from `protoc --include_imports --descriptor_set_out`
+    pub descriptor_set: String,
+    // Fully qualified name of the message type of the payloads
+    pub message: String,
+    pub convert: Option<ProtobufConversion>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtobufConversion {
+    // Only validates binary payloads
+    None,
+    ToJson,
+    FromJson,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct EnvelopeConfig {
+    pub format: Option<EnvelopeFormat>,
+    // Optional fields added next to `topic`, `payload` and `payload_encoding`
+    pub fields: Option<Vec<EnvelopeField>>,
+    // CloudEvents only
+    pub mode: Option<CloudEventsMode>,
+    #[serde(rename = "type")]
+    pub event_type: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvelopeFormat {
+    Json,
+    #[serde(rename = "cloudevents")]
+    CloudEvents,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CloudEventsMode {
+    // The event including its data as JSON payload
+    Structured,
+    // The data as payload and the other attributes as `ce_` headers
+    Binary,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvelopeField {
+    Qos,
+    Retain,
+    Timestamp,
+    ClientId,
+    Forwarding,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct MqttSource {
     pub topic: String,
+    pub no_local: Option<bool>,
+    pub retain_as_published: Option<bool>,
+    pub retain_handling: Option<RetainHandling>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RetainHandling {
+    SendOnSubscribe,
+    SendOnNewSubscribe,
+    Never,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct KafkaDest {
     pub topic: String,
+    pub dead_letter_topic: Option<String>,
+    pub allowed_topics: Option<Vec<String>>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SinkConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: SinkKind,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkKind {
+    File {
+        path: String,
+        max_size: Option<u64>,
+        max_files: Option<usize>,
+    },
+    Stdout,
+    Http {
+        url: String,
+        headers: Option<HashMap<String, String>>,
+        timeout_ms: Option<u64>,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ReverseForwardingConfig {
+    pub name: String,
+    pub kafka: ReverseKafkaSource,
+    pub mqtt: ReverseMqttDest,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ReverseKafkaSource {
+    pub topics: Vec<String>,
+    pub group_id: String,
+    pub config: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ReverseMqttDest {
+    pub topic: String,
+    pub qos: Option<u8>,
+    pub retain: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SpillFullPolicy {
+    Block,
+    DropOldest,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SpillConfig {
+    pub path: String,
+    pub max_size: u64,
+    pub segment_size: Option<u64>,
+    pub when_full: Option<SpillFullPolicy>,
+    pub ready_threshold: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderingMode {
+    Strict,
+    Throughput,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardBy {
+    MqttTopic,
+    KafkaKey,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct OrderingConfig {
+    pub mode: OrderingMode,
+    pub workers: Option<usize>,
+    pub shard_by: Option<ShardBy>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct MetricsConfig {
+    pub topic_labels: Option<bool>,
+    pub max_topic_series: Option<usize>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct TracingConfig {
+    pub endpoint: Option<String>,
+    pub service_name: Option<String>,
+    pub traceparent_pointer: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SchemaRegistryConfig {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ApiConfig {
+    pub port: Option<u16>,
+    pub manage_forwardings: Option<bool>,
+    pub persist_forwardings: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Config {
     pub mqtt: MqttConfig,
-    pub kafka: KafkaConfig,
+    pub kafka: Option<KafkaConfig>,
+    pub sinks: Option<Vec<SinkConfig>>,
+    pub schema_registry: Option<SchemaRegistryConfig>,
     pub forwarding: Vec<ForwardingConfig>,
+    pub reverse_forwarding: Option<Vec<ReverseForwardingConfig>>,
+    pub spill: Option<SpillConfig>,
+    pub ordering: Option<OrderingConfig>,
+    pub api: Option<ApiConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub tracing: Option<TracingConfig>,
+}
+
+pub fn config_path() -> String {
+    std::env::var("CONFIG_FILE").unwrap_or_else(|_| "config.yaml".to_string())
 }
 
 pub fn load_config() -> Config {
-    let path = std::env::var("CONFIG_FILE").unwrap_or_else(|_| "config.yaml".to_string());
-    let mut f = File::open(path).expect("config file not found");
-    let mut contents = String::new();
-    f.read_to_string(&mut contents)
-        .expect("something went wrong reading the config file");
-    contents = parse_config(contents);
-    serde_yaml::from_str(&contents).expect("Could not parse config")
+    read_config().unwrap_or_else(|err| panic!("{}", err))
+}
+
+pub fn read_config() -> Result<Config, String> {
+    let contents = std::fs::read_to_string(config_path())
+        .map_err(|err| format!("Could not read config file: {err}"))?;
+    serde_yaml::from_str(&parse_config(contents))
+        .map_err(|err| format!("Could not parse config: {err}"))
+}
+
+// Replaces the forwarding section of the config file, keeping everything else (including ${ENV} placeholders and comments) as is
+pub fn save_forwardings(forwardings: &[ForwardingConfig]) -> Result<(), String> {
+    let path = config_path();
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|err| format!("Could not read config file: {err}"))?;
+    let mut config: serde_yaml::Value = serde_yaml::from_str(&contents)
+        .map_err(|err| format!("Could not parse config file: {err}"))?;
+    let mut forwarding = serde_yaml::to_value(forwardings)
+        .map_err(|err| format!("Could not serialize forwardings: {err}"))?;
+    remove_nulls(&mut forwarding);
+    let config = config
+        .as_mapping_mut()
+        .ok_or_else(|| "Config file is not a mapping".to_string())?;
+    if let (Some(serde_yaml::Value::Sequence(saved)), serde_yaml::Value::Sequence(updated)) =
+        (config.get("forwarding"), &mut forwarding)
+    {
+        for updated in updated.iter_mut() {
+            if let Some(saved) = saved.iter().find(|saved| {
+                saved.get("name").is_some() && saved.get("name") == updated.get("name")
+            }) {
+                keep_placeholders(updated, saved);
+            }
+        }
+    }
+    let mut section = serde_yaml::Mapping::new();
+    section.insert(serde_yaml::Value::from("forwarding"), forwarding.clone());
+    let section = serde_yaml::to_string(&section)
+        .map_err(|err| format!("Could not serialize forwardings: {err}"))?;
+    config.insert(serde_yaml::Value::from("forwarding"), forwarding);
+    let replaced = replace_section(&contents, "forwarding", &section);
+    // Falls back to rewriting the whole file (losing its comments) if the section can not be found reliably
+    let contents = if serde_yaml::from_str::<serde_yaml::Value>(&replaced)
+        .is_ok_and(|value| value.as_mapping() == Some(config))
+    {
+        replaced
+    } else {
+        serde_yaml::to_string(&config)
+            .map_err(|err| format!("Could not serialize config: {err}"))?
+    };
+    let tmp_path = format!("{path}.tmp");
+    std::fs::write(&tmp_path, contents)
+        .and_then(|()| std::fs::rename(&tmp_path, &path))
+        .map_err(|err| format!("Could not write config file: {err}"))
+}
+
+// Replaces a top-level section of the YAML text, so the comments and formatting of the other sections are kept
+fn replace_section(contents: &str, key: &str, section: &str) -> String {
+    let lines = contents.split_inclusive('\n').collect::<Vec<&str>>();
+    let top_level = |line: &&str| !line.starts_with([' ', '\t', '\r', '\n', '#', '-']);
+    let start = lines.iter().position(|line| {
+        top_level(line)
+            && line
+                .strip_prefix(key)
+                .is_some_and(|rest| rest.trim_start().starts_with(':'))
+    });
+    let Some(start) = start else {
+        let separator = if contents.is_empty() || contents.ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        };
+        return format!("{contents}{separator}{section}");
+    };
+    let mut end = lines[start + 1..]
+        .iter()
+        .position(top_level)
+        .map_or(lines.len(), |offset| start + 1 + offset);
+    // Comments and empty lines before the next section belong to it
+    while end > start + 1 && (lines[end - 1].starts_with('#') || lines[end - 1].trim().is_empty()) {
+        end -= 1;
+    }
+    format!(
+        "{}{section}{}",
+        lines[..start].concat(),
+        lines[end..].concat()
+    )
+}
+
+// Values that are unchanged since they were read are written back with their ${ENV} placeholders instead of the substituted value
+fn keep_placeholders(updated: &mut serde_yaml::Value, saved: &serde_yaml::Value) {
+    match (updated, saved) {
+        (serde_yaml::Value::Mapping(updated), serde_yaml::Value::Mapping(saved)) => {
+            for (key, value) in updated.iter_mut() {
+                if let Some(saved) = saved.get(key) {
+                    keep_placeholders(value, saved);
+                }
+            }
+        }
+        (serde_yaml::Value::Sequence(updated), serde_yaml::Value::Sequence(saved)) => {
+            for (value, saved) in updated.iter_mut().zip(saved) {
+                keep_placeholders(value, saved);
+            }
+        }
+        (updated, serde_yaml::Value::String(saved))
+            if saved.contains("${")
+                && serde_yaml::from_str::<serde_yaml::Value>(&parse_config(saved.clone()))
+                    .is_ok_and(|substituted| substituted == *updated) =>
+        {
+            *updated = serde_yaml::Value::String(saved.clone());
+        }
+        _ => (),
+    }
+}
+
+fn remove_nulls(value: &mut serde_yaml::Value) {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => {
+            mapping.retain(|_, value| !value.is_null());
+            mapping.values_mut().for_each(remove_nulls);
+        }
+        serde_yaml::Value::Sequence(sequence) => sequence.iter_mut().for_each(remove_nulls),
+        _ => (),
+    }
 }
 
 enum ParseState {
@@ -130,3 +563,46 @@ fn parse_config(mut content: String) -> String {
     }
     content
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_placeholders_of_unchanged_values() {
+        // The variables are not set, so they are replaced with empty strings
+        let saved: serde_yaml::Value = serde_yaml::from_str(
+            "name: demo\nkafka:\n  topic: ${FORWARDER_TEST_UNSET}readings\n  allowed_topics: ['${FORWARDER_TEST_UNSET}a', b]\nmqtt:\n  topic: ${FORWARDER_TEST_UNSET}demo/#\n",
+        )
+        .unwrap();
+        let mut updated: serde_yaml::Value = serde_yaml::from_str(
+            "name: demo\nkafka:\n  topic: readings\n  allowed_topics: [a, b]\nmqtt:\n  topic: other/#\n",
+        )
+        .unwrap();
+        keep_placeholders(&mut updated, &saved);
+        assert_eq!(
+            updated,
+            serde_yaml::from_str::<serde_yaml::Value>(
+                "name: demo\nkafka:\n  topic: ${FORWARDER_TEST_UNSET}readings\n  allowed_topics: ['${FORWARDER_TEST_UNSET}a', b]\nmqtt:\n  topic: other/#\n",
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn replaces_only_the_forwarding_section() {
+        let contents = "# MQTT\nmqtt:\n  host: localhost # local broker\n\nforwarding:\n- name: old\n  # old topic\n  mqtt:\n    topic: old\n\n# Kafka\nkafka:\n  bootstrap_server: localhost\n";
+        assert_eq!(
+            replace_section(contents, "forwarding", "forwarding:\n- name: new\n"),
+            "# MQTT\nmqtt:\n  host: localhost # local broker\n\nforwarding:\n- name: new\n\n# Kafka\nkafka:\n  bootstrap_server: localhost\n"
+        );
+        assert_eq!(
+            replace_section("mqtt: {}\nforwarding: []", "forwarding", "forwarding: []\n"),
+            "mqtt: {}\nforwarding: []\n"
+        );
+        assert_eq!(
+            replace_section("mqtt: {}", "forwarding", "forwarding: []\n"),
+            "mqtt: {}\nforwarding: []\n"
+        );
+    }
+}
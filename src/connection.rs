@@ -0,0 +1,332 @@
+use crate::config::{MqttConfig, MqttTlsConfig, RetainHandling};
+use bytes::Bytes;
+use rumqttc::v5;
+use rumqttc::v5::mqttbytes::v5::{Filter, PublishProperties, RetainForwardRule};
+use rumqttc::{
+    AsyncClient, Event, EventLoop, MqttOptions, Outgoing, Packet, Publish, QoS, SubscribeFilter,
+    SubscribeReasonCode, TlsConfiguration, Transport,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolVersion {
+    V4,
+    V5,
+}
+
+impl ProtocolVersion {
+    pub fn from_config(config: &MqttConfig) -> ProtocolVersion {
+        match config.protocol_version {
+            None | Some(3) | Some(4) => ProtocolVersion::V4,
+            Some(5) => ProtocolVersion::V5,
+            Some(version) => panic!("Unsupported MQTT protocol version {}", version),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum PublishPacket {
+    V4(Publish),
+    V5(v5::mqttbytes::v5::Publish),
+}
+
+// A received MQTT message, independent of the protocol version
+#[derive(Clone, Debug)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    pub pkid: u16,
+    pub properties: Option<PublishProperties>,
+    packet: PublishPacket,
+}
+
+impl MqttMessage {
+    fn from_v4(publish: Publish) -> MqttMessage {
+        MqttMessage {
+            topic: publish.topic.clone(),
+            payload: publish.payload.clone(),
+            qos: publish.qos as u8,
+            retain: publish.retain,
+            dup: publish.dup,
+            pkid: publish.pkid,
+            properties: None,
+            packet: PublishPacket::V4(publish),
+        }
+    }
+
+    fn from_v5(publish: v5::mqttbytes::v5::Publish) -> MqttMessage {
+        MqttMessage {
+            topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+            payload: publish.payload.clone(),
+            qos: publish.qos as u8,
+            retain: publish.retain,
+            dup: publish.dup,
+            pkid: publish.pkid,
+            properties: publish.properties.clone(),
+            packet: PublishPacket::V5(publish),
+        }
+    }
+}
+
+pub enum MqttEvent {
+    Publish(Box<MqttMessage>),
+    // Outgoing publish with its packet identifier, 0 for QoS 0
+    Published(u16),
+    PubAck(u16),
+    PubComp(u16),
+    // Outgoing subscribe with its packet identifier
+    Subscribed(u16),
+    // Packet identifier and whether the broker granted each topic filter of the subscribe
+    SubAck(u16, Vec<bool>),
+    ConnAck,
+    Disconnect,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subscription {
+    pub topic: String,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling,
+}
+
+#[derive(Clone)]
+pub enum MqttHandle {
+    V4(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl MqttHandle {
+    pub async fn subscribe_many(&self, subscriptions: Vec<Subscription>) -> Result<(), String> {
+        match self {
+            MqttHandle::V4(client) => {
+                client
+                    .subscribe_many(subscriptions.into_iter().map(|subscription| {
+                        SubscribeFilter::new(subscription.topic, QoS::ExactlyOnce)
+                    }))
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+            MqttHandle::V5(client) => client
+                .subscribe_many(subscriptions.into_iter().map(|subscription| Filter {
+                    path: subscription.topic,
+                    qos: v5::mqttbytes::QoS::ExactlyOnce,
+                    nolocal: subscription.no_local,
+                    preserve_retain: subscription.retain_as_published,
+                    retain_forward_rule: match subscription.retain_handling {
+                        RetainHandling::SendOnSubscribe => RetainForwardRule::OnEverySubscribe,
+                        RetainHandling::SendOnNewSubscribe => RetainForwardRule::OnNewSubscribe,
+                        RetainHandling::Never => RetainForwardRule::Never,
+                    },
+                }))
+                .await
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    pub async fn publish(
+        &self,
+        topic: String,
+        qos: u8,
+        retain: bool,
+        payload: Vec<u8>,
+    ) -> Result<(), String> {
+        match self {
+            MqttHandle::V4(client) => {
+                let qos = match qos {
+                    0 => QoS::AtMostOnce,
+                    1 => QoS::AtLeastOnce,
+                    _ => QoS::ExactlyOnce,
+                };
+                client
+                    .publish(topic, qos, retain, payload)
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+            MqttHandle::V5(client) => {
+                let qos = match qos {
+                    0 => v5::mqttbytes::QoS::AtMostOnce,
+                    1 => v5::mqttbytes::QoS::AtLeastOnce,
+                    _ => v5::mqttbytes::QoS::ExactlyOnce,
+                };
+                client
+                    .publish(topic, qos, retain, payload)
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+        }
+    }
+
+    pub async fn unsubscribe(&self, topic: String) -> Result<(), String> {
+        match self {
+            MqttHandle::V4(client) => client
+                .unsubscribe(topic)
+                .await
+                .map_err(|err| err.to_string()),
+            MqttHandle::V5(client) => client
+                .unsubscribe(topic)
+                .await
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    pub async fn ack(&self, message: &MqttMessage) -> Result<(), String> {
+        match (self, &message.packet) {
+            (MqttHandle::V4(client), PublishPacket::V4(publish)) => {
+                client.ack(publish).await.map_err(|err| err.to_string())
+            }
+            (MqttHandle::V5(client), PublishPacket::V5(publish)) => {
+                client.ack(publish).await.map_err(|err| err.to_string())
+            }
+            _ => Err("Message was received with a different protocol version".to_string()),
+        }
+    }
+
+    pub async fn disconnect(&self) -> Result<(), String> {
+        match self {
+            MqttHandle::V4(client) => client.disconnect().await.map_err(|err| err.to_string()),
+            MqttHandle::V5(client) => client.disconnect().await.map_err(|err| err.to_string()),
+        }
+    }
+}
+
+pub enum MqttEventLoop {
+    V4(Box<EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+impl MqttEventLoop {
+    pub async fn poll(&mut self) -> Result<MqttEvent, String> {
+        match self {
+            MqttEventLoop::V4(eventloop) => match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    Ok(MqttEvent::Publish(Box::new(MqttMessage::from_v4(publish))))
+                }
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => Ok(MqttEvent::Published(pkid)),
+                Ok(Event::Incoming(Packet::PubAck(puback))) => Ok(MqttEvent::PubAck(puback.pkid)),
+                Ok(Event::Incoming(Packet::PubComp(pubcomp))) => {
+                    Ok(MqttEvent::PubComp(pubcomp.pkid))
+                }
+                Ok(Event::Outgoing(Outgoing::Subscribe(pkid))) => Ok(MqttEvent::Subscribed(pkid)),
+                Ok(Event::Incoming(Packet::SubAck(suback))) => Ok(MqttEvent::SubAck(
+                    suback.pkid,
+                    suback
+                        .return_codes
+                        .iter()
+                        .map(|code| matches!(code, SubscribeReasonCode::Success(_)))
+                        .collect(),
+                )),
+                Ok(Event::Incoming(Packet::ConnAck(_))) => Ok(MqttEvent::ConnAck),
+                Ok(Event::Incoming(Packet::Disconnect)) => Ok(MqttEvent::Disconnect),
+                Ok(_) => Ok(MqttEvent::Other),
+                Err(err) => Err(err.to_string()),
+            },
+            MqttEventLoop::V5(eventloop) => match eventloop.poll().await {
+                Ok(v5::Event::Incoming(v5::Incoming::Publish(publish))) => {
+                    Ok(MqttEvent::Publish(Box::new(MqttMessage::from_v5(publish))))
+                }
+                Ok(v5::Event::Outgoing(Outgoing::Publish(pkid))) => Ok(MqttEvent::Published(pkid)),
+                Ok(v5::Event::Incoming(v5::Incoming::PubAck(puback))) => {
+                    Ok(MqttEvent::PubAck(puback.pkid))
+                }
+                Ok(v5::Event::Incoming(v5::Incoming::PubComp(pubcomp))) => {
+                    Ok(MqttEvent::PubComp(pubcomp.pkid))
+                }
+                Ok(v5::Event::Outgoing(Outgoing::Subscribe(pkid))) => {
+                    Ok(MqttEvent::Subscribed(pkid))
+                }
+                Ok(v5::Event::Incoming(v5::Incoming::SubAck(suback))) => Ok(MqttEvent::SubAck(
+                    suback.pkid,
+                    suback
+                        .return_codes
+                        .iter()
+                        .map(|code| {
+                            matches!(code, v5::mqttbytes::v5::SubscribeReasonCode::Success(_))
+                        })
+                        .collect(),
+                )),
+                Ok(v5::Event::Incoming(v5::Incoming::ConnAck(_))) => Ok(MqttEvent::ConnAck),
+                Ok(v5::Event::Incoming(v5::Incoming::Disconnect(_))) => Ok(MqttEvent::Disconnect),
+                Ok(_) => Ok(MqttEvent::Other),
+                Err(err) => Err(err.to_string()),
+            },
+        }
+    }
+}
+
+pub fn connect(config: &MqttConfig, max_in_flight: u16) -> (MqttHandle, MqttEventLoop) {
+    let transport = config.tls.as_ref().map(|tlsconfig| {
+        log::debug!("Using TLS for MQTT connection");
+        init_tls_transport(tlsconfig.clone())
+    });
+    match ProtocolVersion::from_config(config) {
+        ProtocolVersion::V4 => {
+            let mut mqttoptions =
+                MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+            mqttoptions
+                .set_clean_session(config.clean_session())
+                .set_inflight(max_in_flight)
+                .set_manual_acks(true);
+            if let Some(transport) = transport {
+                mqttoptions.set_transport(transport);
+            }
+            if let Some(credentials) = config.credentials.as_ref() {
+                mqttoptions.set_credentials(&credentials.username, &credentials.password);
+            }
+            let (client, eventloop) = AsyncClient::new(mqttoptions, max_in_flight as usize);
+            (
+                MqttHandle::V4(client),
+                MqttEventLoop::V4(Box::new(eventloop)),
+            )
+        }
+        ProtocolVersion::V5 => {
+            let mut mqttoptions =
+                v5::MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+            mqttoptions
+                .set_clean_start(config.clean_session())
+                .set_outgoing_inflight_upper_limit(max_in_flight)
+                .set_manual_acks(true);
+            if !config.clean_session() {
+                // Without a session expiry MQTT 5 brokers discard the session on disconnect
+                mqttoptions.set_session_expiry_interval(Some(u32::MAX));
+            }
+            if let Some(transport) = transport {
+                mqttoptions.set_transport(transport);
+            }
+            if let Some(credentials) = config.credentials.as_ref() {
+                mqttoptions.set_credentials(&credentials.username, &credentials.password);
+            }
+            let (client, eventloop) = v5::AsyncClient::new(mqttoptions, max_in_flight as usize);
+            (
+                MqttHandle::V5(client),
+                MqttEventLoop::V5(Box::new(eventloop)),
+            )
+        }
+    }
+}
+
+fn init_tls_transport(config: MqttTlsConfig) -> Transport {
+    let ca_cert = std::fs::read_to_string(&config.ca_cert).expect("Could not read CA cert file");
+
+    let client_auth = if let Some(client_cert) = config.client_cert {
+        let client_cert = std::fs::read_to_string(client_cert).expect("Could not read client cert");
+
+        if let Some(client_key) = config.client_key {
+            let client_key =
+                std::fs::read_to_string(client_key).expect("Could not read client key");
+            Some((client_cert.into_bytes(), client_key.into_bytes()))
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+    Transport::Tls(TlsConfiguration::Simple {
+        ca: ca_cert.into_bytes(),
+        alpn: None,
+        client_auth,
+    })
+}
@@ -0,0 +1,597 @@
+use crate::config::{ErrorPolicy, OrderingConfig, OrderingMode, ShardBy};
+use crate::connection::{MqttHandle, MqttMessage};
+use crate::envelope::Envelope;
+use crate::events::{Events, ForwarderEvent};
+use crate::fatal::Fatal;
+use crate::kafka::ProduceOutcome;
+use crate::key::KeyResult;
+use crate::metrics::{
+    ForwardingLabels, MetricLabels, COUNT_FORMAT_ERRORS, COUNT_KAFKA_KEY_MISSING,
+    COUNT_KAFKA_PUBLISHED, COUNT_MQTT_ACKS, COUNT_TRANSFORM_ERRORS, FORWARDING_LATENCY,
+    PAYLOAD_SIZE,
+};
+use crate::mqtt::Stats;
+use crate::protobuf::PROTOBUF_TYPE_HEADER;
+use crate::routing::{Route, TopicMatch};
+use crate::sink::{SinkMessage, Sinks};
+use crate::telemetry;
+use crate::transcode;
+use opentelemetry::trace::SpanKind;
+use opentelemetry::Context;
+use opentelemetry::KeyValue;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+static DEFAULT_WORKERS: usize = 16;
+static WORKER_QUEUE_SIZE: usize = 100;
+static DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct ConversionFailure {
+    policy: ErrorPolicy,
+    // Error code of dead-lettered messages
+    code: &'static str,
+    error: String,
+    counter: &'static Family<MetricLabels, Counter>,
+}
+
+// A received MQTT message together with everything shared by its routes
+pub struct Delivery {
+    publish: MqttMessage,
+    received_at: SystemTime,
+    expires_at: Option<SystemTime>,
+    client_id: Arc<str>,
+    headers: Vec<(String, Vec<u8>)>,
+    // CloudEvents id, shared by all routes of the message
+    event_id: Option<String>,
+    // Names of the forwardings the message is routed to, the ack is counted for each of them
+    forwardings: Vec<String>,
+    trace_context: Context,
+    pending: AtomicUsize,
+}
+
+impl Delivery {
+    pub fn new(
+        publish: MqttMessage,
+        routes: &[Route],
+        client_id: &Arc<str>,
+        received_at: SystemTime,
+        trace_context: Context,
+    ) -> Delivery {
+        let expires_at = publish
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.message_expiry_interval)
+            .map(|interval| received_at + Duration::from_secs(interval as u64));
+        let headers = if routes.iter().any(|route| route.topic_match.mqtt_headers) {
+            mqtt_headers(&publish, client_id, received_at)
+        } else {
+            Vec::new()
+        };
+        let event_id = routes
+            .iter()
+            .any(|route| {
+                route
+                    .topic_match
+                    .envelope
+                    .as_ref()
+                    .is_some_and(Envelope::is_cloudevents)
+            })
+            .then(|| uuid::Uuid::new_v4().to_string());
+        let mut forwardings = routes
+            .iter()
+            .map(|route| route.topic_match.name.clone())
+            .collect::<Vec<String>>();
+        forwardings.sort();
+        forwardings.dedup();
+        Delivery {
+            publish,
+            received_at,
+            expires_at,
+            client_id: client_id.clone(),
+            headers,
+            event_id,
+            forwardings,
+            trace_context,
+            pending: AtomicUsize::new(routes.len()),
+        }
+    }
+}
+
+struct Job {
+    delivery: Arc<Delivery>,
+    route: Route,
+    key: KeyResult,
+}
+
+#[derive(Clone)]
+pub struct Dispatcher {
+    sinks: Sinks,
+    mqtt: MqttHandle,
+    stats: Arc<Stats>,
+    events: Events,
+    fatal: Fatal,
+    // Dispatched messages that are not acknowledged yet
+    in_flight: Arc<AtomicUsize>,
+    // Only set in strict ordering mode
+    workers: Option<Vec<mpsc::Sender<Job>>>,
+    shard_by: ShardBy,
+}
+
+impl Dispatcher {
+    pub fn new(
+        config: Option<&OrderingConfig>,
+        sinks: Sinks,
+        mqtt: MqttHandle,
+        stats: Arc<Stats>,
+        events: Events,
+        fatal: Fatal,
+    ) -> Dispatcher {
+        let mut dispatcher = Dispatcher {
+            sinks,
+            mqtt,
+            stats,
+            events,
+            fatal,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            workers: None,
+            shard_by: config
+                .and_then(|config| config.shard_by)
+                .unwrap_or(ShardBy::MqttTopic),
+        };
+        if let Some(config) = config
+            && config.mode == OrderingMode::Strict
+        {
+            let count = config.workers.unwrap_or(DEFAULT_WORKERS).max(1);
+            log::info!("Forwarding with strict ordering using {} workers", count);
+            let workers = (0..count)
+                .map(|_| {
+                    let (sender, mut receiver) = mpsc::channel::<Job>(WORKER_QUEUE_SIZE);
+                    let worker = dispatcher.clone();
+                    dispatcher.fatal.spawn(async move {
+                        while let Some(job) = receiver.recv().await {
+                            worker.process(job).await;
+                        }
+                    });
+                    sender
+                })
+                .collect();
+            dispatcher.workers = Some(workers);
+        }
+        dispatcher
+    }
+
+    pub async fn dispatch(&self, delivery: Delivery, routes: Vec<Route>) {
+        let delivery = Arc::new(delivery);
+        if routes.is_empty() {
+            self.ack(&delivery).await;
+            return;
+        }
+        let jobs = routes
+            .into_iter()
+            .map(|route| {
+                let key = route
+                    .topic_match
+                    .key
+                    .extract(&delivery.publish.topic, &delivery.publish.payload);
+                Job {
+                    delivery: delivery.clone(),
+                    route,
+                    key,
+                }
+            })
+            .collect::<Vec<Job>>();
+
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        match self.workers.as_ref() {
+            // Jobs with the same shard are processed one after another by the same worker
+            Some(workers) => {
+                for job in jobs {
+                    let worker = &workers[self.shard(&job) % workers.len()];
+                    if worker.send(job).await.is_err() {
+                        // Only happens after the worker failed, which already stopped the forwarder
+                        self.fatal.fail("Forwarding worker stopped".to_string());
+                        return;
+                    }
+                }
+            }
+            // Spawn new thread for each mqtt message to not block the eventloop
+            None => {
+                let dispatcher = self.clone();
+                self.fatal.spawn(async move {
+                    for job in jobs {
+                        dispatcher.process(job).await;
+                    }
+                });
+            }
+        }
+    }
+
+    fn shard(&self, job: &Job) -> usize {
+        let mut hasher = DefaultHasher::new();
+        match (self.shard_by, &job.key) {
+            (ShardBy::KafkaKey, KeyResult::Key(Some(key))) => {
+                job.route.kafka_topic.hash(&mut hasher);
+                key.hash(&mut hasher);
+            }
+            _ => job.delivery.publish.topic.hash(&mut hasher),
+        }
+        hasher.finish() as usize
+    }
+
+    async fn process(&self, job: Job) {
+        let Job {
+            delivery,
+            route,
+            key,
+        } = job;
+        self.forward(&delivery, route, key).await;
+        if delivery.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.ack(&delivery).await;
+            self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    // Waits until all dispatched messages are forwarded and acknowledged, unless the forwarder failed
+    pub async fn drain(&self) {
+        let started = Instant::now();
+        while self.in_flight.load(Ordering::Acquire) > 0 && self.fatal.error().is_none() {
+            if started.elapsed() > DRAIN_TIMEOUT {
+                log::warn!(
+                    "Stopping with {} messages still being forwarded, they are redelivered by the broker",
+                    self.in_flight.load(Ordering::Acquire)
+                );
+                return;
+            }
+            crate::health::heartbeat();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn forward(&self, delivery: &Delivery, route: Route, key: KeyResult) {
+        let publish = &delivery.publish;
+        let topic = &route.topic_match;
+        let mut headers = if topic.mqtt_headers {
+            delivery.headers.as_slice()
+        } else {
+            &[]
+        };
+        let protobuf_headers: Vec<(String, Vec<u8>)>;
+        if let Some(protobuf) = topic.protobuf.as_ref() {
+            protobuf_headers = headers
+                .iter()
+                .cloned()
+                .chain([(
+                    PROTOBUF_TYPE_HEADER.to_string(),
+                    protobuf.message_type().as_bytes().to_vec(),
+                )])
+                .collect();
+            headers = &protobuf_headers;
+        }
+        let key = match key {
+            KeyResult::Key(key) => key,
+            result => {
+                COUNT_KAFKA_KEY_MISSING
+                    .get_or_create(&MetricLabels::new(
+                        &topic.name,
+                        &topic.subscription.topic,
+                        &route.kafka_topic,
+                    ))
+                    .inc();
+                if let KeyResult::DeadLetter(reason) = result {
+                    let message = SinkMessage {
+                        forwarding: &topic.name,
+                        subscription: &topic.subscription.topic,
+                        kafka_topic: &route.kafka_topic,
+                        key: Some(&publish.topic),
+                        payload: &publish.payload,
+                        mqtt_topic: &publish.topic,
+                        dead_letter_topic: topic.dead_letter_topic.as_deref(),
+                        headers,
+                        expires_at: delivery.expires_at,
+                        trace_context: &delivery.trace_context,
+                    };
+                    // Validated when the forwarding is created: dead-letter topics require the kafka sink
+                    self.sinks
+                        .kafka()
+                        .expect("No kafka sink for dead-letter topic")
+                        .reject(&message, &reason, "MissingKey")
+                        .await;
+                } else {
+                    log::warn!(
+                        "Dropping message from {}: could not determine kafka key",
+                        publish.topic
+                    );
+                }
+                return;
+            }
+        };
+        let mut message = SinkMessage {
+            forwarding: &topic.name,
+            subscription: &topic.subscription.topic,
+            kafka_topic: &route.kafka_topic,
+            key: key.as_deref(),
+            payload: &publish.payload,
+            mqtt_topic: &publish.topic,
+            dead_letter_topic: topic.dead_letter_topic.as_deref(),
+            headers,
+            expires_at: delivery.expires_at,
+            trace_context: &delivery.trace_context,
+        };
+        let transform = telemetry::start_span(
+            "transform",
+            SpanKind::Internal,
+            &delivery.trace_context,
+            vec![KeyValue::new("forwarding", topic.name.clone())],
+        );
+        let result = self.convert(topic, &message, delivery.received_at).await;
+        telemetry::end_span(
+            &transform,
+            match &result {
+                Err(failure) => vec![KeyValue::new("error", failure.error.clone())],
+                Ok(_) => Vec::new(),
+            },
+        );
+        let converted: Vec<u8>;
+        match result {
+            Ok(Some(payload)) => {
+                converted = payload;
+                message.payload = &converted;
+            }
+            Ok(None) => {}
+            Err(failure) => {
+                failure
+                    .counter
+                    .get_or_create(&MetricLabels::new(
+                        &topic.name,
+                        &topic.subscription.topic,
+                        &route.kafka_topic,
+                    ))
+                    .inc();
+                if !self
+                    .on_error(failure.policy, &message, &failure.error, failure.code)
+                    .await
+                {
+                    return;
+                }
+            }
+        }
+        let wrapped: Option<Vec<u8>>;
+        let envelope_headers: Vec<(String, Vec<u8>)>;
+        if let Some(envelope) = topic.envelope.as_ref() {
+            let added_headers;
+            (wrapped, added_headers) = envelope.wrap(
+                message.payload,
+                publish,
+                &topic.name,
+                &delivery.client_id,
+                delivery.received_at,
+                delivery.event_id.as_deref().unwrap_or_default(),
+            );
+            if let Some(wrapped) = wrapped.as_ref() {
+                message.payload = wrapped;
+            }
+            if !added_headers.is_empty() {
+                envelope_headers = message
+                    .headers
+                    .iter()
+                    .cloned()
+                    .chain(added_headers)
+                    .collect();
+                message.headers = &envelope_headers;
+            }
+        }
+        let labels = ForwardingLabels {
+            forwarding: topic.name.clone(),
+        };
+        PAYLOAD_SIZE
+            .get_or_create(&labels)
+            .observe(message.payload.len() as f64);
+        let sink = self
+            .sinks
+            .get(&topic.sink)
+            .expect("Forwarding uses an unknown sink");
+        let outcome = sink.produce(&message).await;
+        if outcome == ProduceOutcome::Delivered {
+            let latency = delivery.received_at.elapsed().unwrap_or_default();
+            FORWARDING_LATENCY
+                .get_or_create(&labels)
+                .observe(latency.as_secs_f64());
+        }
+        self.events.emit(|| ForwarderEvent::Forwarded {
+            forwarding: topic.name.clone(),
+            mqtt_topic: publish.topic.clone(),
+            kafka_topic: route.kafka_topic.clone(),
+            outcome,
+        });
+        // Dead-lettered, expired and dropped messages did not reach their topic
+        if matches!(outcome, ProduceOutcome::Delivered | ProduceOutcome::Spilled) {
+            COUNT_KAFKA_PUBLISHED
+                .get_or_create(&MetricLabels::new(
+                    &topic.name,
+                    &topic.subscription.topic,
+                    &route.kafka_topic,
+                ))
+                .inc();
+            self.stats.count_published.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Decodes, transforms and encodes the payload as configured for the forwarding, None if it is unchanged
+    async fn convert(
+        &self,
+        topic: &TopicMatch,
+        message: &SinkMessage<'_>,
+        received_at: SystemTime,
+    ) -> Result<Option<Vec<u8>>, ConversionFailure> {
+        let format_failure = |error| ConversionFailure {
+            policy: topic.on_format_error,
+            code: "FormatFailed",
+            error,
+            counter: &COUNT_FORMAT_ERRORS,
+        };
+        let mut payload = None;
+        if let Some(format) = topic.input_format {
+            payload = transcode::decode(format, message.payload).map_err(format_failure)?;
+        }
+        if let Some(protobuf) = topic.protobuf.as_ref()
+            && let Some(decoded) = protobuf.decode(message.payload).map_err(format_failure)?
+        {
+            payload = Some(decoded);
+        }
+        if let Some(transforms) = topic.transforms.as_ref() {
+            let current = payload.as_deref().unwrap_or(message.payload);
+            payload = Some(
+                transforms
+                    .apply(current, message.mqtt_topic, received_at)
+                    .map_err(|error| ConversionFailure {
+                        policy: transforms.on_error,
+                        code: "TransformFailed",
+                        error,
+                        counter: &COUNT_TRANSFORM_ERRORS,
+                    })?,
+            );
+        }
+        if let Some(protobuf) = topic.protobuf.as_ref() {
+            let current = payload.as_deref().unwrap_or(message.payload);
+            if let Some(encoded) = protobuf.encode(current).map_err(format_failure)? {
+                payload = Some(encoded);
+            }
+        }
+        if let Some(format) = topic.output_format {
+            let current = payload.as_deref().unwrap_or(message.payload);
+            if let Some(encoded) = transcode::encode(format, current).map_err(format_failure)? {
+                payload = Some(encoded);
+            }
+        }
+        if let Some(avro) = topic.avro.as_ref() {
+            let current = payload.as_deref().unwrap_or(message.payload);
+            payload = Some(
+                avro.encode(current, message.kafka_topic)
+                    .await
+                    .map_err(format_failure)?,
+            );
+        }
+        Ok(payload)
+    }
+
+    // Handles a message whose transforms or format conversion failed, returns whether its original payload is still sent
+    async fn on_error(
+        &self,
+        policy: ErrorPolicy,
+        message: &SinkMessage<'_>,
+        error: &str,
+        code: &str,
+    ) -> bool {
+        match policy {
+            ErrorPolicy::PassThrough => {
+                log::warn!(
+                    "Forwarding original payload from {}: {}",
+                    message.mqtt_topic,
+                    error
+                );
+                true
+            }
+            ErrorPolicy::Drop => {
+                log::warn!("Dropping message from {}: {}", message.mqtt_topic, error);
+                false
+            }
+            ErrorPolicy::DeadLetter => {
+                // Validated when the forwarding is created: dead-letter topics require the kafka sink
+                self.sinks
+                    .kafka()
+                    .expect("No kafka sink for dead-letter topic")
+                    .reject(message, error, code)
+                    .await;
+                false
+            }
+        }
+    }
+
+    // Messages that match no forwarding are counted with an empty forwarding
+    async fn ack(&self, delivery: &Delivery) {
+        for _ in 0..5 {
+            if self.mqtt.ack(&delivery.publish).await.is_ok() {
+                if delivery.forwardings.is_empty() {
+                    COUNT_MQTT_ACKS
+                        .get_or_create(&ForwardingLabels {
+                            forwarding: String::new(),
+                        })
+                        .inc();
+                }
+                for forwarding in delivery.forwardings.iter() {
+                    COUNT_MQTT_ACKS
+                        .get_or_create(&ForwardingLabels {
+                            forwarding: forwarding.clone(),
+                        })
+                        .inc();
+                }
+                return;
+            }
+        }
+        self.fatal.fail("Could not send ack to MQTT".to_string());
+    }
+}
+
+fn mqtt_headers(
+    publish: &MqttMessage,
+    client_id: &str,
+    received_at: SystemTime,
+) -> Vec<(String, Vec<u8>)> {
+    let timestamp = received_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let mut headers = vec![
+        ("mqtt.topic".to_string(), publish.topic.clone().into_bytes()),
+        ("mqtt.qos".to_string(), publish.qos.to_string().into_bytes()),
+        (
+            "mqtt.retain".to_string(),
+            publish.retain.to_string().into_bytes(),
+        ),
+        ("mqtt.dup".to_string(), publish.dup.to_string().into_bytes()),
+        (
+            "mqtt.pkid".to_string(),
+            publish.pkid.to_string().into_bytes(),
+        ),
+        (
+            "mqtt.client_id".to_string(),
+            client_id.to_string().into_bytes(),
+        ),
+        (
+            "forwarding.timestamp".to_string(),
+            timestamp.to_string().into_bytes(),
+        ),
+    ];
+    if let Some(properties) = publish.properties.as_ref() {
+        if let Some(content_type) = properties.content_type.as_ref() {
+            headers.push((
+                "mqtt.content_type".to_string(),
+                content_type.clone().into_bytes(),
+            ));
+        }
+        if let Some(response_topic) = properties.response_topic.as_ref() {
+            headers.push((
+                "mqtt.response_topic".to_string(),
+                response_topic.clone().into_bytes(),
+            ));
+        }
+        if let Some(correlation_data) = properties.correlation_data.as_ref() {
+            headers.push((
+                "mqtt.correlation_data".to_string(),
+                correlation_data.to_vec(),
+            ));
+        }
+        for (name, value) in properties.user_properties.iter() {
+            headers.push((name.clone(), value.clone().into_bytes()));
+        }
+    }
+    headers
+}
@@ -0,0 +1,249 @@
+use crate::config::{CloudEventsMode, EnvelopeConfig, EnvelopeField, EnvelopeFormat};
+use crate::connection::MqttMessage;
+use crate::transform::rfc3339;
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::value::{to_raw_value, RawValue};
+use serde_json::{json, Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static CLOUDEVENTS_SPEC_VERSION: &str = "1.0";
+static CLOUDEVENTS_CONTENT_TYPE: &str = "application/cloudevents+json; charset=UTF-8";
+
+// The wrapped payload, None if it is forwarded unchanged, and the headers to add
+type Wrapped = (Option<Vec<u8>>, Vec<(String, Vec<u8>)>);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct WrappedPayload {
+    topic: String,
+    payload: String,
+}
+
+// Structured mode event of the CloudEvents Kafka protocol binding
+#[derive(Debug, Serialize)]
+struct CloudEvent<'a> {
+    specversion: &'static str,
+    id: &'a str,
+    source: &'a str,
+    #[serde(rename = "type")]
+    event_type: &'a str,
+    time: &'a str,
+    datacontenttype: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<&'a RawValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data_base64: Option<&'a RawValue>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonEnvelope<'a> {
+    topic: &'a str,
+    payload: &'a RawValue,
+    payload_encoding: &'static str,
+    #[serde(flatten)]
+    fields: Map<String, Value>,
+}
+
+// How the payload of a forwarding is wrapped
+#[derive(Clone, Debug)]
+pub enum Envelope {
+    // `wrap_as_json` without an `envelope` section: the topic and the base64-encoded payload
+    Base64,
+    Json(Vec<EnvelopeField>),
+    CloudEvents {
+        mode: CloudEventsMode,
+        event_type: String,
+    },
+}
+
+impl Envelope {
+    pub fn new(
+        wrap_as_json: bool,
+        config: Option<&EnvelopeConfig>,
+        forwarding: &str,
+    ) -> Result<Option<Envelope>, String> {
+        let Some(config) = config else {
+            return Ok(wrap_as_json.then_some(Envelope::Base64));
+        };
+        if wrap_as_json {
+            return Err(format!(
+                "Forwarding {forwarding} sets both wrap_as_json and envelope"
+            ));
+        }
+        match config.format.unwrap_or(EnvelopeFormat::Json) {
+            EnvelopeFormat::Json => {
+                if config.mode.is_some() || config.event_type.is_some() {
+                    return Err(format!(
+                        "Forwarding {forwarding} sets mode or type without envelope format cloudevents"
+                    ));
+                }
+                Ok(Some(Envelope::Json(
+                    config.fields.clone().unwrap_or_default(),
+                )))
+            }
+            EnvelopeFormat::CloudEvents => {
+                if config.fields.is_some() {
+                    return Err(format!(
+                        "Forwarding {forwarding} sets envelope fields with format cloudevents"
+                    ));
+                }
+                Ok(Some(Envelope::CloudEvents {
+                    mode: config.mode.unwrap_or(CloudEventsMode::Structured),
+                    event_type: config
+                        .event_type
+                        .clone()
+                        .unwrap_or_else(|| forwarding.to_string()),
+                }))
+            }
+        }
+    }
+
+    pub fn is_cloudevents(&self) -> bool {
+        matches!(self, Envelope::CloudEvents { .. })
+    }
+
+    pub fn wrap(
+        &self,
+        payload: &[u8],
+        publish: &MqttMessage,
+        forwarding: &str,
+        client_id: &str,
+        received_at: SystemTime,
+        event_id: &str,
+    ) -> Wrapped {
+        match self {
+            Envelope::Base64 => {
+                let wrapped = WrappedPayload {
+                    topic: publish.topic.clone(),
+                    payload: BASE64_STANDARD.encode(payload),
+                };
+                let wrapped = serde_json::to_vec(&wrapped).expect("Could not wrap payload");
+                (Some(wrapped), Vec::new())
+            }
+            Envelope::Json(fields) => {
+                let (payload, encoding) = embed(payload);
+                let mut wrapped = JsonEnvelope {
+                    topic: &publish.topic,
+                    payload: &payload,
+                    payload_encoding: encoding,
+                    fields: Map::new(),
+                };
+                for field in fields {
+                    let (name, value) = match field {
+                        EnvelopeField::Qos => ("qos", json!(publish.qos)),
+                        EnvelopeField::Retain => ("retain", json!(publish.retain)),
+                        EnvelopeField::Timestamp => (
+                            "timestamp",
+                            json!(received_at
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_millis() as u64),
+                        ),
+                        EnvelopeField::ClientId => ("client_id", json!(client_id)),
+                        EnvelopeField::Forwarding => ("forwarding", json!(forwarding)),
+                    };
+                    wrapped.fields.insert(name.to_string(), value);
+                }
+                let wrapped = serde_json::to_vec(&wrapped).expect("Could not wrap payload");
+                (Some(wrapped), Vec::new())
+            }
+            Envelope::CloudEvents { mode, event_type } => {
+                let time = rfc3339(received_at);
+                let (data, encoding) = embed(payload);
+                let content_type = match encoding {
+                    "json" => "application/json",
+                    "string" => "text/plain; charset=UTF-8",
+                    _ => "application/octet-stream",
+                };
+                match mode {
+                    CloudEventsMode::Structured => {
+                        let (data, data_base64) = match encoding {
+                            "base64" => (None, Some(&*data)),
+                            _ => (Some(&*data), None),
+                        };
+                        let event = CloudEvent {
+                            specversion: CLOUDEVENTS_SPEC_VERSION,
+                            id: event_id,
+                            source: &publish.topic,
+                            event_type,
+                            time: &time,
+                            datacontenttype: content_type,
+                            data,
+                            data_base64,
+                        };
+                        let wrapped = serde_json::to_vec(&event).expect("Could not wrap payload");
+                        (
+                            Some(wrapped),
+                            vec![header("content-type", CLOUDEVENTS_CONTENT_TYPE)],
+                        )
+                    }
+                    // The payload stays as is, the attributes become `ce_` headers
+                    CloudEventsMode::Binary => (
+                        None,
+                        vec![
+                            header("ce_specversion", CLOUDEVENTS_SPEC_VERSION),
+                            header("ce_id", event_id),
+                            header("ce_source", &publish.topic),
+                            header("ce_type", event_type),
+                            header("ce_time", &time),
+                            header("content-type", content_type),
+                        ],
+                    ),
+                }
+            }
+        }
+    }
+}
+
+// Payloads are kept readable where possible, the returned encoding tells consumers how.
+// JSON is embedded verbatim, parsing it would reorder keys and round large numbers
+fn embed(payload: &[u8]) -> (Box<RawValue>, &'static str) {
+    if let Ok(json) = serde_json::from_slice::<Box<RawValue>>(payload) {
+        (json, "json")
+    } else if let Ok(text) = std::str::from_utf8(payload) {
+        (
+            to_raw_value(text).expect("Could not embed payload"),
+            "string",
+        )
+    } else {
+        (
+            to_raw_value(&BASE64_STANDARD.encode(payload)).expect("Could not embed payload"),
+            "base64",
+        )
+    }
+}
+
+fn header(name: &str, value: &str) -> (String, Vec<u8>) {
+    (name.to_string(), value.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_wrap_as_json_with_an_envelope() {
+        let config: EnvelopeConfig = serde_yaml::from_str("fields: [qos]").unwrap();
+        assert!(Envelope::new(true, Some(&config), "demo").is_err());
+        assert!(matches!(
+            Envelope::new(false, Some(&config), "demo"),
+            Ok(Some(Envelope::Json(_)))
+        ));
+        assert!(matches!(
+            Envelope::new(true, None, "demo"),
+            Ok(Some(Envelope::Base64))
+        ));
+    }
+
+    #[test]
+    fn embeds_json_verbatim() {
+        let (payload, encoding) = embed(br#" {"b": 1.50, "a": 12345678901234567890123} "#);
+        assert_eq!(encoding, "json");
+        assert_eq!(
+            payload.get(),
+            r#"{"b": 1.50, "a": 12345678901234567890123}"#
+        );
+        let (payload, encoding) = embed(b"warm");
+        assert_eq!((payload.get(), encoding), (r#""warm""#, "string"));
+    }
+}
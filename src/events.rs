@@ -0,0 +1,46 @@
+use crate::kafka::ProduceOutcome;
+use tokio::sync::broadcast;
+
+static EVENT_QUEUE_SIZE: usize = 1000;
+
+// Notifications for code embedding the forwarder, see `Forwarder::subscribe`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ForwarderEvent {
+    MqttConnected,
+    MqttDisconnected,
+    // The broker acknowledged subscriptions, e.g. of a forwarding added at runtime
+    MqttSubscribed,
+    Forwarded {
+        forwarding: String,
+        mqtt_topic: String,
+        kafka_topic: String,
+        outcome: ProduceOutcome,
+    },
+    Stopped,
+}
+
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<ForwarderEvent>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            sender: broadcast::channel(EVENT_QUEUE_SIZE).0,
+        }
+    }
+}
+
+impl Events {
+    pub fn subscribe(&self) -> broadcast::Receiver<ForwarderEvent> {
+        self.sender.subscribe()
+    }
+
+    // The event is only built if someone is listening, subscribers that fall behind miss events
+    pub fn emit(&self, event: impl FnOnce() -> ForwarderEvent) {
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(event());
+        }
+    }
+}
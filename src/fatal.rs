@@ -0,0 +1,65 @@
+use futures_util::FutureExt;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use tokio::task::JoinHandle;
+
+// Stops the forwarder on errors it can not recover from. The first error is returned by `Forwarder::stopped`
+#[derive(Clone)]
+pub struct Fatal {
+    running: Arc<AtomicBool>,
+    error: Arc<Mutex<Option<String>>>,
+}
+
+impl Fatal {
+    pub fn new(running: Arc<AtomicBool>) -> Fatal {
+        Fatal {
+            running,
+            error: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    pub fn fail(&self, error: String) {
+        log::error!("{}. Stopping", error);
+        self.error
+            .lock()
+            .expect("Fatal error lock poisoned")
+            .get_or_insert(error);
+        self.running.store(false, Ordering::Release);
+    }
+
+    pub fn error(&self) -> Option<String> {
+        self.error
+            .lock()
+            .expect("Fatal error lock poisoned")
+            .clone()
+    }
+
+    // Spawns a task whose panic stops the forwarder instead of only ending the task
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) -> JoinHandle<()> {
+        let fatal = self.clone();
+        tokio::spawn(async move {
+            if let Err(panic) = AssertUnwindSafe(task).catch_unwind().await {
+                fatal.fail(panic_message(panic));
+            }
+        })
+    }
+}
+
+pub fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast_ref::<&str>().map_or_else(
+            || "Unknown panic".to_string(),
+            |message| message.to_string(),
+        ),
+    }
+}
@@ -0,0 +1,161 @@
+use crate::health::{ComponentHealth, Status};
+use crate::kafka::ProduceOutcome;
+use crate::sink::{expired, is_expired, json_record, Sink, SinkFuture, SinkMessage};
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+static DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+static DEFAULT_MAX_FILES: usize = 5;
+
+struct ActiveFile {
+    file: File,
+    size: u64,
+}
+
+// Appends messages as JSON lines. Once the file reaches max_size it is renamed to `<path>.1`, older files are shifted up to `<path>.<max_files>`
+pub struct FileSink {
+    // Shared with the blocking tasks doing the file I/O
+    file: Arc<RotatingFile>,
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    active: Mutex<ActiveFile>,
+}
+
+impl FileSink {
+    pub fn open(
+        path: &str,
+        max_size: Option<u64>,
+        max_files: Option<usize>,
+    ) -> Result<FileSink, String> {
+        let path = PathBuf::from(path);
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent).map_err(|err| {
+                format!("Could not create directory for {}: {err}", path.display())
+            })?;
+        }
+        Ok(FileSink {
+            file: Arc::new(RotatingFile {
+                active: Mutex::new(open_file(&path)?),
+                path,
+                max_size: max_size.unwrap_or(DEFAULT_MAX_SIZE),
+                max_files: max_files.unwrap_or(DEFAULT_MAX_FILES).max(1),
+            }),
+        })
+    }
+
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&RotatingFile) -> T + Send + 'static,
+    ) -> T {
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || f(&file))
+            .await
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+    }
+}
+
+impl RotatingFile {
+    fn write(&self, line: &[u8]) -> Result<(), String> {
+        let mut active = self.active.lock().expect("File sink lock poisoned");
+        if active.size > 0 && active.size + line.len() as u64 > self.max_size {
+            self.rotate(&mut active)?;
+        }
+        active
+            .file
+            .write_all(line)
+            .map_err(|err| format!("Could not write to {}: {err}", self.path.display()))?;
+        active.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self, active: &mut ActiveFile) -> Result<(), String> {
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated_path(index + 1))
+                    .map_err(|err| format!("Could not rotate {}: {err}", from.display()))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))
+            .map_err(|err| format!("Could not rotate {}: {err}", self.path.display()))?;
+        *active = open_file(&self.path)?;
+        log::debug!("Rotated file sink {}", self.path.display());
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        PathBuf::from(path)
+    }
+}
+
+fn open_file(path: &Path) -> Result<ActiveFile, String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| format!("Could not open {}: {err}", path.display()))?;
+    let size = file
+        .metadata()
+        .map_err(|err| format!("Could not open {}: {err}", path.display()))?
+        .len();
+    Ok(ActiveFile { file, size })
+}
+
+impl Sink for FileSink {
+    fn produce<'a>(&'a self, message: &'a SinkMessage<'a>) -> SinkFuture<'a, ProduceOutcome> {
+        Box::pin(async move {
+            if is_expired(message.expires_at) {
+                expired(
+                    message.forwarding,
+                    message.subscription,
+                    message.kafka_topic,
+                );
+                return ProduceOutcome::Expired;
+            }
+            let mut line = json_record(message);
+            line.push(b'\n');
+            // The message is acknowledged to MQTT afterwards, so it must not be skipped
+            if let Err(err) = self.blocking(move |file| file.write(&line)).await {
+                panic!("{}. Aborting", err);
+            }
+            ProduceOutcome::Delivered
+        })
+    }
+
+    fn flush(&self) -> SinkFuture<'_, Result<(), String>> {
+        Box::pin(self.blocking(|file| {
+            file.active
+                .lock()
+                .expect("File sink lock poisoned")
+                .file
+                .sync_data()
+                .map_err(|err| err.to_string())
+        }))
+    }
+
+    fn health(&self) -> SinkFuture<'_, ComponentHealth> {
+        Box::pin(async {
+            let size = self
+                .file
+                .active
+                .lock()
+                .expect("File sink lock poisoned")
+                .size;
+            ComponentHealth {
+                status: Status::Up,
+                details: json!({ "path": self.file.path, "size": size }),
+            }
+        })
+    }
+}
@@ -0,0 +1,262 @@
+use crate::config::{ApiConfig, Config, ForwardingConfig};
+use crate::events::{Events, ForwarderEvent};
+use crate::fatal::{panic_message, Fatal};
+use crate::forwardings::{ForwardingError, Forwardings};
+use crate::kafka::KafkaClient;
+use crate::mqtt::MqttClient;
+use crate::reverse::ReverseForwarder;
+use crate::schema_registry::SchemaRegistry;
+use crate::sink::{Sink, Sinks};
+use crate::{api, metrics, reload, telemetry};
+use log::info;
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+static DEFAULT_WORKER_THREADS: usize = 8;
+
+// Configures a forwarder. Process-wide hooks (Ctrl-C handler, config file watching) are off unless enabled
+pub struct ForwarderBuilder {
+    config: Config,
+    sinks: Vec<(String, Arc<dyn Sink>)>,
+    api_enabled: bool,
+    api_address: Option<SocketAddr>,
+    worker_threads: usize,
+    stop_on_ctrl_c: bool,
+    watch_config: bool,
+}
+
+impl ForwarderBuilder {
+    pub fn new(config: Config) -> ForwarderBuilder {
+        ForwarderBuilder {
+            config,
+            sinks: Vec::new(),
+            api_enabled: true,
+            api_address: None,
+            worker_threads: DEFAULT_WORKER_THREADS,
+            stop_on_ctrl_c: false,
+            watch_config: false,
+        }
+    }
+
+    // Adds a sink forwardings can select with `sink: <name>` in addition to the ones from the config
+    pub fn sink(mut self, name: &str, sink: Arc<dyn Sink>) -> ForwarderBuilder {
+        self.sinks.push((name.to_string(), sink));
+        self
+    }
+
+    // Address of the HTTP API, defaults to 0.0.0.0 and `api.port` of the config
+    pub fn api_address(mut self, address: SocketAddr) -> ForwarderBuilder {
+        self.api_address = Some(address);
+        self
+    }
+
+    // Does not serve the HTTP API, e.g. if the embedding application exposes metrics and health itself
+    pub fn without_api(mut self) -> ForwarderBuilder {
+        self.api_enabled = false;
+        self
+    }
+
+    // Size of the tokio runtime created by `run`, ignored by `start`
+    pub fn worker_threads(mut self, worker_threads: usize) -> ForwarderBuilder {
+        self.worker_threads = worker_threads;
+        self
+    }
+
+    // Stops the forwarder on Ctrl-C. The handler can only be installed once per process
+    pub fn stop_on_ctrl_c(mut self, enabled: bool) -> ForwarderBuilder {
+        self.stop_on_ctrl_c = enabled;
+        self
+    }
+
+    // Reloads the config when the file at CONFIG_FILE changes or on SIGHUP
+    pub fn watch_config(mut self, enabled: bool) -> ForwarderBuilder {
+        self.watch_config = enabled;
+        self
+    }
+
+    // Connects to MQTT and kafka and starts forwarding on the current tokio runtime.
+    // Panics if the config is invalid or the MQTT broker is not reachable
+    pub async fn start(self) -> Forwarder {
+        let config = self.config;
+        metrics::init_metrics().await;
+        metrics::configure_topic_labels(config.metrics.as_ref());
+        let tracer_provider = telemetry::init(config.tracing.as_ref());
+        let api_config = config.api.clone().unwrap_or(ApiConfig {
+            port: None,
+            manage_forwardings: None,
+            persist_forwardings: None,
+        });
+
+        let running = Arc::new(AtomicBool::new(true));
+        let fatal = Fatal::new(running.clone());
+        let events = Events::default();
+        let kafka_client = match config.kafka.as_ref() {
+            Some(kafka_config) => {
+                Some(KafkaClient::new(kafka_config, config.spill.as_ref(), &fatal).await)
+            }
+            None if config.spill.is_some() => panic!("The spill buffer requires a kafka section"),
+            None => None,
+        };
+        let sinks = Sinks::new(
+            kafka_client,
+            config.sinks.as_deref().unwrap_or_default(),
+            self.sinks,
+            config.schema_registry.as_ref().map(|registry| {
+                SchemaRegistry::new(registry)
+                    .unwrap_or_else(|err| panic!("Invalid schema registry config: {}", err))
+            }),
+        )
+        .unwrap_or_else(|err| panic!("Invalid sink config: {}", err));
+        let mut mqtt_client = MqttClient::new(
+            &config.mqtt,
+            config.forwarding.clone(),
+            config.ordering.clone(),
+            &sinks,
+            api_config.persist_forwardings.unwrap_or(false),
+            events.clone(),
+            running.clone(),
+        )
+        .await;
+
+        info!("Clients created. Subscribing to mqtt topics...");
+        mqtt_client.subscribe().await;
+
+        // Gracefully stop mqtt client on ctr-c
+        if self.stop_on_ctrl_c {
+            let r = running.clone();
+            ctrlc::set_handler(move || {
+                info!("Stopping Mqtt Client...");
+                r.store(false, Ordering::Release);
+            })
+            .expect("Error setting Crtl-C handler");
+        }
+
+        let api_task = self.api_enabled.then(|| {
+            let address = self.api_address.unwrap_or_else(|| {
+                SocketAddr::from(([0, 0, 0, 0], api_config.port.unwrap_or(api::DEFAULT_PORT)))
+            });
+            info!("Starting HTTP API on {}", address);
+            tokio::task::spawn(api::api(
+                mqtt_client.forwardings(),
+                sinks.clone(),
+                address,
+                api_config.manage_forwardings.unwrap_or(false),
+            ))
+        });
+
+        for reverse_config in config.reverse_forwarding.clone().unwrap_or_default() {
+            let kafka_config = config
+                .kafka
+                .as_ref()
+                .expect("Reverse forwardings require a kafka section");
+            let forwarder =
+                ReverseForwarder::new(kafka_config, &reverse_config, mqtt_client.publisher())
+                    .unwrap_or_else(|err| {
+                        panic!(
+                            "Invalid reverse forwarding {}: {}",
+                            reverse_config.name, err
+                        )
+                    });
+            fatal.spawn(forwarder.run(running.clone()));
+        }
+
+        if self.watch_config {
+            fatal.spawn(reload::watch(
+                config,
+                mqtt_client.forwardings(),
+                sinks.clone(),
+                running.clone(),
+            ));
+        }
+
+        let forwardings = mqtt_client.forwardings();
+        let f = fatal.clone();
+        let e = events.clone();
+        let task = tokio::task::spawn(async move {
+            info!("Running forwarding");
+            mqtt_client.run(sinks.clone(), f).await;
+
+            info!("Disconnecting...");
+            mqtt_client.disconnect().await;
+            sinks.flush().await;
+            if let Some(api_task) = api_task {
+                api_task.abort();
+            }
+            if let Some(provider) = tracer_provider {
+                telemetry::shutdown(provider);
+            }
+            e.emit(|| ForwarderEvent::Stopped);
+            info!("Stop.");
+        });
+
+        Forwarder {
+            running,
+            fatal,
+            forwardings,
+            events,
+            task,
+        }
+    }
+
+    // Runs the forwarder on a new tokio runtime until it is stopped, see `Forwarder::stopped`
+    pub fn run(self) -> Result<(), String> {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(self.worker_threads)
+            .enable_all()
+            .build()
+            .expect("Could not create tokio runtime")
+            .block_on(async { self.start().await.stopped().await })
+    }
+}
+
+// Handle to a running forwarder
+pub struct Forwarder {
+    running: Arc<AtomicBool>,
+    fatal: Fatal,
+    forwardings: Arc<Forwardings>,
+    events: Events,
+    task: JoinHandle<()>,
+}
+
+impl Forwarder {
+    pub fn builder(config: Config) -> ForwarderBuilder {
+        ForwarderBuilder::new(config)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ForwarderEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn forwardings(&self) -> Vec<ForwardingConfig> {
+        self.forwardings.list().await
+    }
+
+    pub async fn add_forwarding(&self, config: ForwardingConfig) -> Result<(), ForwardingError> {
+        self.forwardings.create(config).await
+    }
+
+    pub async fn remove_forwarding(&self, name: &str) -> Result<(), ForwardingError> {
+        self.forwardings.delete(name).await
+    }
+
+    // Stops receiving from MQTT, messages already received are still forwarded and acknowledged (for at most 30 seconds)
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Release);
+    }
+
+    // Waits until the forwarder has stopped and disconnected from MQTT.
+    // Returns the error if it stopped because of an error it could not recover from, e.g. a sink that kept failing
+    pub async fn stopped(self) -> Result<(), String> {
+        if let Err(err) = self.task.await
+            && err.is_panic()
+        {
+            return Err(panic_message(err.into_panic()));
+        }
+        self.fatal.error().map_or(Ok(()), Err)
+    }
+}
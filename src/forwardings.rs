@@ -0,0 +1,353 @@
+use crate::config::{save_forwardings, ForwardingConfig};
+use crate::connection::{MqttHandle, ProtocolVersion, Subscription};
+use crate::routing::TopicMatch;
+use crate::sink::Sinks;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{oneshot, Mutex};
+
+static SUBACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum ForwardingError {
+    NotFound(String),
+    AlreadyExists(String),
+    Invalid(String),
+    Persist(String),
+    Subscribe(String),
+}
+
+impl fmt::Display for ForwardingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardingError::NotFound(name) => write!(f, "Forwarding {name} does not exist"),
+            ForwardingError::AlreadyExists(name) => write!(f, "Forwarding {name} already exists"),
+            ForwardingError::Invalid(err) => write!(f, "Invalid forwarding: {err}"),
+            ForwardingError::Persist(err) => write!(f, "Could not persist forwardings: {err}"),
+            ForwardingError::Subscribe(err) => {
+                write!(f, "Could not subscribe to MQTT topics: {err}")
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct TrackerState {
+    // Subscribes handed to the MQTT client that were not yet sent out, in order
+    queued: VecDeque<oneshot::Sender<Vec<bool>>>,
+    // Sent subscribes waiting for their SubAck by packet identifier
+    pending: HashMap<u16, oneshot::Sender<Vec<bool>>>,
+}
+
+// Matches SubAcks from the broker to subscribes, as rumqttc does not report the packet identifier to the caller
+#[derive(Default)]
+pub struct SubscribeTracker {
+    state: std::sync::Mutex<TrackerState>,
+    // Keeps the queue in the same order as the requests of the MQTT client
+    order: Mutex<()>,
+}
+
+impl SubscribeTracker {
+    pub fn subscribed(&self, pkid: u16) {
+        let mut state = self.state.lock().unwrap();
+        // Subscribes that are still pending are retransmitted after a reconnect
+        if state
+            .pending
+            .get(&pkid)
+            .is_some_and(|sender| !sender.is_closed())
+        {
+            return;
+        }
+        if let Some(sender) = state.queued.pop_front() {
+            state.pending.insert(pkid, sender);
+        }
+    }
+
+    pub fn acknowledged(&self, pkid: u16, granted: Vec<bool>) {
+        if let Some(sender) = self.state.lock().unwrap().pending.remove(&pkid) {
+            let _ = sender.send(granted);
+        }
+    }
+}
+
+// The set of forwardings that can be changed while the service is running
+pub struct Forwardings {
+    client: MqttHandle,
+    tracker: SubscribeTracker,
+    protocol_version: ProtocolVersion,
+    sinks: Sinks,
+    persist: bool,
+    configs: Mutex<Vec<ForwardingConfig>>,
+    active: RwLock<Arc<Vec<TopicMatch>>>,
+}
+
+impl Forwardings {
+    pub fn new(
+        client: MqttHandle,
+        protocol_version: ProtocolVersion,
+        configs: Vec<ForwardingConfig>,
+        sinks: Sinks,
+        persist: bool,
+    ) -> Result<Forwardings, String> {
+        let active = topic_matches(&configs, protocol_version, &sinks)?;
+        Ok(Forwardings {
+            client,
+            tracker: SubscribeTracker::default(),
+            protocol_version,
+            sinks,
+            persist,
+            configs: Mutex::new(configs),
+            active: RwLock::new(Arc::new(active)),
+        })
+    }
+
+    // Snapshot of the forwardings that are currently not paused
+    pub fn topic_matches(&self) -> Arc<Vec<TopicMatch>> {
+        self.active
+            .read()
+            .expect("Forwardings lock poisoned")
+            .clone()
+    }
+
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        subscriptions(&self.topic_matches())
+    }
+
+    pub async fn list(&self) -> Vec<ForwardingConfig> {
+        self.configs.lock().await.clone()
+    }
+
+    pub async fn get(&self, name: &str) -> Option<ForwardingConfig> {
+        self.configs
+            .lock()
+            .await
+            .iter()
+            .find(|config| config.name == name)
+            .cloned()
+    }
+
+    pub async fn create(&self, config: ForwardingConfig) -> Result<(), ForwardingError> {
+        let mut configs = self.configs.lock().await;
+        if configs.iter().any(|existing| existing.name == config.name) {
+            return Err(ForwardingError::AlreadyExists(config.name));
+        }
+        let mut updated = configs.clone();
+        updated.push(config);
+        self.apply(&mut configs, updated, self.persist).await
+    }
+
+    pub async fn update(
+        &self,
+        name: &str,
+        config: ForwardingConfig,
+    ) -> Result<(), ForwardingError> {
+        let mut configs = self.configs.lock().await;
+        let index = position(&configs, name)?;
+        if config.name != name && configs.iter().any(|existing| existing.name == config.name) {
+            return Err(ForwardingError::AlreadyExists(config.name));
+        }
+        let mut updated = configs.clone();
+        updated[index] = config;
+        self.apply(&mut configs, updated, self.persist).await
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), ForwardingError> {
+        let mut configs = self.configs.lock().await;
+        let index = position(&configs, name)?;
+        let mut updated = configs.clone();
+        updated.remove(index);
+        self.apply(&mut configs, updated, self.persist).await
+    }
+
+    pub async fn set_paused(&self, name: &str, paused: bool) -> Result<(), ForwardingError> {
+        let mut configs = self.configs.lock().await;
+        let index = position(&configs, name)?;
+        let mut updated = configs.clone();
+        updated[index].paused = Some(paused);
+        self.apply(&mut configs, updated, self.persist).await
+    }
+
+    pub fn tracker(&self) -> &SubscribeTracker {
+        &self.tracker
+    }
+
+    // Returns the granted state of each topic filter once the broker sent its SubAck
+    pub async fn subscribe(
+        &self,
+        subscriptions: Vec<Subscription>,
+    ) -> Result<oneshot::Receiver<Vec<bool>>, String> {
+        let _guard = self.tracker.order.lock().await;
+        let (sender, receiver) = oneshot::channel();
+        self.tracker.state.lock().unwrap().queued.push_back(sender);
+        if let Err(err) = self.client.subscribe_many(subscriptions).await {
+            self.tracker.state.lock().unwrap().queued.pop_back();
+            return Err(err);
+        }
+        Ok(receiver)
+    }
+
+    async fn subscribe_granted(&self, subscriptions: Vec<Subscription>) -> Result<(), String> {
+        let topics = subscriptions
+            .iter()
+            .map(|subscription| subscription.topic.clone())
+            .collect::<Vec<String>>();
+        let receiver = self.subscribe(subscriptions).await?;
+        let granted = match tokio::time::timeout(SUBACK_TIMEOUT, receiver).await {
+            Ok(Ok(granted)) => granted,
+            Ok(Err(_)) => return Err("Subscribe was dropped".to_string()),
+            Err(_) => return Err("Timed out waiting for the SubAck".to_string()),
+        };
+        let rejected = topics
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| granted.get(*index) != Some(&true))
+            .map(|(_, topic)| topic.as_str())
+            .collect::<Vec<&str>>();
+        if !rejected.is_empty() {
+            return Err(format!("Broker rejected {}", rejected.join(", ")));
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self, configs: &[ForwardingConfig]) -> Result<(), String> {
+        topic_matches(configs, self.protocol_version, &self.sinks).map(|_| ())
+    }
+
+    // Used when the config file changed, so the new forwardings are not written back to it
+    pub async fn replace_all(&self, updated: Vec<ForwardingConfig>) -> Result<(), ForwardingError> {
+        let mut configs = self.configs.lock().await;
+        if *configs == updated {
+            return Ok(());
+        }
+        self.apply(&mut configs, updated, false).await
+    }
+
+    async fn apply(
+        &self,
+        configs: &mut Vec<ForwardingConfig>,
+        updated: Vec<ForwardingConfig>,
+        persist: bool,
+    ) -> Result<(), ForwardingError> {
+        let active = topic_matches(&updated, self.protocol_version, &self.sinks)
+            .map_err(ForwardingError::Invalid)?;
+        let old_subscriptions = self.subscriptions();
+        let new_subscriptions = subscriptions(&active);
+        let added = new_subscriptions
+            .iter()
+            .filter(|subscription| !old_subscriptions.contains(subscription))
+            .cloned()
+            .collect::<Vec<Subscription>>();
+        // Nothing is changed if the broker does not grant all new topics
+        if !added.is_empty() {
+            log::info!("Subscribing to {} new MQTT topics", added.len());
+            if let Err(err) = self.subscribe_granted(added.clone()).await {
+                self.unsubscribe(added).await;
+                return Err(ForwardingError::Subscribe(err));
+            }
+        }
+        if persist && let Err(err) = save_forwardings(&updated) {
+            self.unsubscribe(added).await;
+            return Err(ForwardingError::Persist(err));
+        }
+        *self.active.write().expect("Forwardings lock poisoned") = Arc::new(active);
+        *configs = updated;
+
+        for subscription in old_subscriptions {
+            if new_subscriptions
+                .iter()
+                .any(|new| new.topic == subscription.topic)
+            {
+                continue;
+            }
+            log::info!("Unsubscribing from MQTT topic {}", subscription.topic);
+            if let Err(err) = self.client.unsubscribe(subscription.topic).await {
+                log::error!("Could not unsubscribe from mqtt topic: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, subscriptions: Vec<Subscription>) {
+        for subscription in subscriptions {
+            if let Err(err) = self.client.unsubscribe(subscription.topic).await {
+                log::error!("Could not unsubscribe from mqtt topic: {}", err);
+            }
+        }
+    }
+}
+
+fn position(configs: &[ForwardingConfig], name: &str) -> Result<usize, ForwardingError> {
+    configs
+        .iter()
+        .position(|config| config.name == name)
+        .ok_or_else(|| ForwardingError::NotFound(name.to_string()))
+}
+
+fn topic_matches(
+    configs: &[ForwardingConfig],
+    protocol_version: ProtocolVersion,
+    sinks: &Sinks,
+) -> Result<Vec<TopicMatch>, String> {
+    let mut topic_matches = Vec::new();
+    for (index, config) in configs.iter().enumerate() {
+        if config.name.is_empty() {
+            return Err("Forwarding name must not be empty".to_string());
+        }
+        if configs[..index]
+            .iter()
+            .any(|other| other.name == config.name)
+        {
+            return Err(format!("Duplicate forwarding name {}", config.name));
+        }
+        let topic_match = TopicMatch::new(config, protocol_version, sinks)
+            .map_err(|err| format!("Forwarding {}: {}", config.name, err))?;
+        if !config.paused.unwrap_or(false) {
+            topic_matches.push(topic_match);
+        }
+    }
+    Ok(topic_matches)
+}
+
+// One subscription per MQTT topic filter, the first forwarding using a filter decides its options
+fn subscriptions(topic_matches: &[TopicMatch]) -> Vec<Subscription> {
+    let mut subscriptions: Vec<Subscription> = Vec::new();
+    for topic_match in topic_matches {
+        if !subscriptions
+            .iter()
+            .any(|subscription| subscription.topic == topic_match.subscription.topic)
+        {
+            subscriptions.push(topic_match.subscription.clone());
+        }
+    }
+    subscriptions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(tracker: &SubscribeTracker) -> oneshot::Receiver<Vec<bool>> {
+        let (sender, receiver) = oneshot::channel();
+        tracker.state.lock().unwrap().queued.push_back(sender);
+        receiver
+    }
+
+    #[test]
+    fn matches_subacks_to_subscribes_in_order() {
+        let tracker = SubscribeTracker::default();
+        let mut first = queue(&tracker);
+        let mut second = queue(&tracker);
+        tracker.subscribed(1);
+        tracker.subscribed(2);
+        // Retransmitted after a reconnect
+        tracker.subscribed(1);
+
+        tracker.acknowledged(2, vec![false]);
+        tracker.acknowledged(1, vec![true, true]);
+        assert_eq!(first.try_recv().unwrap(), vec![true, true]);
+        assert_eq!(second.try_recv().unwrap(), vec![false]);
+        assert!(tracker.state.lock().unwrap().pending.is_empty());
+    }
+}
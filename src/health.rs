@@ -0,0 +1,129 @@
+use crate::metrics::MQTT_CONNECTED;
+use crate::sink::{Sinks, KAFKA_SINK};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static LIVENESS_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Time of the last iteration of the MQTT event loop in milliseconds since the epoch, 0 if it has not started yet
+static EVENTLOOP_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+// Set once the broker acknowledged the subscriptions of the forwardings, messages are only received after that
+static MQTT_SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ComponentHealth {
+    pub status: Status,
+    pub details: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub status: Status,
+    pub components: BTreeMap<String, ComponentHealth>,
+}
+
+impl HealthReport {
+    fn new(components: BTreeMap<String, ComponentHealth>) -> HealthReport {
+        let status = if components
+            .values()
+            .all(|component| component.status == Status::Up)
+        {
+            Status::Up
+        } else {
+            Status::Down
+        };
+        HealthReport { status, components }
+    }
+}
+
+pub fn heartbeat() {
+    EVENTLOOP_HEARTBEAT.store(now_millis(), Ordering::Relaxed);
+}
+
+pub fn subscribed() {
+    MQTT_SUBSCRIBED.store(true, Ordering::Relaxed);
+}
+
+pub fn liveness() -> HealthReport {
+    let last = EVENTLOOP_HEARTBEAT.load(Ordering::Relaxed);
+    let age = Duration::from_millis(now_millis().saturating_sub(last));
+    // Until the event loop has started the service is still initializing
+    let status = if last == 0 || age < LIVENESS_TIMEOUT {
+        Status::Up
+    } else {
+        Status::Down
+    };
+    let details = if last == 0 {
+        json!({ "started": false })
+    } else {
+        json!({ "started": true, "last_iteration_ms_ago": age.as_millis() as u64 })
+    };
+    HealthReport::new(BTreeMap::from([(
+        "eventloop".to_string(),
+        ComponentHealth { status, details },
+    )]))
+}
+
+pub async fn readiness(sinks: &Sinks) -> HealthReport {
+    let mut components = BTreeMap::new();
+
+    let connected = MQTT_CONNECTED.get() > 0;
+    let subscribed = MQTT_SUBSCRIBED.load(Ordering::Relaxed);
+    components.insert(
+        "mqtt".to_string(),
+        ComponentHealth {
+            status: if connected && subscribed {
+                Status::Up
+            } else {
+                Status::Down
+            },
+            details: json!({ "connected": connected, "subscribed": subscribed }),
+        },
+    );
+
+    // Other sinks are prefixed so their names can not clash with the built-in components
+    for (name, health) in sinks.health().await {
+        if name == KAFKA_SINK {
+            components.insert(name, health);
+        } else {
+            components.insert(format!("sink.{name}"), health);
+        }
+    }
+
+    if let Some(registry) = sinks.schema_registry() {
+        components.insert("schema_registry".to_string(), registry.health());
+    }
+
+    if let Some(usage) = sinks.kafka().and_then(|kafka| kafka.spill_usage()) {
+        components.insert(
+            "spill".to_string(),
+            ComponentHealth {
+                status: if usage.below_threshold() {
+                    Status::Up
+                } else {
+                    Status::Down
+                },
+                details: json!(usage),
+            },
+        );
+    }
+
+    HealthReport::new(components)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,158 @@
+use crate::health::{ComponentHealth, Status};
+use crate::kafka::ProduceOutcome;
+use crate::metrics::{MetricLabels, COUNT_SINK_DROPPED};
+use crate::sink::{expired, is_expired, json_record, Sink, SinkFuture, SinkMessage};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::StatusCode;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+static MAX_ATTEMPTS: u32 = 5;
+static RETRY_DELAY: Duration = Duration::from_secs(1);
+// The sink is reported down after this many failed requests in a row, until no request failed for FAILURE_WINDOW
+static UNHEALTHY_AFTER_FAILURES: u32 = 3;
+static FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Failures {
+    consecutive: u32,
+    last_error: Option<String>,
+    last_failed_at: Option<Instant>,
+}
+
+// Posts each message as JSON to a webhook
+pub struct HttpSink {
+    url: String,
+    client: reqwest::Client,
+    // Cleared once a message is delivered again
+    failures: Mutex<Failures>,
+}
+
+impl HttpSink {
+    pub fn new(
+        url: &str,
+        headers: Option<&HashMap<String, String>>,
+        timeout_ms: Option<u64>,
+    ) -> Result<HttpSink, String> {
+        let mut default_headers = HeaderMap::new();
+        default_headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        for (name, value) in headers.into_iter().flatten() {
+            default_headers.insert(
+                HeaderName::try_from(name)
+                    .map_err(|err| format!("Invalid header {name}: {err}"))?,
+                HeaderValue::try_from(value)
+                    .map_err(|err| format!("Invalid value for header {name}: {err}"))?,
+            );
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(default_headers)
+            .timeout(timeout_ms.map_or(DEFAULT_TIMEOUT, Duration::from_millis))
+            .build()
+            .map_err(|err| format!("Could not create HTTP client: {err}"))?;
+        Ok(HttpSink {
+            url: url.to_string(),
+            client,
+            failures: Mutex::new(Failures::default()),
+        })
+    }
+
+    fn record_success(&self) {
+        *self.failures.lock().expect("HTTP sink lock poisoned") = Failures::default();
+    }
+
+    fn record_failure(&self, error: String) {
+        let mut failures = self.failures.lock().expect("HTTP sink lock poisoned");
+        failures.consecutive += 1;
+        failures.last_error = Some(error);
+        failures.last_failed_at = Some(Instant::now());
+    }
+}
+
+impl Sink for HttpSink {
+    fn produce<'a>(&'a self, message: &'a SinkMessage<'a>) -> SinkFuture<'a, ProduceOutcome> {
+        Box::pin(async move {
+            let body = json_record(message);
+            for attempt in 1..=MAX_ATTEMPTS {
+                if is_expired(message.expires_at) {
+                    expired(
+                        message.forwarding,
+                        message.subscription,
+                        message.kafka_topic,
+                    );
+                    return ProduceOutcome::Expired;
+                }
+                if attempt > 1 {
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+                let error = match self.client.post(&self.url).body(body.clone()).send().await {
+                    Ok(response) if response.status().is_success() => {
+                        self.record_success();
+                        return ProduceOutcome::Delivered;
+                    }
+                    Ok(response) if !is_retryable(response.status()) => {
+                        // Sending the same message again will not succeed
+                        log::error!(
+                            "{} rejected message from {} with {}, dropping it",
+                            self.url,
+                            message.mqtt_topic,
+                            response.status()
+                        );
+                        COUNT_SINK_DROPPED
+                            .get_or_create(&MetricLabels::new(
+                                message.forwarding,
+                                message.subscription,
+                                message.kafka_topic,
+                            ))
+                            .inc();
+                        return ProduceOutcome::Dropped;
+                    }
+                    Ok(response) => format!("{} responded with {}", self.url, response.status()),
+                    Err(err) => format!("Could not send to {}: {}", self.url, err),
+                };
+                log::error!("Failed to send: {}", error);
+                self.record_failure(error);
+            }
+            // If we come here we failed to send the message
+            panic!("Could not send a message to {}. Aborting", self.url)
+        })
+    }
+
+    fn flush(&self) -> SinkFuture<'_, Result<(), String>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn health(&self) -> SinkFuture<'_, ComponentHealth> {
+        Box::pin(async {
+            let failures = self.failures.lock().expect("HTTP sink lock poisoned");
+            // Single transient failures and failures without recent traffic do not make the service unready
+            let down = failures.consecutive >= UNHEALTHY_AFTER_FAILURES
+                && failures
+                    .last_failed_at
+                    .is_some_and(|failed_at| failed_at.elapsed() < FAILURE_WINDOW);
+            if down {
+                ComponentHealth {
+                    status: Status::Down,
+                    details: json!({
+                        "url": self.url,
+                        "error": failures.last_error,
+                        "consecutive_failures": failures.consecutive,
+                    }),
+                }
+            } else {
+                ComponentHealth {
+                    status: Status::Up,
+                    details: json!({ "url": self.url }),
+                }
+            }
+        })
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
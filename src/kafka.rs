@@ -1,69 +1,560 @@
-use crate::config::KafkaConfig;
+use crate::config::{KafkaConfig, SpillConfig};
+use crate::fatal::Fatal;
+use crate::health::{ComponentHealth, Status};
+use crate::metrics::{
+    ErrorLabels, ForwardingLabels, MetricLabels, COUNT_KAFKA_DEAD_LETTERED, COUNT_KAFKA_DROPPED,
+    COUNT_KAFKA_PRODUCE_ERRORS, COUNT_KAFKA_RETRIES, COUNT_SPILL_DROPPED, KAFKA_PRODUCE_LATENCY,
+};
+use crate::sink::{expired, is_expired, Sink, SinkFuture, SinkMessage};
+use crate::spill::{SpillBuffer, SpillRecord, SpillUsage};
+use crate::statistics::StatsContext;
+use crate::telemetry;
+use crate::transaction::Transactions;
 use log::error;
+use opentelemetry::trace::SpanKind;
+use opentelemetry::KeyValue;
 use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::{Header, Headers, OwnedHeaders};
 use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
-use std::time::Duration;
+use serde_json::json;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+static MAX_ATTEMPTS: u32 = 5;
+static HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProduceOutcome {
+    Delivered,
+    Spilled,
+    DeadLettered,
+    Expired,
+    // Rejected by a sink and not dead-lettered
+    Dropped,
+}
+
+pub type KafkaProducer = FutureProducer<StatsContext>;
+
+impl SinkMessage<'_> {
+    fn spill_record(&self) -> SpillRecord {
+        SpillRecord {
+            forwarding: self.forwarding.to_string(),
+            subscription: self.subscription.to_string(),
+            kafka_topic: self.kafka_topic.to_string(),
+            key: self.key.map(str::to_string),
+            payload: self.payload.to_vec(),
+            mqtt_topic: self.mqtt_topic.to_string(),
+            dead_letter_topic: self.dead_letter_topic.map(str::to_string),
+            headers: self.headers.to_vec(),
+            expires_at: self.expires_at,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct KafkaClient {
-    producer: FutureProducer,
+    // Shared by all clones so the producer can be replaced when the config changes
+    producer: Arc<RwLock<KafkaProducer>>,
+    spill: Option<Arc<SpillBuffer>>,
+    transactions: Option<Transactions>,
 }
 
 impl KafkaClient {
-    pub async fn new(config: &KafkaConfig) -> KafkaClient {
-        let mut client_config = ClientConfig::new();
-        client_config
-            .set("bootstrap.servers", config.url_string())
-            .set("message.timeout.ms", "12000")
-            .set("max.in.flight.requests.per.connection", "500");
-        if let Some(params) = config.config.as_ref() {
-            for (key, value) in params.iter() {
-                client_config.set(key, value);
-            }
+    pub async fn new(
+        config: &KafkaConfig,
+        spill_config: Option<&SpillConfig>,
+        fatal: &Fatal,
+    ) -> KafkaClient {
+        if config.transactions.is_some() && spill_config.is_some() {
+            panic!("Kafka transactions can not be combined with a spill buffer");
+        }
+        let producer = create_producer(config).unwrap_or_else(|err| panic!("{}", err));
+        let transactions = config.transactions.as_ref().map(|transaction_config| {
+            Transactions::start(producer.clone(), transaction_config, fatal)
+        });
+
+        let spill = spill_config.map(|config| Arc::new(SpillBuffer::open(config)));
+        let client = KafkaClient {
+            producer: Arc::new(RwLock::new(producer)),
+            spill,
+            transactions,
+        };
+        if client.spill.is_some() {
+            let c = client.clone();
+            let f = fatal.clone();
+            tokio::spawn(async move {
+                c.drain_spill(f).await;
+            });
+        }
+        client
+    }
+
+    pub fn in_flight_messages(&self) -> i32 {
+        self.producer().in_flight_count()
+    }
+
+    pub fn spill_usage(&self) -> Option<SpillUsage> {
+        self.spill.as_ref().map(|spill| spill.usage())
+    }
+
+    pub async fn check_connection(&self, timeout: Duration) -> Result<(), String> {
+        let producer = self.producer();
+        tokio::task::spawn_blocking(move || {
+            producer
+                .client()
+                .fetch_metadata(None, rdkafka::util::Timeout::After(timeout))
+                .map(|_metadata| ())
+                .map_err(|err| err.to_string())
+        })
+        .await
+        .map_err(|err| err.to_string())?
+    }
+
+    // Creates a producer for the new config, it is only used once passed to `replace_producer`
+    pub async fn new_producer(&self, config: &KafkaConfig) -> Result<KafkaProducer, String> {
+        // A new producer with the same transactional id would fence the running one
+        if self.transactions.is_some() || config.transactions.is_some() {
+            return Err(
+                "Kafka settings can not be reloaded when transactions are used".to_string(),
+            );
         }
-        let producer: FutureProducer = client_config
-            .create()
-            .expect("KafkaProducer creation error");
-        // Check for connection
-        match producer
-            .client()
-            .fetch_metadata(None, rdkafka::util::Timeout::After(Duration::from_secs(5)))
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || create_producer(&config))
+            .await
+            .map_err(|err| format!("Could not create kafka producer: {err}"))?
+    }
+
+    // Messages already sent with the old producer are still delivered
+    pub async fn replace_producer(&self, producer: KafkaProducer) {
+        let old = std::mem::replace(
+            &mut *self.producer.write().expect("Kafka producer lock poisoned"),
+            producer,
+        );
+        match tokio::task::spawn_blocking(move || old.flush(Duration::from_secs(30))).await {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => log::error!("Could not drain old kafka producer: {}", err),
+            Err(err) => log::error!("Could not drain old kafka producer: {}", err),
+        }
+    }
+
+    fn producer(&self) -> KafkaProducer {
+        self.producer
+            .read()
+            .expect("Kafka producer lock poisoned")
+            .clone()
+    }
+
+    pub async fn produce(&self, message: &SinkMessage<'_>) -> ProduceOutcome {
+        let span = telemetry::start_span(
+            "produce",
+            SpanKind::Producer,
+            message.trace_context,
+            vec![
+                KeyValue::new("messaging.system", "kafka"),
+                KeyValue::new(
+                    "messaging.destination.name",
+                    message.kafka_topic.to_string(),
+                ),
+                KeyValue::new("forwarding", message.forwarding.to_string()),
+            ],
+        );
+        // Downstream consumers continue the trace from the traceparent header
+        let headers = telemetry::inject(&span, message.headers);
+        let outcome = self
+            .produce_message(&SinkMessage {
+                headers: &headers,
+                trace_context: &span,
+                ..*message
+            })
+            .await;
+        telemetry::end_span(
+            &span,
+            vec![KeyValue::new("outcome", format!("{outcome:?}"))],
+        );
+        outcome
+    }
+
+    async fn produce_message(&self, message: &SinkMessage<'_>) -> ProduceOutcome {
+        if is_expired(message.expires_at) {
+            expired(
+                message.forwarding,
+                message.subscription,
+                message.kafka_topic,
+            );
+            return ProduceOutcome::Expired;
+        }
+        // Keep ordering: as long as older messages are waiting in the spill buffer new ones have to queue up behind them
+        if let Some(spill) = self.spill.as_ref()
+            && !spill.is_empty()
         {
-            Ok(_) => (), // Got data, connection is established, nothing to do
-            Err(err) => {
-                panic!("Could not establish connection to kafka: {}", err);
+            spill.push(&message.spill_record()).await;
+            return ProduceOutcome::Spilled;
+        }
+        let labels = ForwardingLabels {
+            forwarding: message.forwarding.to_string(),
+        };
+        let started_at = Instant::now();
+        let mut attempts = 0;
+        let err = loop {
+            attempts += 1;
+            if attempts > 1 {
+                COUNT_KAFKA_RETRIES.get_or_create(&labels).inc();
             }
+            match self
+                .send(
+                    message.kafka_topic,
+                    message.key,
+                    message.payload,
+                    owned_headers(message.headers),
+                )
+                .await
+            {
+                Ok(()) => {
+                    KAFKA_PRODUCE_LATENCY
+                        .get_or_create(&labels)
+                        .observe(started_at.elapsed().as_secs_f64());
+                    return ProduceOutcome::Delivered;
+                }
+                Err(err) => {
+                    error!("Failed to send: {}", err);
+                    COUNT_KAFKA_PRODUCE_ERRORS
+                        .get_or_create(&ErrorLabels {
+                            forwarding: message.forwarding.to_string(),
+                            code: error_label(&err),
+                        })
+                        .inc();
+                    if !is_retryable(&err) || attempts >= MAX_ATTEMPTS {
+                        break err;
+                    }
+                    if is_expired(message.expires_at) {
+                        expired(
+                            message.forwarding,
+                            message.subscription,
+                            message.kafka_topic,
+                        );
+                        return ProduceOutcome::Expired;
+                    }
+                }
+            };
+        };
+        if is_retryable(&err)
+            && let Some(spill) = self.spill.as_ref()
+        {
+            log::warn!("Could not send message to kafka, writing it to the spill buffer");
+            spill.push(&message.spill_record()).await;
+            return ProduceOutcome::Spilled;
+        }
+        if let Some(dead_letter_topic) = message.dead_letter_topic
+            && self
+                .dead_letter(
+                    dead_letter_topic,
+                    &message.spill_record(),
+                    &err.to_string(),
+                    &error_code(&err),
+                    attempts,
+                )
+                .await
+        {
+            return ProduceOutcome::DeadLettered;
+        }
+        if is_retryable(&err) {
+            // Kafka is unavailable, the broker delivers the message again after the restart
+            panic!("Could not send a message. Aborting")
         }
+        // A message kafka will never accept must not stop the service
+        drop_rejected(message, &err.to_string());
+        ProduceOutcome::Dropped
+    }
 
-        KafkaClient { producer }
+    // Sends a message that can not be forwarded to its kafka topic directly to the dead-letter topic
+    pub async fn reject(&self, message: &SinkMessage<'_>, error: &str, error_code: &str) {
+        let dead_letter_topic = message
+            .dead_letter_topic
+            .expect("No dead-letter topic configured");
+        if !self
+            .dead_letter(
+                dead_letter_topic,
+                &message.spill_record(),
+                error,
+                error_code,
+                0,
+            )
+            .await
+        {
+            drop_rejected(message, error);
+        }
     }
 
-    pub fn in_flight_messages(&self) -> i32 {
-        self.producer.in_flight_count()
+    async fn send(
+        &self,
+        kafka_topic: &str,
+        key: Option<&str>,
+        payload: &[u8],
+        headers: OwnedHeaders,
+    ) -> Result<(), KafkaError> {
+        if let Some(transactions) = self.transactions.as_ref() {
+            return transactions.send(kafka_topic, key, payload, headers).await;
+        }
+        let mut record = FutureRecord::to(kafka_topic).payload(payload);
+        if let Some(key) = key {
+            record = record.key(key);
+        }
+        if headers.count() > 0 {
+            record = record.headers(headers);
+        }
+        let producer = self.producer();
+        let delivery_status = producer.send(record, Duration::from_secs(1)).await;
+        producer.poll(Duration::from_secs(0));
+        delivery_status
+            .map(|_delivery| ())
+            .map_err(|(err, _msg)| err)
     }
 
-    pub async fn produce(&mut self, kafka_topic: &str, mqtt_topic: &str, payload: &[u8]) {
-        for _ in 0..5 {
-            let delivery_status = self
-                .producer
+    async fn dead_letter(
+        &self,
+        dead_letter_topic: &str,
+        record: &SpillRecord,
+        error: &str,
+        error_code: &str,
+        attempts: u32,
+    ) -> bool {
+        let headers = owned_headers(&record.headers)
+            .insert(Header {
+                key: "forwarding.error",
+                value: Some(error),
+            })
+            .insert(Header {
+                key: "forwarding.error.code",
+                value: Some(error_code),
+            })
+            .insert(Header {
+                key: "forwarding.mqtt.topic",
+                value: Some(&record.mqtt_topic),
+            })
+            .insert(Header {
+                key: "forwarding.kafka.topic",
+                value: Some(&record.kafka_topic),
+            })
+            .insert(Header {
+                key: "forwarding.attempts",
+                value: Some(&attempts.to_string()),
+            });
+        for _ in 0..MAX_ATTEMPTS {
+            match self
                 .send(
-                    FutureRecord::to(kafka_topic)
-                        .payload(payload)
-                        .key(mqtt_topic),
-                    Duration::from_secs(1),
+                    dead_letter_topic,
+                    record.key.as_deref(),
+                    &record.payload,
+                    headers.clone(),
                 )
-                .await;
-            self.producer.poll(Duration::from_secs(0));
-            match delivery_status {
-                Ok(_delivery) => {
-                    return;
+                .await
+            {
+                Ok(()) => {
+                    log::warn!(
+                        "Sent message for kafka topic {} to dead-letter topic {}: {}",
+                        record.kafka_topic,
+                        dead_letter_topic,
+                        error
+                    );
+                    COUNT_KAFKA_DEAD_LETTERED
+                        .get_or_create(&MetricLabels::new(
+                            &record.forwarding,
+                            &record.subscription,
+                            &record.kafka_topic,
+                        ))
+                        .inc();
+                    return true;
                 }
-                Err((err, _msg)) => {
-                    error!("Failed to send: {}", err);
+                Err(err) => {
+                    error!("Failed to send to dead-letter topic: {}", err);
                 }
+            }
+        }
+        false
+    }
+
+    async fn drain_spill(&self, fatal: Fatal) {
+        let spill = self.spill.as_ref().expect("No spill buffer configured");
+        while fatal.is_running() {
+            let Some((position, record)) = spill.peek().await else {
+                spill.wait(Duration::from_secs(1)).await;
+                continue;
             };
+            if is_expired(record.expires_at) {
+                expired(
+                    &record.forwarding,
+                    &record.subscription,
+                    &record.kafka_topic,
+                );
+                spill.commit(position).await;
+                continue;
+            }
+            match self
+                .send(
+                    &record.kafka_topic,
+                    record.key.as_deref(),
+                    &record.payload,
+                    owned_headers(&record.headers),
+                )
+                .await
+            {
+                Ok(()) => spill.commit(position).await,
+                Err(err) if !is_retryable(&err) => {
+                    if let Some(dead_letter_topic) = record.dead_letter_topic.as_deref() {
+                        if self
+                            .dead_letter(
+                                dead_letter_topic,
+                                &record,
+                                &err.to_string(),
+                                &error_code(&err),
+                                1,
+                            )
+                            .await
+                        {
+                            spill.commit(position).await;
+                        } else {
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        }
+                    } else {
+                        // The message was already acknowledged to MQTT, retrying would block the buffer forever
+                        error!(
+                            "Kafka rejected message from spill buffer for topic {}, dropping it: {}",
+                            record.kafka_topic, err
+                        );
+                        COUNT_SPILL_DROPPED.inc();
+                        spill.commit(position).await;
+                    }
+                }
+                Err(err) => {
+                    log::warn!("Failed to send message from spill buffer: {}", err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
         }
-        // If we come here we failed to send the message
-        panic!("Could not send a message. Aborting")
     }
 }
+
+impl Sink for KafkaClient {
+    fn produce<'a>(&'a self, message: &'a SinkMessage<'a>) -> SinkFuture<'a, ProduceOutcome> {
+        Box::pin(KafkaClient::produce(self, message))
+    }
+
+    fn flush(&self) -> SinkFuture<'_, Result<(), String>> {
+        let producer = self.producer();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || producer.flush(Duration::from_secs(30)))
+                .await
+                .map_err(|err| err.to_string())?
+                .map_err(|err| err.to_string())
+        })
+    }
+
+    fn health(&self) -> SinkFuture<'_, ComponentHealth> {
+        Box::pin(async {
+            match self.check_connection(HEALTH_TIMEOUT).await {
+                Ok(()) => ComponentHealth {
+                    status: Status::Up,
+                    details: json!({ "metadata_reachable": true }),
+                },
+                Err(err) => ComponentHealth {
+                    status: Status::Down,
+                    details: json!({ "metadata_reachable": false, "error": err }),
+                },
+            }
+        })
+    }
+}
+
+fn drop_rejected(message: &SinkMessage, error: &str) {
+    log::error!(
+        "Dropping message from {} that could not be sent to kafka topic {}: {}",
+        message.mqtt_topic,
+        message.kafka_topic,
+        error
+    );
+    COUNT_KAFKA_DROPPED
+        .get_or_create(&MetricLabels::new(
+            message.forwarding,
+            message.subscription,
+            message.kafka_topic,
+        ))
+        .inc();
+}
+
+fn create_producer(config: &KafkaConfig) -> Result<KafkaProducer, String> {
+    let mut client_config = ClientConfig::new();
+    client_config
+        .set("bootstrap.servers", config.url_string())
+        .set("message.timeout.ms", "12000")
+        .set("max.in.flight.requests.per.connection", "500");
+    if let Some(transactions) = config.transactions.as_ref() {
+        client_config
+            .set("transactional.id", &transactions.transactional_id)
+            .set("enable.idempotence", "true")
+            // The idempotent producer only keeps ordering with at most 5 requests in flight
+            .set("max.in.flight.requests.per.connection", "5");
+    }
+    if let Some(params) = config.config.as_ref() {
+        for (key, value) in params.iter() {
+            client_config.set(key, value);
+        }
+    }
+    let producer: KafkaProducer = client_config
+        .create_with_context(StatsContext)
+        .map_err(|err| format!("KafkaProducer creation error: {err}"))?;
+    // Check for connection
+    producer
+        .client()
+        .fetch_metadata(None, rdkafka::util::Timeout::After(Duration::from_secs(5)))
+        .map_err(|err| format!("Could not establish connection to kafka: {err}"))?;
+    if config.transactions.is_some() {
+        producer
+            .init_transactions(Duration::from_secs(30))
+            .map_err(|err| format!("Could not initialize kafka transactions: {err}"))?;
+    }
+    Ok(producer)
+}
+
+fn owned_headers(headers: &[(String, Vec<u8>)]) -> OwnedHeaders {
+    headers.iter().fold(
+        OwnedHeaders::new_with_capacity(headers.len()),
+        |owned, (key, value)| {
+            owned.insert(Header {
+                key,
+                value: Some(value),
+            })
+        },
+    )
+}
+
+fn error_code(err: &KafkaError) -> String {
+    err.rdkafka_error_code()
+        .map(|code| format!("{code:?}"))
+        .unwrap_or_default()
+}
+
+fn error_label(err: &KafkaError) -> String {
+    err.rdkafka_error_code()
+        .map(|code| format!("{code:?}"))
+        .unwrap_or_else(|| "Unknown".to_string())
+}
+
+// Errors where sending the same message again will not succeed
+pub fn is_retryable(err: &KafkaError) -> bool {
+    !matches!(
+        err.rdkafka_error_code(),
+        Some(
+            RDKafkaErrorCode::MessageSizeTooLarge
+                | RDKafkaErrorCode::InvalidMessageSize
+                | RDKafkaErrorCode::InvalidMessage
+                | RDKafkaErrorCode::InvalidRecord
+                | RDKafkaErrorCode::InvalidTopic
+                | RDKafkaErrorCode::UnknownTopic
+                | RDKafkaErrorCode::UnknownTopicOrPartition
+                | RDKafkaErrorCode::TopicAuthorizationFailed
+                | RDKafkaErrorCode::ClusterAuthorizationFailed
+                | RDKafkaErrorCode::PolicyViolation
+        )
+    )
+}
@@ -0,0 +1,93 @@
+use crate::config::{KeyConfig, KeyFallback, KeyStrategyConfig};
+use crate::template::{TopicPattern, TopicTemplate};
+use serde_json::Value;
+
+#[derive(Clone, Debug)]
+enum KeyStrategy {
+    Topic,
+    Segment(TopicTemplate),
+    JsonPointer(String),
+    Fixed(String),
+    None,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeyResult {
+    Key(Option<String>),
+    Drop,
+    DeadLetter(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyExtractor {
+    strategy: KeyStrategy,
+    fallback: KeyFallback,
+}
+
+impl KeyExtractor {
+    pub fn new(config: Option<&KeyConfig>, pattern: &TopicPattern) -> Result<KeyExtractor, String> {
+        let Some(config) = config else {
+            return Ok(KeyExtractor {
+                strategy: KeyStrategy::Topic,
+                fallback: KeyFallback::Topic,
+            });
+        };
+        let strategy = match &config.strategy {
+            KeyStrategyConfig::Topic => KeyStrategy::Topic,
+            KeyStrategyConfig::Segment { template } => {
+                KeyStrategy::Segment(TopicTemplate::parse(template, pattern)?)
+            }
+            KeyStrategyConfig::JsonPointer { pointer } => {
+                if !pointer.is_empty() && !pointer.starts_with('/') {
+                    return Err(format!("JSON pointer '{pointer}' must start with '/'"));
+                }
+                KeyStrategy::JsonPointer(pointer.clone())
+            }
+            KeyStrategyConfig::Fixed { value } => KeyStrategy::Fixed(value.clone()),
+            KeyStrategyConfig::None => KeyStrategy::None,
+        };
+        Ok(KeyExtractor {
+            strategy,
+            fallback: config.fallback.unwrap_or(KeyFallback::Topic),
+        })
+    }
+
+    pub fn needs_dead_letter_topic(&self) -> bool {
+        self.fallback == KeyFallback::DeadLetter
+    }
+
+    pub fn needs_json_payload(&self) -> bool {
+        matches!(self.strategy, KeyStrategy::JsonPointer(_))
+    }
+
+    pub fn extract(&self, mqtt_topic: &str, payload: &[u8]) -> KeyResult {
+        let key = match &self.strategy {
+            KeyStrategy::Topic => return KeyResult::Key(Some(mqtt_topic.to_string())),
+            KeyStrategy::Fixed(value) => return KeyResult::Key(Some(value.clone())),
+            KeyStrategy::None => return KeyResult::Key(None),
+            KeyStrategy::Segment(template) => template
+                .render(mqtt_topic, str::to_string)
+                .ok_or_else(|| format!("Topic {mqtt_topic} has no segment for the key")),
+            KeyStrategy::JsonPointer(pointer) => json_key(payload, pointer),
+        };
+        match key {
+            Ok(key) => KeyResult::Key(Some(key)),
+            Err(reason) => match self.fallback {
+                KeyFallback::Topic => KeyResult::Key(Some(mqtt_topic.to_string())),
+                KeyFallback::None => KeyResult::Key(None),
+                KeyFallback::Drop => KeyResult::Drop,
+                KeyFallback::DeadLetter => KeyResult::DeadLetter(reason),
+            },
+        }
+    }
+}
+
+fn json_key(payload: &[u8], pointer: &str) -> Result<String, String> {
+    let value = serde_json::from_slice::<Value>(payload)
+        .map_err(|err| format!("Payload is not valid JSON: {err}"))?;
+    match value.pointer(pointer) {
+        Some(Value::String(key)) => Ok(key.clone()),
+        Some(Value::Null) | None => Err(format!("Payload has no value at {pointer}")),
+        Some(value) => Ok(value.to_string()),
+    }
+}
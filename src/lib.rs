@@ -0,0 +1,38 @@
+mod api;
+mod avro;
+pub mod config;
+mod connection;
+mod dispatcher;
+mod envelope;
+mod events;
+mod fatal;
+mod file_sink;
+mod forwarder;
+mod forwardings;
+mod health;
+mod http_sink;
+mod kafka;
+mod key;
+mod metrics;
+mod mqtt;
+mod protobuf;
+mod reload;
+mod reverse;
+mod routing;
+mod schema_registry;
+mod sink;
+mod spill;
+mod statistics;
+mod telemetry;
+mod template;
+mod transaction;
+mod transcode;
+mod transform;
+
+pub use config::Config;
+pub use events::ForwarderEvent;
+pub use forwarder::{Forwarder, ForwarderBuilder};
+pub use forwardings::ForwardingError;
+pub use health::{ComponentHealth, Status};
+pub use kafka::{KafkaClient, ProduceOutcome};
+pub use sink::{Sink, SinkFuture, SinkMessage};
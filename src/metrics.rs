@@ -1,27 +1,189 @@
+use crate::config::MetricsConfig;
 use lazy_static::lazy_static;
 use prometheus_client::encoding::text::encode;
 use prometheus_client::encoding::EncodeLabelSet;
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
 use prometheus_client::registry::Registry;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use tokio::sync::Mutex;
 
+static DEFAULT_MAX_TOPIC_SERIES: usize = 1000;
+static OTHER_TOPIC: &str = "__other__";
+static METRICS_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+// The topic label is empty unless per-topic labels are enabled, so wildcard subscriptions do not create a series per topic
 #[derive(Clone, Hash, PartialEq, Eq, Debug, EncodeLabelSet)]
 pub struct MetricLabels {
+    pub forwarding: String,
+    pub subscription: String,
+    pub topic: String,
+}
+
+impl MetricLabels {
+    pub fn new(forwarding: &str, subscription: &str, topic: &str) -> MetricLabels {
+        MetricLabels {
+            forwarding: forwarding.to_string(),
+            subscription: subscription.to_string(),
+            topic: topic_label(topic),
+        }
+    }
+}
+
+struct TopicLabels {
+    enabled: bool,
+    max_series: usize,
+    seen: HashSet<String>,
+}
+
+pub fn configure_topic_labels(config: Option<&MetricsConfig>) {
+    let mut labels = TOPIC_LABELS.lock().unwrap();
+    labels.enabled = config
+        .and_then(|config| config.topic_labels)
+        .unwrap_or(false);
+    labels.max_series = config
+        .and_then(|config| config.max_topic_series)
+        .unwrap_or(DEFAULT_MAX_TOPIC_SERIES);
+}
+
+// Topics beyond the configured maximum are aggregated into a single label value
+fn topic_label(topic: &str) -> String {
+    let mut labels = TOPIC_LABELS.lock().unwrap();
+    if !labels.enabled {
+        return String::new();
+    }
+    if labels.seen.contains(topic) {
+        return topic.to_string();
+    }
+    if labels.seen.len() < labels.max_series {
+        labels.seen.insert(topic.to_string());
+        return topic.to_string();
+    }
+    OTHER_TOPIC.to_string()
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Debug, EncodeLabelSet)]
+pub struct ForwardingLabels {
+    pub forwarding: String,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Debug, EncodeLabelSet)]
+pub struct ErrorLabels {
+    pub forwarding: String,
+    pub code: String,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Debug, EncodeLabelSet)]
+pub struct BrokerLabels {
+    pub broker: String,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Debug, EncodeLabelSet)]
+pub struct KafkaTopicLabels {
     pub topic: String,
 }
 
+type HistogramFamily = Family<ForwardingLabels, Histogram, fn() -> Histogram>;
+
+fn latency_histogram() -> Histogram {
+    // 1ms to ~33s
+    Histogram::new(exponential_buckets(0.001, 2.0, 16))
+}
+
+fn size_histogram() -> Histogram {
+    // 16B to 4MiB
+    Histogram::new(exponential_buckets(16.0, 4.0, 10))
+}
+
 lazy_static! {
     static ref REGISTRY: Mutex<Registry> = Mutex::new(<Registry>::default());
+    static ref TOPIC_LABELS: std::sync::Mutex<TopicLabels> = std::sync::Mutex::new(TopicLabels {
+        enabled: false,
+        max_series: DEFAULT_MAX_TOPIC_SERIES,
+        seen: HashSet::new(),
+    });
     pub static ref COUNT_MQTT_RECEIVED: Family<MetricLabels, Counter> =
         Family::<MetricLabels, Counter>::default();
     pub static ref COUNT_KAFKA_PUBLISHED: Family<MetricLabels, Counter> =
         Family::<MetricLabels, Counter>::default();
+    pub static ref COUNT_KAFKA_DEAD_LETTERED: Family<MetricLabels, Counter> =
+        Family::<MetricLabels, Counter>::default();
+    pub static ref COUNT_KAFKA_TOPIC_REJECTED: Family<MetricLabels, Counter> =
+        Family::<MetricLabels, Counter>::default();
+    pub static ref COUNT_KAFKA_KEY_MISSING: Family<MetricLabels, Counter> =
+        Family::<MetricLabels, Counter>::default();
+    pub static ref COUNT_MQTT_EXPIRED: Family<MetricLabels, Counter> =
+        Family::<MetricLabels, Counter>::default();
+    pub static ref COUNT_REVERSE_PUBLISHED: Family<MetricLabels, Counter> =
+        Family::<MetricLabels, Counter>::default();
+    pub static ref COUNT_REVERSE_REJECTED: Family<MetricLabels, Counter> =
+        Family::<MetricLabels, Counter>::default();
+    pub static ref FORWARDING_LATENCY: HistogramFamily =
+        HistogramFamily::new_with_constructor(latency_histogram);
+    pub static ref KAFKA_PRODUCE_LATENCY: HistogramFamily =
+        HistogramFamily::new_with_constructor(latency_histogram);
+    pub static ref PAYLOAD_SIZE: HistogramFamily =
+        HistogramFamily::new_with_constructor(size_histogram);
+    pub static ref COUNT_KAFKA_PRODUCE_ERRORS: Family<ErrorLabels, Counter> =
+        Family::<ErrorLabels, Counter>::default();
+    pub static ref COUNT_KAFKA_RETRIES: Family<ForwardingLabels, Counter> =
+        Family::<ForwardingLabels, Counter>::default();
+    pub static ref COUNT_MQTT_RECONNECTS: Counter = Counter::default();
+    pub static ref COUNT_MQTT_ACKS: Family<ForwardingLabels, Counter> =
+        Family::<ForwardingLabels, Counter>::default();
     pub static ref MQTT_CONNECTED: Gauge = Gauge::default();
+    pub static ref SPILL_QUEUED_BYTES: Gauge = Gauge::default();
+    pub static ref SPILL_QUEUED_MESSAGES: Gauge = Gauge::default();
+    pub static ref COUNT_SPILL_DROPPED: Counter = Counter::default();
+    pub static ref COUNT_SPILL_CORRUPT: Counter = Counter::default();
+    pub static ref COUNT_KAFKA_DROPPED: Family<MetricLabels, Counter> =
+        Family::<MetricLabels, Counter>::default();
+    pub static ref COUNT_SINK_DROPPED: Family<MetricLabels, Counter> =
+        Family::<MetricLabels, Counter>::default();
+    pub static ref COUNT_TRANSFORM_ERRORS: Family<MetricLabels, Counter> =
+        Family::<MetricLabels, Counter>::default();
+    pub static ref COUNT_FORMAT_ERRORS: Family<MetricLabels, Counter> =
+        Family::<MetricLabels, Counter>::default();
+    pub static ref COUNT_CONFIG_RELOAD_ERRORS: Counter = Counter::default();
+    pub static ref CONFIG_RELOAD_FAILED: Gauge = Gauge::default();
+    pub static ref KAFKA_QUEUE_MESSAGES: Gauge = Gauge::default();
+    pub static ref KAFKA_QUEUE_BYTES: Gauge = Gauge::default();
+    pub static ref KAFKA_SENT_MESSAGES: Gauge = Gauge::default();
+    pub static ref KAFKA_SENT_BYTES: Gauge = Gauge::default();
+    pub static ref KAFKA_BROKER_UP: Family<BrokerLabels, Gauge> =
+        Family::<BrokerLabels, Gauge>::default();
+    pub static ref KAFKA_BROKER_RTT_AVG: Family<BrokerLabels, Gauge<f64, AtomicU64>> =
+        Family::<BrokerLabels, Gauge<f64, AtomicU64>>::default();
+    pub static ref KAFKA_BROKER_RTT_P99: Family<BrokerLabels, Gauge<f64, AtomicU64>> =
+        Family::<BrokerLabels, Gauge<f64, AtomicU64>>::default();
+    pub static ref KAFKA_BROKER_OUTBUF_MESSAGES: Family<BrokerLabels, Gauge> =
+        Family::<BrokerLabels, Gauge>::default();
+    pub static ref KAFKA_BROKER_WAITRESP_MESSAGES: Family<BrokerLabels, Gauge> =
+        Family::<BrokerLabels, Gauge>::default();
+    pub static ref KAFKA_BROKER_TX_ERRORS: Family<BrokerLabels, Gauge> =
+        Family::<BrokerLabels, Gauge>::default();
+    pub static ref KAFKA_BROKER_TX_RETRIES: Family<BrokerLabels, Gauge> =
+        Family::<BrokerLabels, Gauge>::default();
+    pub static ref KAFKA_BROKER_REQUEST_TIMEOUTS: Family<BrokerLabels, Gauge> =
+        Family::<BrokerLabels, Gauge>::default();
+    pub static ref KAFKA_TOPIC_BATCH_SIZE: Family<KafkaTopicLabels, Gauge> =
+        Family::<KafkaTopicLabels, Gauge>::default();
+    pub static ref KAFKA_TOPIC_BATCH_MESSAGES: Family<KafkaTopicLabels, Gauge> =
+        Family::<KafkaTopicLabels, Gauge>::default();
+    pub static ref KAFKA_TOPIC_QUEUE_MESSAGES: Family<KafkaTopicLabels, Gauge> =
+        Family::<KafkaTopicLabels, Gauge>::default();
+    pub static ref KAFKA_TOPIC_SENT_MESSAGES: Family<KafkaTopicLabels, Gauge> =
+        Family::<KafkaTopicLabels, Gauge>::default();
 }
 
 pub async fn init_metrics() {
+    // Metrics are global, so they are only registered by the first forwarder of the process
+    if METRICS_REGISTERED.swap(true, Ordering::AcqRel) {
+        return;
+    }
     let mut registry = REGISTRY.lock().await;
     registry.register(
         "forwarding_mqtt_received",
@@ -33,11 +195,206 @@ pub async fn init_metrics() {
         "Number of messages published to kafka",
         COUNT_KAFKA_PUBLISHED.clone(),
     );
+    registry.register(
+        "forwarding_kafka_dead_lettered",
+        "Number of messages sent to a dead-letter topic instead of their kafka topic",
+        COUNT_KAFKA_DEAD_LETTERED.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_topic_rejected",
+        "Number of messages not forwarded because the resolved kafka topic was invalid or not allowed",
+        COUNT_KAFKA_TOPIC_REJECTED.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_key_missing",
+        "Number of messages for which the configured kafka key could not be determined",
+        COUNT_KAFKA_KEY_MISSING.clone(),
+    );
+    registry.register(
+        "forwarding_mqtt_expired",
+        "Number of messages dropped because their MQTT message expiry interval elapsed",
+        COUNT_MQTT_EXPIRED.clone(),
+    );
+    registry.register(
+        "forwarding_reverse_published",
+        "Number of messages from kafka published to mqtt",
+        COUNT_REVERSE_PUBLISHED.clone(),
+    );
+    registry.register(
+        "forwarding_reverse_rejected",
+        "Number of messages from kafka skipped because no mqtt topic could be built for them",
+        COUNT_REVERSE_REJECTED.clone(),
+    );
+    registry.register(
+        "forwarding_latency_seconds",
+        "Time from receiving a message from mqtt until kafka confirmed its delivery",
+        FORWARDING_LATENCY.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_produce_latency_seconds",
+        "Time from sending a message to kafka until kafka confirmed its delivery, including retries",
+        KAFKA_PRODUCE_LATENCY.clone(),
+    );
+    registry.register(
+        "forwarding_payload_size_bytes",
+        "Size of the payloads sent to kafka",
+        PAYLOAD_SIZE.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_produce_errors",
+        "Number of failed attempts to send a message to kafka by error code",
+        COUNT_KAFKA_PRODUCE_ERRORS.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_retries",
+        "Number of times sending a message to kafka was retried",
+        COUNT_KAFKA_RETRIES.clone(),
+    );
+    registry.register(
+        "forwarding_mqtt_reconnects",
+        "Number of times the connection to the MQTT broker was reestablished",
+        COUNT_MQTT_RECONNECTS.clone(),
+    );
+    registry.register(
+        "forwarding_mqtt_acks",
+        "Number of acknowledgements sent to the MQTT broker",
+        COUNT_MQTT_ACKS.clone(),
+    );
     registry.register(
         "forwarding_mqtt_connected",
         "Is the connection to the MQTT broker active",
         MQTT_CONNECTED.clone(),
     );
+    registry.register(
+        "forwarding_spill_queued_bytes",
+        "Number of bytes waiting in the spill buffer to be sent to kafka",
+        SPILL_QUEUED_BYTES.clone(),
+    );
+    registry.register(
+        "forwarding_spill_queued_messages",
+        "Number of messages waiting in the spill buffer to be sent to kafka",
+        SPILL_QUEUED_MESSAGES.clone(),
+    );
+    registry.register(
+        "forwarding_spill_dropped",
+        "Number of messages dropped from the spill buffer because it was full or kafka rejected them",
+        COUNT_SPILL_DROPPED.clone(),
+    );
+    registry.register(
+        "forwarding_spill_corrupt",
+        "Number of corrupt records or unreadable segment ends skipped in the spill buffer",
+        COUNT_SPILL_CORRUPT.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_dropped",
+        "Number of messages dropped because kafka rejected them and they could not be dead-lettered",
+        COUNT_KAFKA_DROPPED.clone(),
+    );
+    registry.register(
+        "forwarding_sink_dropped",
+        "Number of messages dropped because a sink other than kafka rejected them",
+        COUNT_SINK_DROPPED.clone(),
+    );
+    registry.register(
+        "forwarding_transform_errors",
+        "Number of messages whose payload transforms failed",
+        COUNT_TRANSFORM_ERRORS.clone(),
+    );
+    registry.register(
+        "forwarding_format_errors",
+        "Number of messages whose payload could not be converted to the format of their forwarding",
+        COUNT_FORMAT_ERRORS.clone(),
+    );
+    registry.register(
+        "forwarding_config_reload_errors",
+        "Number of config reloads that were rejected because the new config was invalid",
+        COUNT_CONFIG_RELOAD_ERRORS.clone(),
+    );
+    registry.register(
+        "forwarding_config_reload_failed",
+        "Was the last config reload rejected, the previous config is still active in this case",
+        CONFIG_RELOAD_FAILED.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_queue_messages",
+        "Number of messages waiting in the kafka producer queue, from librdkafka statistics",
+        KAFKA_QUEUE_MESSAGES.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_queue_bytes",
+        "Size of the messages waiting in the kafka producer queue, from librdkafka statistics",
+        KAFKA_QUEUE_BYTES.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_sent_messages",
+        "Number of messages sent to kafka brokers by the current producer, from librdkafka statistics",
+        KAFKA_SENT_MESSAGES.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_sent_bytes",
+        "Size of the messages sent to kafka brokers by the current producer, from librdkafka statistics",
+        KAFKA_SENT_BYTES.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_broker_up",
+        "Is the connection to the kafka broker up, from librdkafka statistics",
+        KAFKA_BROKER_UP.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_broker_rtt_avg_seconds",
+        "Average round-trip time of requests to the kafka broker, from librdkafka statistics",
+        KAFKA_BROKER_RTT_AVG.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_broker_rtt_p99_seconds",
+        "99th percentile of the round-trip time of requests to the kafka broker, from librdkafka statistics",
+        KAFKA_BROKER_RTT_P99.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_broker_outbuf_messages",
+        "Number of messages waiting to be sent to the kafka broker, from librdkafka statistics",
+        KAFKA_BROKER_OUTBUF_MESSAGES.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_broker_waitresp_messages",
+        "Number of messages sent to the kafka broker that wait for a response, from librdkafka statistics",
+        KAFKA_BROKER_WAITRESP_MESSAGES.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_broker_tx_errors",
+        "Number of failed requests to the kafka broker by the current producer, from librdkafka statistics",
+        KAFKA_BROKER_TX_ERRORS.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_broker_tx_retries",
+        "Number of retried requests to the kafka broker by the current producer, from librdkafka statistics",
+        KAFKA_BROKER_TX_RETRIES.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_broker_request_timeouts",
+        "Number of timed out requests to the kafka broker by the current producer, from librdkafka statistics",
+        KAFKA_BROKER_REQUEST_TIMEOUTS.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_topic_batch_size_bytes",
+        "Average size of the batches sent to the kafka topic, from librdkafka statistics",
+        KAFKA_TOPIC_BATCH_SIZE.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_topic_batch_messages",
+        "Average number of messages in the batches sent to the kafka topic, from librdkafka statistics",
+        KAFKA_TOPIC_BATCH_MESSAGES.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_topic_queue_messages",
+        "Number of messages for the kafka topic waiting in the producer, from librdkafka statistics",
+        KAFKA_TOPIC_QUEUE_MESSAGES.clone(),
+    );
+    registry.register(
+        "forwarding_kafka_topic_sent_messages",
+        "Number of messages sent to the kafka topic by the current producer, from librdkafka statistics",
+        KAFKA_TOPIC_SENT_MESSAGES.clone(),
+    );
     MQTT_CONNECTED.set(1); // During initialization MQTT is always connected otherwise it wouldn't get to this point
 }
 
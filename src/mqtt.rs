@@ -1,43 +1,39 @@
-use crate::config::{ForwardingConfig, MqttConfig, MqttTlsConfig};
-use crate::kafka::KafkaClient;
-use crate::metrics::{MetricLabels, COUNT_KAFKA_PUBLISHED, COUNT_MQTT_RECEIVED, MQTT_CONNECTED};
-use base64::prelude::*;
-use rumqttc::{
-    matches, AsyncClient, Event, EventLoop, MqttOptions, Packet, Publish, QoS,
-    SubscribeFilter, TlsConfiguration, Transport,
-};
-use serde::{Deserialize, Serialize};
+use crate::config::{ForwardingConfig, MqttConfig, OrderingConfig};
+use crate::connection::{MqttEvent, MqttEventLoop, MqttHandle, MqttMessage, ProtocolVersion};
+use crate::dispatcher::{Delivery, Dispatcher};
+use crate::events::{Events, ForwarderEvent};
+use crate::fatal::Fatal;
+use crate::forwardings::Forwardings;
+use crate::metrics::{MetricLabels, COUNT_MQTT_RECEIVED, COUNT_MQTT_RECONNECTS, MQTT_CONNECTED};
+use crate::reverse::{MqttPublisher, PublishTracker};
+use crate::routing::matching_topics;
+use crate::sink::Sinks;
+use crate::telemetry;
+use opentelemetry::trace::SpanKind;
+use opentelemetry::KeyValue;
+use rumqttc::matches;
 use std::{
     sync::{
         atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 static MAX_IN_FLIGHT: u16 = 10;
 
-#[derive(Clone, Debug)]
-struct TopicMatch {
-    mqtt_topic: String,
-    kafka_topic: String,
-    wrap_as_json: bool,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct WrappedPayload {
-    topic: String,
-    payload: String,
-}
-
 pub struct MqttClient {
-    client: AsyncClient,
-    eventloop: EventLoop,
+    client_id: Arc<str>,
+    client: MqttHandle,
+    eventloop: MqttEventLoop,
     stats: Arc<Stats>,
-    topic_config: Vec<TopicMatch>,
+    forwardings: Arc<Forwardings>,
+    publish_tracker: Arc<PublishTracker>,
+    ordering: Option<OrderingConfig>,
+    events: Events,
 }
 
-struct Stats {
+pub struct Stats {
     pub count_received: AtomicU64,
     pub count_published: AtomicU64,
     pub in_flight: AtomicI32,
@@ -56,51 +52,18 @@ impl Stats {
     }
 }
 
-fn init_tls_transport(config: MqttTlsConfig) -> Transport {
-    let ca_cert = std::fs::read_to_string(&config.ca_cert).expect("Could not read CA cert file");
-
-    let client_auth = if let Some(client_cert) = config.client_cert {
-        let client_cert = std::fs::read_to_string(client_cert).expect("Could not read client cert");
-
-        if let Some(client_key) = config.client_key {
-            let client_key =
-                std::fs::read_to_string(client_key).expect("Could not read client key");
-            Some((client_cert.into_bytes(), client_key.into_bytes()))
-        } else {
-            None
-        }
-    } else {
-        None
-    };
-    Transport::Tls(TlsConfiguration::Simple {
-        ca: ca_cert.into_bytes(),
-        alpn: None,
-        client_auth,
-    })
-}
-
 impl MqttClient {
     pub async fn new(
         config: &MqttConfig,
         forwardings: Vec<ForwardingConfig>,
+        ordering: Option<OrderingConfig>,
+        sinks: &Sinks,
+        persist_forwardings: bool,
+        events: Events,
         running: Arc<AtomicBool>,
     ) -> MqttClient {
-        let mut mqttoptions =
-            MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
-        mqttoptions
-            .set_clean_session(config.clean_session())
-            .set_inflight(MAX_IN_FLIGHT)
-            .set_manual_acks(true);
-
-        if let Some(tlsconfig) = config.tls.as_ref() {
-            log::debug!("Using TLS for MQTT connection");
-            mqttoptions.set_transport(init_tls_transport(tlsconfig.clone()));
-        }
-        if let Some(credentials) = config.credentials.as_ref() {
-            mqttoptions.set_credentials(&credentials.username, &credentials.password);
-        }
-
-        let (client, mut eventloop) = AsyncClient::new(mqttoptions, MAX_IN_FLIGHT as usize);
+        let (client, mut eventloop) = crate::connection::connect(config, MAX_IN_FLIGHT);
+        let protocol_version = ProtocolVersion::from_config(config);
 
         // Do one poll to check if the connection is established
         match eventloop.poll().await {
@@ -110,14 +73,14 @@ impl MqttClient {
             }
         }
 
-        let topic_config = forwardings
-            .iter()
-            .map(|forwarding_config| TopicMatch {
-                mqtt_topic: forwarding_config.mqtt.topic.clone(),
-                kafka_topic: forwarding_config.kafka.topic.clone(),
-                wrap_as_json: forwarding_config.wrap_as_json.unwrap_or(false),
-            })
-            .collect::<Vec<TopicMatch>>();
+        let forwardings = Forwardings::new(
+            client.clone(),
+            protocol_version,
+            forwardings,
+            sinks.clone(),
+            persist_forwardings,
+        )
+        .unwrap_or_else(|err| panic!("Invalid forwarding config: {}", err));
 
         let stats = Arc::new(Stats::new());
         let s = stats.clone();
@@ -126,35 +89,60 @@ impl MqttClient {
         });
 
         MqttClient {
+            client_id: Arc::from(config.client_id.as_str()),
             client,
             eventloop,
             stats,
-            topic_config,
+            forwardings: Arc::new(forwardings),
+            publish_tracker: Arc::new(PublishTracker::default()),
+            ordering,
+            events,
         }
     }
 
+    pub fn forwardings(&self) -> Arc<Forwardings> {
+        self.forwardings.clone()
+    }
+
+    pub fn publisher(&self) -> MqttPublisher {
+        MqttPublisher::new(self.client.clone(), self.publish_tracker.clone())
+    }
+
     pub async fn subscribe(&mut self) {
-        let subscribe_filter = self.topic_config.iter().map(|topic_match| {
-            SubscribeFilter::new(topic_match.mqtt_topic.clone(), QoS::ExactlyOnce)
-        });
-        self.client
-            .subscribe_many(subscribe_filter)
+        let subscriptions = self.forwardings.subscriptions();
+        if subscriptions.is_empty() {
+            log::warn!("No active forwardings, not subscribing to any mqtt topics");
+            crate::health::subscribed();
+            return;
+        }
+        self.forwardings
+            .subscribe(subscriptions)
             .await
             .expect("Error while subscribing to mqtt topics");
     }
 
-    pub async fn run(&mut self, kafka: KafkaClient, running: Arc<AtomicBool>) {
-        while running.load(Ordering::Relaxed) {
+    pub async fn run(&mut self, sinks: Sinks, fatal: Fatal) {
+        let dispatcher = Dispatcher::new(
+            self.ordering.as_ref(),
+            sinks.clone(),
+            self.client.clone(),
+            self.stats.clone(),
+            self.events.clone(),
+            fatal.clone(),
+        );
+        while fatal.is_running() {
+            crate::health::heartbeat();
             tokio::select! {
                 poll_result = self.eventloop.poll() => {
                     match poll_result {
                         Ok(event) => {
-                            self.handle_event(&kafka, event).await;
+                            self.handle_event(&sinks, &dispatcher, event).await;
                         },
                         Err(err) => {
                             let old = MQTT_CONNECTED.set(0);
                             if old > 0 {
                                 log::warn!("Lost connection to MQTT: {}", err);
+                                self.events.emit(|| ForwarderEvent::MqttDisconnected);
                             }
                             tokio::time::sleep(Duration::from_secs(1)).await;
                         },
@@ -163,76 +151,124 @@ impl MqttClient {
                 _ = tokio::time::sleep(Duration::from_secs(2)) => (),
             }
         }
+
+        // The event loop has to keep running to send the acks of messages that are still being forwarded
+        let drain = dispatcher.drain();
+        tokio::pin!(drain);
+        loop {
+            tokio::select! {
+                _ = &mut drain => break,
+                poll_result = self.eventloop.poll() => {
+                    match poll_result {
+                        // Not acknowledged, the broker delivers it again after a restart
+                        Ok(MqttEvent::Publish(_)) => (),
+                        Ok(event) => self.handle_event(&sinks, &dispatcher, event).await,
+                        Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+                    }
+                }
+            }
+        }
     }
 
-    async fn handle_event(&mut self, kafka: &KafkaClient, event: Event) {
+    async fn handle_event(&mut self, sinks: &Sinks, dispatcher: &Dispatcher, event: MqttEvent) {
         match event {
-            Event::Incoming(Packet::Publish(publish)) => {
-                self.handle_publish(kafka, publish).await;
+            MqttEvent::Publish(publish) => {
+                self.handle_publish(sinks, dispatcher, *publish).await;
             }
-            Event::Incoming(Packet::SubAck(_)) => {
-                log::info!("Subscribed to MQTT topics successfully");
+            MqttEvent::Published(pkid) => self.publish_tracker.published(pkid),
+            MqttEvent::PubAck(pkid) | MqttEvent::PubComp(pkid) => {
+                self.publish_tracker.acknowledged(pkid)
             }
-            Event::Incoming(Packet::ConnAck(_)) => {
+            MqttEvent::Subscribed(pkid) => self.forwardings.tracker().subscribed(pkid),
+            MqttEvent::SubAck(pkid, granted) => {
+                let rejected = granted.iter().filter(|granted| !**granted).count();
+                if rejected > 0 {
+                    log::error!(
+                        "MQTT broker rejected {rejected} of {} topics",
+                        granted.len()
+                    );
+                } else {
+                    log::info!("Subscribed to MQTT topics successfully");
+                }
+                self.forwardings.tracker().acknowledged(pkid, granted);
+                crate::health::subscribed();
+                self.events.emit(|| ForwarderEvent::MqttSubscribed);
+            }
+            MqttEvent::ConnAck => {
                 log::info!("Reconnected to MQTT broker");
+                COUNT_MQTT_RECONNECTS.inc();
                 MQTT_CONNECTED.set(1);
+                self.events.emit(|| ForwarderEvent::MqttConnected);
             }
-            Event::Incoming(Packet::Disconnect) => {
+            MqttEvent::Disconnect => {
                 MQTT_CONNECTED.set(0);
                 log::warn!("Got disconnect from MQTT broker");
+                self.events.emit(|| ForwarderEvent::MqttDisconnected);
             }
-            _ => (),
+            MqttEvent::Other => (),
         }
     }
 
-    async fn handle_publish(&mut self, kafka: &KafkaClient, publish: Publish) {
+    async fn handle_publish(
+        &mut self,
+        sinks: &Sinks,
+        dispatcher: &Dispatcher,
+        publish: MqttMessage,
+    ) {
+        let received_at = SystemTime::now();
         self.stats.count_received.fetch_add(1, Ordering::Relaxed);
-        COUNT_MQTT_RECEIVED
-            .get_or_create(&MetricLabels {
-                topic: publish.topic.clone(),
-            })
-            .inc();
-        let kafka_topics = matching_topics(&publish.topic, &self.topic_config);
+        let topic_matches = self.forwardings.topic_matches();
+        let mut matched = false;
+        for topic_match in topic_matches
+            .iter()
+            .filter(|topic_match| matches(&publish.topic, &topic_match.subscription.topic))
+        {
+            matched = true;
+            COUNT_MQTT_RECEIVED
+                .get_or_create(&MetricLabels::new(
+                    &topic_match.name,
+                    &topic_match.subscription.topic,
+                    &publish.topic,
+                ))
+                .inc();
+        }
+        if !matched {
+            COUNT_MQTT_RECEIVED
+                .get_or_create(&MetricLabels::new("", "", &publish.topic))
+                .inc();
+        }
+        let receive = telemetry::start_span(
+            "receive",
+            SpanKind::Consumer,
+            &telemetry::extract(&publish),
+            vec![
+                KeyValue::new("messaging.system", "mqtt"),
+                KeyValue::new("messaging.destination.name", publish.topic.clone()),
+                KeyValue::new("mqtt.qos", publish.qos as i64),
+            ],
+        );
+        let route = telemetry::start_span("route", SpanKind::Internal, &receive, Vec::new());
+        let routes = matching_topics(&publish.topic, &topic_matches);
+        telemetry::end_span(&route, vec![KeyValue::new("routes", routes.len() as i64)]);
 
         // Wait for in_flight messages to be low enough
-        while kafka.in_flight_messages() >= 1000 {
+        while sinks.in_flight_messages() >= 1000 {
+            crate::health::heartbeat();
             tokio::time::sleep(Duration::from_millis(10)).await;
         }
         self.stats
             .in_flight
-            .store(kafka.in_flight_messages(), Ordering::Relaxed);
+            .store(sinks.in_flight_messages(), Ordering::Relaxed);
 
-        // Spawn new thread for each mqtt message to not block the eventloop
-        let mqtt_client = self.client.clone();
-        let mut kafka_client = kafka.clone();
-        let stats = self.stats.clone();
-        tokio::spawn(async move {
-            let wrapped_payload = wrap_payload(&publish);
-            let payload = publish.payload.as_ref();
-            for topic in kafka_topics {
-                if topic.wrap_as_json {
-                    kafka_client
-                        .produce(&topic.kafka_topic, &publish.topic, wrapped_payload.as_ref())
-                        .await;
-                } else {
-                    kafka_client
-                        .produce(&topic.kafka_topic, &publish.topic, payload)
-                        .await;
-                }
-                COUNT_KAFKA_PUBLISHED
-                    .get_or_create(&MetricLabels {
-                        topic: topic.kafka_topic,
-                    })
-                    .inc();
-                stats.count_published.fetch_add(1, Ordering::Relaxed);
-            }
-            for _ in 0..5 {
-                if mqtt_client.ack(&publish).await.is_ok() {
-                    return;
-                }
-            }
-            panic!("Could not send ack to MQTT. Aborting");
-        });
+        let delivery = Delivery::new(
+            publish,
+            &routes,
+            &self.client_id,
+            received_at,
+            receive.clone(),
+        );
+        dispatcher.dispatch(delivery, routes).await;
+        telemetry::end_span(&receive, Vec::new());
     }
 
     pub async fn disconnect(&mut self) {
@@ -243,14 +279,6 @@ impl MqttClient {
     }
 }
 
-fn matching_topics(mqtt_topic: &str, topic_config: &[TopicMatch]) -> Vec<TopicMatch> {
-    topic_config
-        .iter()
-        .filter(|topic_match| matches(mqtt_topic, &topic_match.mqtt_topic))
-        .cloned()
-        .collect::<Vec<TopicMatch>>()
-}
-
 async fn stats_reporter(running: Arc<AtomicBool>, stats: Arc<Stats>) {
     let mut last = 0;
     while running.load(Ordering::Relaxed) {
@@ -278,12 +306,3 @@ async fn stats_reporter(running: Arc<AtomicBool>, stats: Arc<Stats>) {
         }
     }
 }
-
-fn wrap_payload(publish: &Publish) -> Vec<u8> {
-    let payload = BASE64_STANDARD.encode(publish.payload.clone());
-    let obj = WrappedPayload {
-        topic: publish.topic.clone(),
-        payload,
-    };
-    serde_json::to_vec(&obj).expect("Could not wrap payload")
-}
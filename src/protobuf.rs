@@ -0,0 +1,71 @@
+use crate::config::{ProtobufConfig, ProtobufConversion};
+use prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+
+// Kafka header with the fully qualified name of the protobuf message type
+pub static PROTOBUF_TYPE_HEADER: &str = "forwarding.protobuf.type";
+
+// Validates protobuf payloads of a forwarding and converts them from or to JSON
+#[derive(Debug)]
+pub struct ProtobufCodec {
+    descriptor: MessageDescriptor,
+    conversion: ProtobufConversion,
+}
+
+impl ProtobufCodec {
+    pub fn new(config: &ProtobufConfig) -> Result<ProtobufCodec, String> {
+        let bytes = std::fs::read(&config.descriptor_set).map_err(|err| {
+            format!(
+                "Could not read descriptor set {}: {err}",
+                config.descriptor_set
+            )
+        })?;
+        let pool = DescriptorPool::decode(bytes.as_slice())
+            .map_err(|err| format!("Invalid descriptor set {}: {err}", config.descriptor_set))?;
+        let descriptor = pool.get_message_by_name(&config.message).ok_or_else(|| {
+            format!(
+                "Message type {} not found in {}",
+                config.message, config.descriptor_set
+            )
+        })?;
+        Ok(ProtobufCodec {
+            descriptor,
+            conversion: config.convert.unwrap_or(ProtobufConversion::None),
+        })
+    }
+
+    pub fn message_type(&self) -> &str {
+        self.descriptor.full_name()
+    }
+
+    pub fn conversion(&self) -> ProtobufConversion {
+        self.conversion
+    }
+
+    // Applied to the payload as received: validates binary payloads and converts them for `to_json`, None if unchanged
+    pub fn decode(&self, payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if self.conversion == ProtobufConversion::FromJson {
+            return Ok(None);
+        }
+        let message = DynamicMessage::decode(self.descriptor.clone(), payload)
+            .map_err(|err| format!("Payload is not a valid {}: {err}", self.message_type()))?;
+        if self.conversion == ProtobufConversion::None {
+            return Ok(None);
+        }
+        serde_json::to_vec(&message)
+            .map(Some)
+            .map_err(|err| format!("Could not convert {} to JSON: {err}", self.message_type()))
+    }
+
+    // Applied after the transforms: converts JSON payloads to binary for `from_json`, None if unchanged
+    pub fn encode(&self, payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if self.conversion != ProtobufConversion::FromJson {
+            return Ok(None);
+        }
+        let mut deserializer = serde_json::Deserializer::from_slice(payload);
+        let message = DynamicMessage::deserialize(self.descriptor.clone(), &mut deserializer)
+            .and_then(|message| deserializer.end().map(|_| message))
+            .map_err(|err| format!("Payload is not a valid {}: {err}", self.message_type()))?;
+        Ok(Some(message.encode_to_vec()))
+    }
+}
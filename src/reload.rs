@@ -0,0 +1,112 @@
+use crate::config::{config_path, read_config, Config};
+use crate::forwardings::Forwardings;
+use crate::metrics::{CONFIG_RELOAD_FAILED, COUNT_CONFIG_RELOAD_ERRORS};
+use crate::sink::Sinks;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+
+static WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Reloads the config when the config file changes or on SIGHUP
+pub async fn watch(
+    mut current: Config,
+    forwardings: Arc<Forwardings>,
+    sinks: Sinks,
+    running: Arc<AtomicBool>,
+) {
+    let mut hangup = signal(SignalKind::hangup()).expect("Could not listen for SIGHUP");
+    // The contents are compared instead of the modification time because Kubernetes replaces mounted ConfigMaps via symlinks
+    let mut last_contents = std::fs::read_to_string(config_path()).ok();
+    while running.load(Ordering::Relaxed) {
+        tokio::select! {
+            _ = hangup.recv() => {
+                log::info!("Received SIGHUP, reloading config");
+            }
+            _ = tokio::time::sleep(WATCH_INTERVAL) => {
+                let contents = std::fs::read_to_string(config_path()).ok();
+                if contents.is_none() || contents == last_contents {
+                    continue;
+                }
+                log::info!("Config file changed, reloading config");
+                last_contents = contents;
+            }
+        }
+        match reload(&current, &forwardings, &sinks).await {
+            Ok(config) => {
+                log::info!("Config reloaded");
+                CONFIG_RELOAD_FAILED.set(0);
+                current = config;
+            }
+            Err(err) => {
+                log::error!("Rejected new config, keeping the previous one: {}", err);
+                COUNT_CONFIG_RELOAD_ERRORS.inc();
+                CONFIG_RELOAD_FAILED.set(1);
+            }
+        }
+    }
+}
+
+async fn reload(
+    current: &Config,
+    forwardings: &Forwardings,
+    sinks: &Sinks,
+) -> Result<Config, String> {
+    let mut config = read_config()?;
+    forwardings.validate(&config.forwarding)?;
+    if config.mqtt != current.mqtt {
+        log::warn!("Changes to the mqtt section require a restart and are ignored");
+    }
+    if config.spill != current.spill {
+        log::warn!("Changes to the spill section require a restart and are ignored");
+    }
+    if config.ordering != current.ordering {
+        log::warn!("Changes to the ordering section require a restart and are ignored");
+    }
+    if config.api != current.api {
+        log::warn!("Changes to the api section require a restart and are ignored");
+    }
+    if config.metrics != current.metrics {
+        log::warn!("Changes to the metrics section require a restart and are ignored");
+    }
+    if config.tracing != current.tracing {
+        log::warn!("Changes to the tracing section require a restart and are ignored");
+    }
+    if config.reverse_forwarding != current.reverse_forwarding {
+        log::warn!("Changes to the reverse_forwarding section require a restart and are ignored");
+    }
+    if config.sinks != current.sinks {
+        log::warn!("Changes to the sinks section require a restart and are ignored");
+    }
+    if config.schema_registry != current.schema_registry {
+        log::warn!("Changes to the schema_registry section require a restart and are ignored");
+    }
+    // Nothing is swapped before the new forwardings are subscribed, so a rejected reload changes nothing
+    let producer = match (sinks.kafka(), config.kafka.as_ref()) {
+        _ if config.kafka == current.kafka => None,
+        (Some(kafka), Some(kafka_config)) => Some((kafka, kafka.new_producer(kafka_config).await?)),
+        _ => return Err("Adding or removing the kafka section requires a restart".to_string()),
+    };
+    forwardings
+        .replace_all(config.forwarding.clone())
+        .await
+        .map_err(|err| err.to_string())?;
+    if let Some((kafka, producer)) = producer {
+        log::info!("Kafka config changed, replacing kafka producer");
+        kafka.replace_producer(producer).await;
+    }
+    // Keep what is actually active so ignored changes are reported again on the next reload
+    config.mqtt = current.mqtt.clone();
+    config.sinks = current.sinks.clone();
+    config.schema_registry = current.schema_registry.clone();
+    config.spill = current.spill.clone();
+    config.ordering = current.ordering.clone();
+    config.api = current.api.clone();
+    config.metrics = current.metrics.clone();
+    config.tracing = current.tracing.clone();
+    config.reverse_forwarding = current.reverse_forwarding.clone();
+    Ok(config)
+}
@@ -0,0 +1,325 @@
+use crate::config::{KafkaConfig, ReverseForwardingConfig};
+use crate::connection::MqttHandle;
+use crate::metrics::{MetricLabels, COUNT_REVERSE_PUBLISHED, COUNT_REVERSE_REJECTED};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::message::{BorrowedMessage, Headers, Message};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+static ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct TrackerState {
+    // Publishes handed to the MQTT client that were not yet sent out, in order
+    queued: VecDeque<(u8, oneshot::Sender<()>)>,
+    // Sent publishes waiting for their PubAck/PubComp by packet identifier
+    pending: HashMap<u16, oneshot::Sender<()>>,
+}
+
+// Matches acknowledgements from the broker to publishes, as rumqttc does not report the packet identifier to the caller
+#[derive(Default)]
+pub struct PublishTracker {
+    state: Mutex<TrackerState>,
+    // Keeps the queue in the same order as the requests of the MQTT client
+    order: tokio::sync::Mutex<()>,
+}
+
+impl PublishTracker {
+    pub fn published(&self, pkid: u16) {
+        let mut state = self.state.lock().unwrap();
+        // Publishes that are still pending are retransmitted after a reconnect
+        if state
+            .pending
+            .get(&pkid)
+            .is_some_and(|sender| !sender.is_closed())
+        {
+            return;
+        }
+        let Some((qos, sender)) = state.queued.pop_front() else {
+            return;
+        };
+        if qos == 0 || pkid == 0 {
+            let _ = sender.send(());
+        } else {
+            state.pending.insert(pkid, sender);
+        }
+    }
+
+    pub fn acknowledged(&self, pkid: u16) {
+        if let Some(sender) = self.state.lock().unwrap().pending.remove(&pkid) {
+            let _ = sender.send(());
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MqttPublisher {
+    client: MqttHandle,
+    tracker: Arc<PublishTracker>,
+}
+
+impl MqttPublisher {
+    pub fn new(client: MqttHandle, tracker: Arc<PublishTracker>) -> MqttPublisher {
+        MqttPublisher { client, tracker }
+    }
+
+    // Returns once the broker acknowledged the message, or immediately after sending it for QoS 0
+    pub async fn publish(
+        &self,
+        topic: String,
+        qos: u8,
+        retain: bool,
+        payload: Vec<u8>,
+    ) -> Result<(), String> {
+        let receiver = {
+            let _guard = self.tracker.order.lock().await;
+            let (sender, receiver) = oneshot::channel();
+            self.tracker
+                .state
+                .lock()
+                .unwrap()
+                .queued
+                .push_back((qos, sender));
+            if let Err(err) = self.client.publish(topic, qos, retain, payload).await {
+                self.tracker.state.lock().unwrap().queued.pop_back();
+                return Err(err);
+            }
+            receiver
+        };
+        match tokio::time::timeout(ACK_TIMEOUT, receiver).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err("Publish was dropped".to_string()),
+            Err(_) => Err("Timed out waiting for acknowledgement".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TopicPart {
+    Literal(String),
+    Key,
+    KafkaTopic,
+    Header(String),
+}
+
+// MQTT topic built from a template like `devices/{key}/commands`
+#[derive(Clone, Debug)]
+pub struct ReverseTopicTemplate {
+    parts: Vec<TopicPart>,
+}
+
+impl ReverseTopicTemplate {
+    pub fn parse(template: &str) -> Result<ReverseTopicTemplate, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TopicPart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed placeholder in '{template}'"))?
+                + start;
+            let name = &rest[start + 1..end];
+            parts.push(match name {
+                "key" => TopicPart::Key,
+                "kafka_topic" => TopicPart::KafkaTopic,
+                _ => match name.strip_prefix("header:") {
+                    Some(header) if !header.is_empty() => TopicPart::Header(header.to_string()),
+                    _ => return Err(format!("Unknown placeholder {{{name}}} in '{template}'")),
+                },
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TopicPart::Literal(rest.to_string()));
+        }
+        if parts
+            .iter()
+            .any(|part| matches!(part, TopicPart::Literal(literal) if literal.contains(['+', '#'])))
+        {
+            return Err(format!(
+                "MQTT topic '{template}' must not contain wildcards"
+            ));
+        }
+        if parts.is_empty() {
+            return Err("MQTT topic must not be empty".to_string());
+        }
+        Ok(ReverseTopicTemplate { parts })
+    }
+
+    // Values from the message must not contain `/` so they can not reach other topic levels
+    fn render(&self, message: &BorrowedMessage<'_>) -> Result<String, String> {
+        let mut topic = String::new();
+        for part in self.parts.iter() {
+            let value = match part {
+                TopicPart::Literal(literal) => {
+                    topic.push_str(literal);
+                    continue;
+                }
+                TopicPart::KafkaTopic => message.topic().to_string(),
+                TopicPart::Key => message
+                    .key()
+                    .ok_or_else(|| "Message has no key".to_string())
+                    .and_then(|key| {
+                        std::str::from_utf8(key)
+                            .map(str::to_string)
+                            .map_err(|_| "Key is not valid UTF-8".to_string())
+                    })?,
+                TopicPart::Header(name) => message
+                    .headers()
+                    .and_then(|headers| {
+                        headers
+                            .iter()
+                            .find(|header| header.key == name)
+                            .and_then(|header| header.value)
+                    })
+                    .ok_or_else(|| format!("Message has no header {name}"))
+                    .and_then(|value| {
+                        std::str::from_utf8(value)
+                            .map(str::to_string)
+                            .map_err(|_| format!("Header {name} is not valid UTF-8"))
+                    })?,
+            };
+            if value.is_empty() || value.contains(['/', '+', '#', '\0']) {
+                return Err(format!(
+                    "Value '{value}' can not be used as MQTT topic segment"
+                ));
+            }
+            topic.push_str(&value);
+        }
+        Ok(topic)
+    }
+}
+
+pub struct ReverseForwarder {
+    name: String,
+    consumer: StreamConsumer,
+    topic: ReverseTopicTemplate,
+    qos: u8,
+    retain: bool,
+    publisher: MqttPublisher,
+}
+
+impl ReverseForwarder {
+    pub fn new(
+        kafka_config: &KafkaConfig,
+        config: &ReverseForwardingConfig,
+        publisher: MqttPublisher,
+    ) -> Result<ReverseForwarder, String> {
+        let topic = ReverseTopicTemplate::parse(&config.mqtt.topic)?;
+        let qos = config.mqtt.qos.unwrap_or(1);
+        if qos > 2 {
+            return Err(format!("Invalid QoS {qos}"));
+        }
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", kafka_config.url_string())
+            .set("group.id", &config.kafka.group_id)
+            .set("enable.auto.commit", "true")
+            // Offsets are only stored once the message was acknowledged by the MQTT broker
+            .set("enable.auto.offset.store", "false");
+        if let Some(params) = kafka_config.config.as_ref() {
+            for (key, value) in params.iter() {
+                client_config.set(key, value);
+            }
+        }
+        if let Some(params) = config.kafka.config.as_ref() {
+            for (key, value) in params.iter() {
+                client_config.set(key, value);
+            }
+        }
+        let consumer: StreamConsumer = client_config
+            .create()
+            .map_err(|err| format!("KafkaConsumer creation error: {err}"))?;
+        let topics = config
+            .kafka
+            .topics
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<&str>>();
+        consumer
+            .subscribe(&topics)
+            .map_err(|err| format!("Could not subscribe to kafka topics: {err}"))?;
+        Ok(ReverseForwarder {
+            name: config.name.clone(),
+            consumer,
+            topic,
+            qos,
+            retain: config.mqtt.retain.unwrap_or(false),
+            publisher,
+        })
+    }
+
+    pub async fn run(self, running: Arc<AtomicBool>) {
+        log::info!("Running reverse forwarding {}", self.name);
+        while running.load(Ordering::Relaxed) {
+            let message = tokio::select! {
+                message = self.consumer.recv() => message,
+                _ = tokio::time::sleep(Duration::from_secs(2)) => continue,
+            };
+            let message = match message {
+                Ok(message) => message,
+                Err(err) => {
+                    log::error!(
+                        "Reverse forwarding {} failed to consume: {}",
+                        self.name,
+                        err
+                    );
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            if !self.forward(&message, &running).await {
+                break;
+            }
+            if let Err(err) = self.consumer.store_offset_from_message(&message) {
+                log::error!("Could not store kafka offset: {}", err);
+            }
+        }
+    }
+
+    // Returns false if the service is stopped before the message was acknowledged
+    async fn forward(&self, message: &BorrowedMessage<'_>, running: &AtomicBool) -> bool {
+        let labels = MetricLabels::new(&self.name, "", message.topic());
+        let mqtt_topic = match self.topic.render(message) {
+            Ok(mqtt_topic) => mqtt_topic,
+            Err(err) => {
+                log::warn!(
+                    "Reverse forwarding {} skips message from {} at offset {}: {}",
+                    self.name,
+                    message.topic(),
+                    message.offset(),
+                    err
+                );
+                COUNT_REVERSE_REJECTED.get_or_create(&labels).inc();
+                return true;
+            }
+        };
+        let payload = message.payload().unwrap_or_default().to_vec();
+        // Retry until the broker acknowledged the message, the offset must not be committed before
+        while running.load(Ordering::Relaxed) {
+            match self
+                .publisher
+                .publish(mqtt_topic.clone(), self.qos, self.retain, payload.clone())
+                .await
+            {
+                Ok(()) => {
+                    COUNT_REVERSE_PUBLISHED.get_or_create(&labels).inc();
+                    return true;
+                }
+                Err(err) => {
+                    log::warn!("Could not publish to MQTT topic {}: {}", mqtt_topic, err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,248 @@
+use crate::avro::AvroEncoder;
+use crate::config::{
+    ErrorPolicy, ForwardingConfig, PayloadFormat, ProtobufConversion, RetainHandling,
+};
+use crate::connection::{ProtocolVersion, Subscription};
+use crate::envelope::Envelope;
+use crate::key::KeyExtractor;
+use crate::metrics::{MetricLabels, COUNT_KAFKA_TOPIC_REJECTED};
+use crate::protobuf::ProtobufCodec;
+use crate::sink::{Sinks, KAFKA_SINK};
+use crate::template::{
+    is_valid_kafka_topic, matches_wildcard, sanitize_kafka_topic, TopicPattern, TopicTemplate,
+};
+use crate::transform::Transforms;
+use rumqttc::matches;
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
+pub struct TopicMatch {
+    pub name: String,
+    pub subscription: Subscription,
+    pub kafka_topic: TopicTemplate,
+    pub sink: String,
+    pub allowed_topics: Option<Vec<String>>,
+    pub dead_letter_topic: Option<String>,
+    pub key: KeyExtractor,
+    pub transforms: Option<Transforms>,
+    pub avro: Option<Arc<AvroEncoder>>,
+    pub protobuf: Option<Arc<ProtobufCodec>>,
+    pub input_format: Option<PayloadFormat>,
+    // Formats other than avro, which is encoded by `avro`
+    pub output_format: Option<PayloadFormat>,
+    pub on_format_error: ErrorPolicy,
+    pub envelope: Option<Envelope>,
+    pub mqtt_headers: bool,
+}
+
+impl TopicMatch {
+    pub fn new(
+        forwarding_config: &ForwardingConfig,
+        protocol_version: ProtocolVersion,
+        sinks: &Sinks,
+    ) -> Result<TopicMatch, String> {
+        let sink = forwarding_config
+            .sink
+            .clone()
+            .unwrap_or(KAFKA_SINK.to_string());
+        if !sinks.names().contains(&sink) {
+            return Err(if sink == KAFKA_SINK {
+                "No kafka section is configured, set another sink".to_string()
+            } else {
+                format!("Unknown sink {sink}")
+            });
+        }
+        if sink != KAFKA_SINK && forwarding_config.kafka.dead_letter_topic.is_some() {
+            return Err("dead_letter_topic can only be used with the kafka sink".to_string());
+        }
+        let pattern = TopicPattern::parse(&forwarding_config.mqtt.topic);
+        let kafka_topic = TopicTemplate::parse(&forwarding_config.kafka.topic, &pattern)
+            .map_err(|err| format!("Invalid kafka topic: {err}"))?;
+        let key = KeyExtractor::new(forwarding_config.key.as_ref(), &pattern)
+            .map_err(|err| format!("Invalid key: {err}"))?;
+        if key.needs_dead_letter_topic() && forwarding_config.kafka.dead_letter_topic.is_none() {
+            return Err(
+                "dead_letter is used as key fallback but no dead_letter_topic is set".to_string(),
+            );
+        }
+        let transforms = Transforms::new(
+            forwarding_config.transforms.as_ref(),
+            forwarding_config.on_transform_error,
+            &pattern,
+        )
+        .map_err(|err| format!("Invalid transforms: {err}"))?;
+        if let Some(transforms) = transforms.as_ref()
+            && transforms.needs_dead_letter_topic()
+            && forwarding_config.kafka.dead_letter_topic.is_none()
+        {
+            return Err(
+                "dead_letter is used as on_transform_error but no dead_letter_topic is set"
+                    .to_string(),
+            );
+        }
+        let input_format = match forwarding_config.input_format {
+            Some(PayloadFormat::Avro) => {
+                return Err("input_format avro is not supported".to_string())
+            }
+            format => format,
+        };
+        let avro = match forwarding_config.output_format {
+            Some(PayloadFormat::Avro) => {
+                let registry = sinks
+                    .schema_registry()
+                    .ok_or("output_format avro requires a schema_registry section")?;
+                Some(Arc::new(
+                    AvroEncoder::new(forwarding_config.avro.as_ref(), registry.clone())
+                        .map_err(|err| format!("Invalid avro config: {err}"))?,
+                ))
+            }
+            _ => None,
+        };
+        let output_format = forwarding_config
+            .output_format
+            .filter(|format| *format != PayloadFormat::Avro);
+        let protobuf = match forwarding_config.protobuf.as_ref() {
+            Some(config) => Some(Arc::new(
+                ProtobufCodec::new(config)
+                    .map_err(|err| format!("Invalid protobuf config: {err}"))?,
+            )),
+            None => None,
+        };
+        if let Some(protobuf) = protobuf.as_ref() {
+            let output_format = forwarding_config.output_format.is_some();
+            let error = match protobuf.conversion() {
+                // Validated payloads stay binary protobuf and can not be encoded further
+                ProtobufConversion::None if output_format => {
+                    Some("protobuf conversion none can not be combined with output_format")
+                }
+                // Only JSON payloads can be converted to protobuf, which is binary afterwards
+                ProtobufConversion::FromJson if output_format => {
+                    Some("protobuf conversion from_json can not be combined with output_format")
+                }
+                ProtobufConversion::None | ProtobufConversion::ToJson if input_format.is_some() => {
+                    Some("input_format can only be combined with protobuf conversion from_json")
+                }
+                _ => None,
+            };
+            if let Some(error) = error {
+                return Err(error.to_string());
+            }
+        }
+        // Keys are extracted from the received payload, before it is decoded
+        let binary_input = !matches!(input_format, None | Some(PayloadFormat::Json))
+            || protobuf
+                .as_ref()
+                .is_some_and(|protobuf| protobuf.conversion() != ProtobufConversion::FromJson);
+        if key.needs_json_payload() && binary_input {
+            return Err("key strategy json_pointer requires JSON input payloads".to_string());
+        }
+        let on_format_error = forwarding_config
+            .on_format_error
+            .unwrap_or(ErrorPolicy::Drop);
+        if on_format_error == ErrorPolicy::DeadLetter
+            && forwarding_config.kafka.dead_letter_topic.is_none()
+        {
+            return Err(
+                "dead_letter is used as on_format_error but no dead_letter_topic is set"
+                    .to_string(),
+            );
+        }
+        let source = &forwarding_config.mqtt;
+        if protocol_version != ProtocolVersion::V5
+            && (source.no_local.is_some()
+                || source.retain_as_published.is_some()
+                || source.retain_handling.is_some())
+        {
+            log::warn!(
+                "Forwarding {} uses subscription options that require MQTT 5, they will be ignored",
+                forwarding_config.name
+            );
+        }
+        let envelope = Envelope::new(
+            forwarding_config.wrap_as_json.unwrap_or(false),
+            forwarding_config.envelope.as_ref(),
+            &forwarding_config.name,
+        )?;
+        Ok(TopicMatch {
+            name: forwarding_config.name.clone(),
+            subscription: Subscription {
+                topic: pattern.filter,
+                no_local: source.no_local.unwrap_or(false),
+                retain_as_published: source.retain_as_published.unwrap_or(false),
+                retain_handling: source
+                    .retain_handling
+                    .unwrap_or(RetainHandling::SendOnSubscribe),
+            },
+            kafka_topic,
+            sink,
+            // An empty list is treated like no list instead of rejecting every topic
+            allowed_topics: forwarding_config
+                .kafka
+                .allowed_topics
+                .clone()
+                .filter(|allowed_topics| !allowed_topics.is_empty()),
+            dead_letter_topic: forwarding_config.kafka.dead_letter_topic.clone(),
+            key,
+            transforms,
+            avro,
+            protobuf,
+            input_format,
+            output_format,
+            on_format_error,
+            envelope,
+            mqtt_headers: forwarding_config.mqtt_headers.unwrap_or(false),
+        })
+    }
+
+    fn resolve_kafka_topic(&self, mqtt_topic: &str) -> Option<String> {
+        let kafka_topic = self.kafka_topic.render(mqtt_topic, sanitize_kafka_topic);
+        let rejected = match kafka_topic.as_deref() {
+            None => true,
+            Some(topic) => {
+                (!self.kafka_topic.is_static() && !is_valid_kafka_topic(topic))
+                    || self.allowed_topics.as_ref().is_some_and(|allowed| {
+                        !allowed
+                            .iter()
+                            .any(|pattern| matches_wildcard(pattern, topic))
+                    })
+            }
+        };
+        if rejected {
+            log::warn!(
+                "Not forwarding message from {}: kafka topic {:?} resolved from '{}' is not allowed",
+                mqtt_topic,
+                kafka_topic.unwrap_or_default(),
+                self.kafka_topic.as_str()
+            );
+            COUNT_KAFKA_TOPIC_REJECTED
+                .get_or_create(&MetricLabels::new(
+                    &self.name,
+                    &self.subscription.topic,
+                    mqtt_topic,
+                ))
+                .inc();
+            return None;
+        }
+        kafka_topic
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub kafka_topic: String,
+    pub topic_match: TopicMatch,
+}
+
+pub fn matching_topics(mqtt_topic: &str, topic_config: &[TopicMatch]) -> Vec<Route> {
+    topic_config
+        .iter()
+        .filter(|topic_match| matches(mqtt_topic, &topic_match.subscription.topic))
+        .filter_map(|topic_match| {
+            let kafka_topic = topic_match.resolve_kafka_topic(mqtt_topic)?;
+            Some(Route {
+                kafka_topic,
+                topic_match: topic_match.clone(),
+            })
+        })
+        .collect::<Vec<Route>>()
+}
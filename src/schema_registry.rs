@@ -0,0 +1,175 @@
+use crate::config::SchemaRegistryConfig;
+use crate::health::{ComponentHealth, Status};
+use apache_avro::Schema;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use reqwest::{Method, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Mutex;
+use std::time::Duration;
+
+static DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+static MAX_ATTEMPTS: u32 = 5;
+static RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+struct SchemaResponse {
+    id: u32,
+    // Not returned when registering a schema
+    schema: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct RegisteredSchema {
+    pub id: u32,
+    pub schema: Schema,
+}
+
+// Client for a Confluent-compatible schema registry
+#[derive(Debug)]
+pub struct SchemaRegistry {
+    url: Url,
+    client: reqwest::Client,
+    username: Option<String>,
+    password: Option<String>,
+    // Error of the last request, cleared once the registry answers again
+    last_error: Mutex<Option<String>>,
+}
+
+impl SchemaRegistry {
+    pub fn new(config: &SchemaRegistryConfig) -> Result<SchemaRegistry, String> {
+        let url = Url::parse(&config.url)
+            .ok()
+            .filter(|url| !url.cannot_be_a_base())
+            .ok_or_else(|| format!("Invalid schema registry url {}", config.url))?;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/vnd.schemaregistry.v1+json"),
+        );
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(
+                config
+                    .timeout_ms
+                    .map_or(DEFAULT_TIMEOUT, Duration::from_millis),
+            )
+            .build()
+            .map_err(|err| format!("Could not create HTTP client: {err}"))?;
+        Ok(SchemaRegistry {
+            url,
+            client,
+            username: config.username.clone(),
+            password: config.password.clone(),
+            last_error: Mutex::new(None),
+        })
+    }
+
+    pub async fn latest(&self, subject: &str) -> Result<RegisteredSchema, String> {
+        let response = self
+            .request(
+                Method::GET,
+                &["subjects", subject, "versions", "latest"],
+                None,
+            )
+            .await?;
+        let schema = response
+            .schema
+            .ok_or_else(|| format!("Schema registry returned no schema for {subject}"))?;
+        Ok(RegisteredSchema {
+            id: response.id,
+            schema: Schema::parse_str(&schema)
+                .map_err(|err| format!("Invalid schema for {subject}: {err}"))?,
+        })
+    }
+
+    // Id of a schema in a subject, the schema is registered first if `register` is set
+    pub async fn id(&self, subject: &str, schema: &str, register: bool) -> Result<u32, String> {
+        let path: &[&str] = if register {
+            &["subjects", subject, "versions"]
+        } else {
+            &["subjects", subject]
+        };
+        let body = json!({ "schema": schema });
+        Ok(self.request(Method::POST, path, Some(&body)).await?.id)
+    }
+
+    // Retries unavailable registries a few times before giving up
+    async fn request(
+        &self,
+        method: Method,
+        path: &[&str],
+        body: Option<&serde_json::Value>,
+    ) -> Result<SchemaResponse, String> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .expect("Schema registry url can not be a base")
+            .pop_if_empty()
+            .extend(path);
+        for attempt in 1..=MAX_ATTEMPTS {
+            if attempt > 1 {
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+            let mut request = self.client.request(method.clone(), url.clone());
+            if let Some(username) = self.username.as_ref() {
+                request = request.basic_auth(username, self.password.as_ref());
+            }
+            if let Some(body) = body {
+                request = request.body(body.to_string());
+            }
+            let error = match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    self.set_last_error(None);
+                    let body = response.bytes().await.unwrap_or_default();
+                    return serde_json::from_slice::<SchemaResponse>(&body)
+                        .map_err(|err| format!("Invalid response from {url}: {err}"));
+                }
+                Ok(response) if !is_retryable(response.status()) => {
+                    self.set_last_error(None);
+                    let status = response.status();
+                    let text = response.text().await.unwrap_or_default();
+                    return Err(format!("{url} responded with {status}: {text}"));
+                }
+                Ok(response) => format!("{url} responded with {}", response.status()),
+                Err(err) => format!("Could not reach schema registry: {err}"),
+            };
+            log::error!("Schema registry request failed: {}", error);
+            self.set_last_error(Some(error));
+        }
+        Err(format!(
+            "Could not reach schema registry {} after {MAX_ATTEMPTS} attempts",
+            self.url
+        ))
+    }
+
+    fn set_last_error(&self, error: Option<String>) {
+        *self
+            .last_error
+            .lock()
+            .expect("Schema registry lock poisoned") = error;
+    }
+
+    pub fn health(&self) -> ComponentHealth {
+        match self
+            .last_error
+            .lock()
+            .expect("Schema registry lock poisoned")
+            .clone()
+        {
+            None => ComponentHealth {
+                status: Status::Up,
+                details: json!({ "url": self.url.as_str() }),
+            },
+            Some(error) => ComponentHealth {
+                status: Status::Down,
+                details: json!({ "url": self.url.as_str(), "error": error }),
+            },
+        }
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
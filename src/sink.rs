@@ -0,0 +1,228 @@
+use crate::config::{SinkConfig, SinkKind};
+use crate::file_sink::FileSink;
+use crate::health::{ComponentHealth, Status};
+use crate::http_sink::HttpSink;
+use crate::kafka::{KafkaClient, ProduceOutcome};
+use crate::metrics::{MetricLabels, COUNT_MQTT_EXPIRED};
+use crate::schema_registry::SchemaRegistry;
+use base64::prelude::*;
+use opentelemetry::Context;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Name of the sink used by forwardings that do not set `sink`
+pub static KAFKA_SINK: &str = "kafka";
+
+pub type SinkFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub struct SinkMessage<'a> {
+    pub forwarding: &'a str,
+    pub subscription: &'a str,
+    // Resolved from the `kafka.topic` of the forwarding
+    pub kafka_topic: &'a str,
+    pub key: Option<&'a str>,
+    pub payload: &'a [u8],
+    pub mqtt_topic: &'a str,
+    pub dead_letter_topic: Option<&'a str>,
+    pub headers: &'a [(String, Vec<u8>)],
+    pub expires_at: Option<SystemTime>,
+    // Trace of the MQTT message, continued by the produce span
+    pub trace_context: &'a Context,
+}
+
+// Destination of forwarded messages. The MQTT message is acknowledged once `produce` resolves
+pub trait Sink: Send + Sync {
+    fn produce<'a>(&'a self, message: &'a SinkMessage<'a>) -> SinkFuture<'a, ProduceOutcome>;
+
+    // Waits until everything produced so far is written, called on shutdown
+    fn flush(&self) -> SinkFuture<'_, Result<(), String>>;
+
+    fn health(&self) -> SinkFuture<'_, ComponentHealth>;
+}
+
+// All sinks forwardings can send to, by name
+#[derive(Clone)]
+pub struct Sinks {
+    kafka: Option<KafkaClient>,
+    sinks: Arc<BTreeMap<String, Arc<dyn Sink>>>,
+    // Used by forwardings that encode their payloads as Avro
+    schema_registry: Option<Arc<SchemaRegistry>>,
+}
+
+impl Sinks {
+    pub fn new(
+        kafka: Option<KafkaClient>,
+        configs: &[SinkConfig],
+        custom: Vec<(String, Arc<dyn Sink>)>,
+        schema_registry: Option<SchemaRegistry>,
+    ) -> Result<Sinks, String> {
+        let mut sinks: BTreeMap<String, Arc<dyn Sink>> = BTreeMap::new();
+        if let Some(kafka) = kafka.as_ref() {
+            sinks.insert(KAFKA_SINK.to_string(), Arc::new(kafka.clone()));
+        }
+        let mut all = Vec::new();
+        for config in configs {
+            all.push((config.name.clone(), create_sink(config)?));
+        }
+        all.extend(custom);
+        for (name, sink) in all {
+            if name == KAFKA_SINK {
+                return Err(format!("Sink name {KAFKA_SINK} is reserved"));
+            }
+            if sinks.insert(name.clone(), sink).is_some() {
+                return Err(format!("Duplicate sink name {name}"));
+            }
+        }
+        Ok(Sinks {
+            kafka,
+            sinks: Arc::new(sinks),
+            schema_registry: schema_registry.map(Arc::new),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Sink>> {
+        self.sinks.get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.sinks.keys().cloned().collect()
+    }
+
+    pub fn kafka(&self) -> Option<&KafkaClient> {
+        self.kafka.as_ref()
+    }
+
+    pub fn schema_registry(&self) -> Option<&Arc<SchemaRegistry>> {
+        self.schema_registry.as_ref()
+    }
+
+    pub fn in_flight_messages(&self) -> i32 {
+        self.kafka
+            .as_ref()
+            .map_or(0, |kafka| kafka.in_flight_messages())
+    }
+
+    pub async fn health(&self) -> Vec<(String, ComponentHealth)> {
+        let mut health = Vec::new();
+        for (name, sink) in self.sinks.iter() {
+            health.push((name.clone(), sink.health().await));
+        }
+        health
+    }
+
+    pub async fn flush(&self) {
+        for (name, sink) in self.sinks.iter() {
+            if let Err(err) = sink.flush().await {
+                log::error!("Could not flush sink {}: {}", name, err);
+            }
+        }
+    }
+}
+
+fn create_sink(config: &SinkConfig) -> Result<Arc<dyn Sink>, String> {
+    let sink: Arc<dyn Sink> = match &config.kind {
+        SinkKind::File {
+            path,
+            max_size,
+            max_files,
+        } => Arc::new(FileSink::open(path, *max_size, *max_files)?),
+        SinkKind::Stdout => Arc::new(StdoutSink),
+        SinkKind::Http {
+            url,
+            headers,
+            timeout_ms,
+        } => Arc::new(HttpSink::new(url, headers.as_ref(), *timeout_ms)?),
+    };
+    log::info!("Created sink {}", config.name);
+    Ok(sink)
+}
+
+// Writes messages as JSON lines to stdout, logs go to stderr
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn produce<'a>(&'a self, message: &'a SinkMessage<'a>) -> SinkFuture<'a, ProduceOutcome> {
+        Box::pin(async move {
+            if is_expired(message.expires_at) {
+                expired(
+                    message.forwarding,
+                    message.subscription,
+                    message.kafka_topic,
+                );
+                return ProduceOutcome::Expired;
+            }
+            let mut line = json_record(message);
+            line.push(b'\n');
+            tokio::task::spawn_blocking(move || std::io::stdout().lock().write_all(&line))
+                .await
+                .expect("Could not write message to stdout")
+                .unwrap_or_else(|err| panic!("Could not write message to stdout: {err}"));
+            ProduceOutcome::Delivered
+        })
+    }
+
+    fn flush(&self) -> SinkFuture<'_, Result<(), String>> {
+        Box::pin(async {
+            tokio::task::spawn_blocking(|| std::io::stdout().flush())
+                .await
+                .map_err(|err| err.to_string())?
+                .map_err(|err| err.to_string())
+        })
+    }
+
+    fn health(&self) -> SinkFuture<'_, ComponentHealth> {
+        Box::pin(async {
+            ComponentHealth {
+                status: Status::Up,
+                details: json!({}),
+            }
+        })
+    }
+}
+
+// Representation of a message for sinks that do not speak kafka. The payload is kept readable if it is UTF-8
+pub fn json_record(message: &SinkMessage) -> Vec<u8> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let headers = message
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.clone(),
+                Value::String(String::from_utf8_lossy(value).into_owned()),
+            )
+        })
+        .collect::<Map<String, Value>>();
+    let mut record = json!({
+        "forwarding": message.forwarding,
+        "topic": message.kafka_topic,
+        "key": message.key,
+        "mqtt_topic": message.mqtt_topic,
+        "headers": headers,
+        "timestamp": timestamp,
+    });
+    match std::str::from_utf8(message.payload) {
+        Ok(payload) => record["payload"] = json!(payload),
+        Err(_) => record["payload_base64"] = json!(BASE64_STANDARD.encode(message.payload)),
+    }
+    serde_json::to_vec(&record).expect("Could not serialize message")
+}
+
+pub fn is_expired(expires_at: Option<SystemTime>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= SystemTime::now())
+}
+
+pub fn expired(forwarding: &str, subscription: &str, topic: &str) {
+    log::debug!("Dropping expired message for topic {}", topic);
+    COUNT_MQTT_EXPIRED
+        .get_or_create(&MetricLabels::new(forwarding, subscription, topic))
+        .inc();
+}
@@ -0,0 +1,832 @@
+use crate::config::{SpillConfig, SpillFullPolicy};
+use crate::metrics::{
+    COUNT_SPILL_CORRUPT, COUNT_SPILL_DROPPED, SPILL_QUEUED_BYTES, SPILL_QUEUED_MESSAGES,
+};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+static RECORD_VERSION: u8 = 1;
+static HEADER_SIZE: u64 = 8;
+static DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+static DEFAULT_READY_THRESHOLD: f64 = 0.8;
+static CURSOR_FILE: &str = "cursor";
+static SEGMENT_EXTENSION: &str = "seg";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpillRecord {
+    pub forwarding: String,
+    pub subscription: String,
+    pub kafka_topic: String,
+    pub key: Option<String>,
+    pub payload: Vec<u8>,
+    pub mqtt_topic: String,
+    pub dead_letter_topic: Option<String>,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub expires_at: Option<SystemTime>,
+}
+
+impl SpillRecord {
+    // On disk a record is stored as [body length: u32][crc32 of body: u32][body]
+    fn encode(&self) -> Vec<u8> {
+        let mut body = vec![RECORD_VERSION];
+        put_bytes(&mut body, self.kafka_topic.as_bytes());
+        put_optional_bytes(&mut body, self.key.as_ref().map(String::as_bytes));
+        put_bytes(&mut body, &self.payload);
+        put_bytes(&mut body, self.mqtt_topic.as_bytes());
+        put_bytes(
+            &mut body,
+            self.dead_letter_topic
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+        body.extend_from_slice(&(self.headers.len() as u32).to_le_bytes());
+        for (name, value) in self.headers.iter() {
+            put_bytes(&mut body, name.as_bytes());
+            put_bytes(&mut body, value);
+        }
+        let expires_at = self
+            .expires_at
+            .map(|expires_at| {
+                expires_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64
+            })
+            .unwrap_or(0);
+        body.extend_from_slice(&expires_at.to_le_bytes());
+        put_bytes(&mut body, self.forwarding.as_bytes());
+        put_bytes(&mut body, self.subscription.as_bytes());
+
+        let mut data = Vec::with_capacity(HEADER_SIZE as usize + body.len());
+        data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        data.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        data.extend_from_slice(&body);
+        data
+    }
+
+    fn decode(body: &[u8]) -> Option<SpillRecord> {
+        let (&version, mut rest) = body.split_first()?;
+        if version != RECORD_VERSION {
+            return None;
+        }
+        let kafka_topic = take_string(&mut rest)?;
+        let key = take_optional_bytes(&mut rest)?
+            .map(|key| String::from_utf8(key.to_vec()))
+            .transpose()
+            .ok()?;
+        let payload = take_bytes(&mut rest)?.to_vec();
+        let mqtt_topic = take_string(&mut rest)?;
+        let dead_letter_topic = Some(take_string(&mut rest)?).filter(|t| !t.is_empty());
+        let (count, remaining) = rest.split_at_checked(4)?;
+        rest = remaining;
+        let mut headers = Vec::new();
+        for _ in 0..u32::from_le_bytes(count.try_into().ok()?) {
+            let name = take_string(&mut rest)?;
+            let value = take_bytes(&mut rest)?.to_vec();
+            headers.push((name, value));
+        }
+        let (millis, remaining) = rest.split_at_checked(8)?;
+        rest = remaining;
+        let millis = u64::from_le_bytes(millis.try_into().ok()?);
+        let expires_at = (millis > 0).then(|| UNIX_EPOCH + Duration::from_millis(millis));
+        let forwarding = take_string(&mut rest)?;
+        let subscription = take_string(&mut rest)?;
+        Some(SpillRecord {
+            forwarding,
+            subscription,
+            kafka_topic,
+            key,
+            payload,
+            mqtt_topic,
+            dead_letter_topic,
+            headers,
+            expires_at,
+        })
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+// A missing value is stored with u32::MAX as its length
+fn put_optional_bytes(buf: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => put_bytes(buf, bytes),
+        None => buf.extend_from_slice(&u32::MAX.to_le_bytes()),
+    }
+}
+
+fn take_optional_bytes<'a>(buf: &mut &'a [u8]) -> Option<Option<&'a [u8]>> {
+    if buf.get(0..4)? == u32::MAX.to_le_bytes() {
+        *buf = &buf[4..];
+        return Some(None);
+    }
+    take_bytes(buf).map(Some)
+}
+
+fn take_string(buf: &mut &[u8]) -> Option<String> {
+    String::from_utf8(take_bytes(buf)?.to_vec()).ok()
+}
+
+fn take_bytes<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let (len, rest) = buf.split_at_checked(4)?;
+    let len = u32::from_le_bytes(len.try_into().ok()?) as usize;
+    let (bytes, rest) = rest.split_at_checked(len)?;
+    *buf = rest;
+    Some(bytes)
+}
+
+enum ReadRecord {
+    // The record and its length on disk
+    Valid(Box<SpillRecord>, u64),
+    // A complete record whose checksum or contents are invalid, it can be skipped by its length
+    Corrupt(u64),
+    // No complete record, either the end of the segment or a damaged length
+    Incomplete,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpillPosition {
+    segment: u64,
+    offset: u64,
+    len: u64,
+}
+
+struct SpillState {
+    segments: VecDeque<u64>,
+    writer: File,
+    write_offset: u64,
+    reader: Option<(u64, File)>,
+    cursor_segment: u64,
+    cursor_offset: u64,
+    queued_bytes: u64,
+    queued_messages: u64,
+}
+
+impl SpillState {
+    fn writer_segment(&self) -> u64 {
+        *self.segments.back().expect("Spill buffer has no segments")
+    }
+
+    fn update_metrics(&self) {
+        SPILL_QUEUED_BYTES.set(self.queued_bytes as i64);
+        SPILL_QUEUED_MESSAGES.set(self.queued_messages as i64);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct SpillUsage {
+    pub queued_bytes: u64,
+    pub queued_messages: u64,
+    pub max_size: u64,
+    pub ready_threshold: f64,
+}
+
+impl SpillUsage {
+    pub fn below_threshold(&self) -> bool {
+        (self.queued_bytes as f64) < self.max_size as f64 * self.ready_threshold
+    }
+}
+
+pub struct SpillBuffer {
+    dir: PathBuf,
+    max_size: u64,
+    ready_threshold: f64,
+    segment_size: u64,
+    when_full: SpillFullPolicy,
+    state: Mutex<SpillState>,
+    notify: Notify,
+}
+
+impl SpillBuffer {
+    pub fn open(config: &SpillConfig) -> SpillBuffer {
+        let dir = PathBuf::from(&config.path);
+        std::fs::create_dir_all(&dir).expect("Could not create spill buffer directory");
+
+        let mut segments = std::fs::read_dir(&dir)
+            .expect("Could not read spill buffer directory")
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != SEGMENT_EXTENSION {
+                    return None;
+                }
+                path.file_stem()?.to_str()?.parse::<u64>().ok()
+            })
+            .collect::<Vec<u64>>();
+        segments.sort_unstable();
+        let mut segments = VecDeque::from(segments);
+
+        let (mut cursor_segment, mut cursor_offset) = read_cursor(&dir).unwrap_or((0, 0));
+        // Segments before the cursor have been drained completely
+        while let Some(&segment) = segments.front() {
+            if segment >= cursor_segment {
+                break;
+            }
+            let _ = std::fs::remove_file(segment_path(&dir, segment));
+            segments.pop_front();
+        }
+        match segments.front() {
+            Some(&segment) if segment != cursor_segment => {
+                cursor_segment = segment;
+                cursor_offset = 0;
+            }
+            None => {
+                segments.push_back(cursor_segment.max(1));
+                cursor_segment = cursor_segment.max(1);
+                cursor_offset = 0;
+            }
+            _ => {}
+        }
+
+        let mut queued_bytes = 0;
+        let mut queued_messages = 0;
+        let last_segment = *segments.back().unwrap();
+        for &segment in segments.iter() {
+            let path = segment_path(&dir, segment);
+            let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(&path)
+                .expect("Could not open spill buffer segment");
+            let mut offset = if segment == cursor_segment {
+                cursor_offset
+            } else {
+                0
+            };
+            // Corrupt records are counted until the reader skips them
+            while let ReadRecord::Valid(_, len) | ReadRecord::Corrupt(len) =
+                read_record(&mut file, offset).expect("Could not read spill buffer segment")
+            {
+                offset += len;
+                queued_bytes += len;
+                queued_messages += 1;
+            }
+            let file_len = file
+                .metadata()
+                .expect("Could not read spill buffer segment")
+                .len();
+            if offset < file_len {
+                if segment == last_segment {
+                    log::warn!(
+                        "Truncating incomplete data at the end of spill buffer segment {}",
+                        path.display()
+                    );
+                    file.set_len(offset)
+                        .expect("Could not truncate spill buffer segment");
+                } else {
+                    log::error!(
+                        "Spill buffer segment {} is corrupt after offset {}, remaining data will be skipped",
+                        path.display(),
+                        offset
+                    );
+                }
+            }
+        }
+
+        let mut writer = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, last_segment))
+            .expect("Could not open spill buffer segment");
+        let write_offset = writer
+            .seek(SeekFrom::End(0))
+            .expect("Could not open spill buffer segment");
+
+        if queued_messages > 0 {
+            log::info!(
+                "Spill buffer contains {} messages ({} bytes) that will be forwarded to Kafka",
+                queued_messages,
+                queued_bytes
+            );
+        }
+
+        let state = SpillState {
+            segments,
+            writer,
+            write_offset,
+            reader: None,
+            cursor_segment,
+            cursor_offset,
+            queued_bytes,
+            queued_messages,
+        };
+        state.update_metrics();
+        SpillBuffer {
+            dir,
+            max_size: config.max_size,
+            ready_threshold: config.ready_threshold.unwrap_or(DEFAULT_READY_THRESHOLD),
+            segment_size: config.segment_size.unwrap_or(DEFAULT_SEGMENT_SIZE),
+            when_full: config.when_full.unwrap_or(SpillFullPolicy::Block),
+            state: Mutex::new(state),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn usage(&self) -> SpillUsage {
+        let state = self.state.lock().unwrap();
+        SpillUsage {
+            queued_bytes: state.queued_bytes,
+            queued_messages: state.queued_messages,
+            max_size: self.max_size,
+            ready_threshold: self.ready_threshold,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().queued_messages == 0
+    }
+
+    // Returns once the record has been durably written to disk
+    pub async fn push(self: &Arc<Self>, record: &SpillRecord) {
+        let data: Arc<[u8]> = record.encode().into();
+        let mut warned = false;
+        loop {
+            let appended = data.clone();
+            if self
+                .blocking(move |spill| spill.try_append(&appended))
+                .await
+            {
+                break;
+            }
+            if !warned {
+                log::warn!("Spill buffer is full, waiting for it to be drained");
+                warned = true;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        self.notify.notify_one();
+    }
+
+    fn try_append(&self, data: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let len = data.len() as u64;
+        // An empty buffer always accepts a record, otherwise a single oversized record would block forever
+        while state.queued_messages > 0 && state.queued_bytes + len > self.max_size {
+            match self.when_full {
+                SpillFullPolicy::Block => return false,
+                SpillFullPolicy::DropOldest => match self.read_next(&mut state) {
+                    Some((position, _)) if state.queued_bytes + len > self.max_size => {
+                        self.advance(&mut state, position);
+                        COUNT_SPILL_DROPPED.inc();
+                    }
+                    // Corrupt records skipped while reading already made room
+                    Some(_) => {}
+                    // Queued records that can not be read can not be dropped either, wait like `block`
+                    None => return false,
+                },
+            }
+        }
+
+        if state.write_offset > 0 && state.write_offset + len > self.segment_size {
+            let segment = state.writer_segment() + 1;
+            state.writer = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(segment_path(&self.dir, segment))
+                .expect("Could not create spill buffer segment");
+            state.segments.push_back(segment);
+            state.write_offset = 0;
+        }
+        state
+            .writer
+            .write_all(data)
+            .expect("Could not write to spill buffer");
+        state
+            .writer
+            .sync_data()
+            .expect("Could not write to spill buffer");
+        state.write_offset += len;
+        state.queued_bytes += len;
+        state.queued_messages += 1;
+        state.update_metrics();
+        true
+    }
+
+    // Returns the oldest record without removing it, call commit once it has been forwarded
+    pub async fn peek(self: &Arc<Self>) -> Option<(SpillPosition, SpillRecord)> {
+        self.blocking(|spill| {
+            let mut state = spill.state.lock().unwrap();
+            spill.read_next(&mut state)
+        })
+        .await
+    }
+
+    pub async fn commit(self: &Arc<Self>, position: SpillPosition) {
+        self.blocking(move |spill| {
+            let mut state = spill.state.lock().unwrap();
+            spill.advance(&mut state, position);
+        })
+        .await
+    }
+
+    // File I/O and fsyncs happen under the state lock, so they are kept off the async workers
+    async fn blocking<T: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&SpillBuffer) -> T + Send + 'static,
+    ) -> T {
+        let spill = self.clone();
+        tokio::task::spawn_blocking(move || f(&spill))
+            .await
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+    }
+
+    pub async fn wait(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.notify.notified()).await;
+    }
+
+    fn read_next(&self, state: &mut SpillState) -> Option<(SpillPosition, SpillRecord)> {
+        loop {
+            let segment = state.cursor_segment;
+            let is_writer = segment == state.writer_segment();
+            if is_writer && state.cursor_offset >= state.write_offset {
+                return None;
+            }
+            if state.reader.as_ref().map(|(id, _)| *id) != Some(segment) {
+                let file = File::open(segment_path(&self.dir, segment))
+                    .expect("Could not open spill buffer segment");
+                state.reader = Some((segment, file));
+            }
+            let (_, reader) = state.reader.as_mut().unwrap();
+            let position = SpillPosition {
+                segment,
+                offset: state.cursor_offset,
+                len: 0,
+            };
+            match read_record(reader, position.offset).expect("Could not read spill buffer segment")
+            {
+                ReadRecord::Valid(record, len) => {
+                    return Some((SpillPosition { len, ..position }, *record));
+                }
+                ReadRecord::Corrupt(len) => {
+                    log::error!(
+                        "Skipping corrupt record at offset {} of spill buffer segment {}",
+                        position.offset,
+                        segment
+                    );
+                    COUNT_SPILL_CORRUPT.inc();
+                    self.advance(state, SpillPosition { len, ..position });
+                }
+                ReadRecord::Incomplete => {
+                    let end = if is_writer {
+                        state.write_offset
+                    } else {
+                        reader
+                            .metadata()
+                            .expect("Could not read spill buffer segment")
+                            .len()
+                    };
+                    let skipped = position.offset < end;
+                    if skipped {
+                        log::error!(
+                            "Spill buffer segment {} is corrupt after offset {}, skipping the rest of it",
+                            segment,
+                            position.offset
+                        );
+                        COUNT_SPILL_CORRUPT.inc();
+                    }
+                    if is_writer {
+                        state.cursor_offset = state.write_offset;
+                    } else {
+                        // End of a segment, continue with the next one
+                        state.reader = None;
+                        state.segments.pop_front();
+                        let _ = std::fs::remove_file(segment_path(&self.dir, segment));
+                        state.cursor_segment = *state.segments.front().unwrap();
+                        state.cursor_offset = 0;
+                    }
+                    self.write_cursor(state);
+                    if skipped {
+                        // The number of records in the skipped data is unknown
+                        self.recount(state);
+                    }
+                }
+            }
+        }
+    }
+
+    fn recount(&self, state: &mut SpillState) {
+        state.queued_bytes = 0;
+        state.queued_messages = 0;
+        for &segment in state.segments.iter() {
+            let mut file = File::open(segment_path(&self.dir, segment))
+                .expect("Could not open spill buffer segment");
+            let mut offset = if segment == state.cursor_segment {
+                state.cursor_offset
+            } else {
+                0
+            };
+            while let ReadRecord::Valid(_, len) | ReadRecord::Corrupt(len) =
+                read_record(&mut file, offset).expect("Could not read spill buffer segment")
+            {
+                offset += len;
+                state.queued_bytes += len;
+                state.queued_messages += 1;
+            }
+        }
+        state.update_metrics();
+    }
+
+    fn advance(&self, state: &mut SpillState, position: SpillPosition) {
+        if state.cursor_segment != position.segment || state.cursor_offset != position.offset {
+            // Record was already removed, e.g. dropped because the buffer was full
+            return;
+        }
+        state.cursor_offset += position.len;
+        state.queued_bytes -= position.len;
+        state.queued_messages -= 1;
+        state.update_metrics();
+        self.write_cursor(state);
+    }
+
+    fn write_cursor(&self, state: &SpillState) {
+        let tmp_path = self.dir.join(format!("{CURSOR_FILE}.tmp"));
+        std::fs::write(
+            &tmp_path,
+            format!("{} {}", state.cursor_segment, state.cursor_offset),
+        )
+        .expect("Could not write spill buffer cursor");
+        std::fs::rename(tmp_path, self.dir.join(CURSOR_FILE))
+            .expect("Could not write spill buffer cursor");
+    }
+}
+
+fn segment_path(dir: &std::path::Path, segment: u64) -> PathBuf {
+    dir.join(format!("{segment:020}.{SEGMENT_EXTENSION}"))
+}
+
+fn read_cursor(dir: &std::path::Path) -> Option<(u64, u64)> {
+    let content = std::fs::read_to_string(dir.join(CURSOR_FILE)).ok()?;
+    let (segment, offset) = content.trim().split_once(' ')?;
+    Some((segment.parse().ok()?, offset.parse().ok()?))
+}
+
+fn read_record(file: &mut File, offset: u64) -> std::io::Result<ReadRecord> {
+    let file_len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset))?;
+    let mut header = [0u8; HEADER_SIZE as usize];
+    match file.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(ReadRecord::Incomplete),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as u64;
+    let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if offset + HEADER_SIZE + len > file_len {
+        return Ok(ReadRecord::Incomplete);
+    }
+    let mut body = vec![0u8; len as usize];
+    match file.read_exact(&mut body) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(ReadRecord::Incomplete),
+        Err(err) => return Err(err),
+    }
+    if crc32fast::hash(&body) != checksum {
+        return Ok(ReadRecord::Corrupt(HEADER_SIZE + len));
+    }
+    Ok(match SpillRecord::decode(&body) {
+        Some(record) => ReadRecord::Valid(Box::new(record), HEADER_SIZE + len),
+        None => ReadRecord::Corrupt(HEADER_SIZE + len),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "forwarder-spill-{}-{}",
+            std::process::id(),
+            DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn config(dir: &std::path::Path, max_size: u64, segment_size: Option<u64>) -> SpillConfig {
+        SpillConfig {
+            path: dir.display().to_string(),
+            max_size,
+            segment_size,
+            when_full: None,
+            ready_threshold: None,
+        }
+    }
+
+    fn record(payload: &str) -> SpillRecord {
+        SpillRecord {
+            forwarding: "demo".to_string(),
+            subscription: "demo/#".to_string(),
+            kafka_topic: "demo_data".to_string(),
+            key: Some("demo/1".to_string()),
+            payload: payload.as_bytes().to_vec(),
+            mqtt_topic: "demo/1".to_string(),
+            dead_letter_topic: None,
+            headers: vec![("mqtt.qos".to_string(), b"1".to_vec())],
+            expires_at: None,
+        }
+    }
+
+    async fn push_all(spill: &Arc<SpillBuffer>, payloads: &[&str]) {
+        for payload in payloads {
+            spill.push(&record(payload)).await;
+        }
+    }
+
+    // Payloads of all queued records, committing each of them
+    async fn drain(spill: &Arc<SpillBuffer>) -> Vec<String> {
+        let mut payloads = Vec::new();
+        while let Some((position, record)) = spill.peek().await {
+            payloads.push(String::from_utf8(record.payload).unwrap());
+            spill.commit(position).await;
+        }
+        payloads
+    }
+
+    fn segment_files(dir: &std::path::Path) -> Vec<PathBuf> {
+        let mut files = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION))
+            .collect::<Vec<PathBuf>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn decodes_encoded_records() {
+        let mut record = record("payload");
+        record.key = None;
+        record.dead_letter_topic = Some("demo_dlq".to_string());
+        record.expires_at = Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123));
+        let data = record.encode();
+        let body = &data[HEADER_SIZE as usize..];
+        assert_eq!(
+            u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize,
+            body.len()
+        );
+        assert_eq!(
+            u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            crc32fast::hash(body)
+        );
+        assert_eq!(SpillRecord::decode(body), Some(record));
+        assert_eq!(SpillRecord::decode(&body[..body.len() - 1]), None);
+    }
+
+    #[tokio::test]
+    async fn keeps_records_across_restarts() {
+        let dir = temp_dir();
+        let spill = Arc::new(SpillBuffer::open(&config(&dir, 1024 * 1024, None)));
+        push_all(&spill, &["first", "second", "third"]).await;
+        let (position, record) = spill.peek().await.unwrap();
+        assert_eq!(record.payload, b"first");
+        spill.commit(position).await;
+        drop(spill);
+
+        // The cursor is persisted, committed records are not forwarded again
+        let spill = Arc::new(SpillBuffer::open(&config(&dir, 1024 * 1024, None)));
+        assert_eq!(spill.usage().queued_messages, 2);
+        assert_eq!(drain(&spill).await, ["second", "third"]);
+        assert!(spill.is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn truncates_an_incomplete_last_record() {
+        let dir = temp_dir();
+        let spill = Arc::new(SpillBuffer::open(&config(&dir, 1024 * 1024, None)));
+        push_all(&spill, &["first", "second"]).await;
+        drop(spill);
+        let segment = segment_files(&dir).pop().unwrap();
+        let file = OpenOptions::new().write(true).open(&segment).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 3).unwrap();
+
+        let spill = Arc::new(SpillBuffer::open(&config(&dir, 1024 * 1024, None)));
+        assert_eq!(spill.usage().queued_messages, 1);
+        // Records appended after the truncated one can be read again
+        push_all(&spill, &["third"]).await;
+        assert_eq!(drain(&spill).await, ["first", "third"]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    // Flips a byte in the body of the record with the given index
+    fn corrupt_record(segment: &std::path::Path, index: usize) {
+        let mut data = std::fs::read(segment).unwrap();
+        let offset = index * record("first").encode().len() + HEADER_SIZE as usize + 1;
+        data[offset] ^= 0xff;
+        std::fs::write(segment, data).unwrap();
+    }
+
+    #[tokio::test]
+    async fn skips_a_corrupt_record_after_a_restart() {
+        let dir = temp_dir();
+        let spill = Arc::new(SpillBuffer::open(&config(&dir, 1024 * 1024, None)));
+        push_all(&spill, &["first", "second", "third"]).await;
+        drop(spill);
+        corrupt_record(&segment_files(&dir).pop().unwrap(), 1);
+
+        let spill = Arc::new(SpillBuffer::open(&config(&dir, 1024 * 1024, None)));
+        assert_eq!(spill.usage().queued_messages, 3);
+        let corrupt = COUNT_SPILL_CORRUPT.get();
+        assert_eq!(drain(&spill).await, ["first", "third"]);
+        assert!(COUNT_SPILL_CORRUPT.get() > corrupt);
+        assert!(spill.is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn skips_a_corrupt_record_in_the_writer_segment() {
+        let dir = temp_dir();
+        let spill = Arc::new(SpillBuffer::open(&config(&dir, 1024 * 1024, None)));
+        push_all(&spill, &["first", "second", "third"]).await;
+        corrupt_record(&segment_files(&dir).pop().unwrap(), 1);
+
+        assert_eq!(drain(&spill).await, ["first", "third"]);
+        assert!(spill.is_empty());
+        // The buffer is not stuck, new records are still read
+        push_all(&spill, &["fourth"]).await;
+        assert_eq!(drain(&spill).await, ["fourth"]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn skips_the_rest_of_a_segment_after_a_damaged_length() {
+        let dir = temp_dir();
+        let len = record("rec-1").encode().len() as u64;
+        let spill = Arc::new(SpillBuffer::open(&config(&dir, 1024 * 1024, Some(3 * len))));
+        push_all(&spill, &["rec-1", "rec-2", "rec-3", "rec-4"]).await;
+        let first_segment = segment_files(&dir).remove(0);
+        let mut data = std::fs::read(&first_segment).unwrap();
+        data[len as usize..len as usize + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&first_segment, data).unwrap();
+
+        assert_eq!(drain(&spill).await, ["rec-1", "rec-4"]);
+        assert_eq!(spill.usage().queued_messages, 0);
+        assert_eq!(spill.usage().queued_bytes, 0);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn rolls_over_to_new_segments() {
+        let dir = temp_dir();
+        let len = record("rec-1").encode().len() as u64;
+        // Room for two records per segment
+        let spill = Arc::new(SpillBuffer::open(&config(&dir, 1024 * 1024, Some(2 * len))));
+        push_all(&spill, &["rec-1", "rec-2", "rec-3", "rec-4", "rec-5"]).await;
+        assert_eq!(segment_files(&dir).len(), 3);
+        assert_eq!(spill.usage().queued_bytes, 5 * len);
+        drop(spill);
+
+        let spill = Arc::new(SpillBuffer::open(&config(&dir, 1024 * 1024, Some(2 * len))));
+        assert_eq!(
+            drain(&spill).await,
+            ["rec-1", "rec-2", "rec-3", "rec-4", "rec-5"]
+        );
+        // Drained segments are removed, only the one being written to is kept
+        assert_eq!(segment_files(&dir).len(), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_records_when_full() {
+        let dir = temp_dir();
+        let len = record("first").encode().len() as u64;
+        let mut config = config(&dir, 2 * len, None);
+        config.when_full = Some(SpillFullPolicy::DropOldest);
+        let spill = Arc::new(SpillBuffer::open(&config));
+        let dropped = COUNT_SPILL_DROPPED.get();
+        push_all(&spill, &["first", "third", "fifth"]).await;
+        assert_eq!(spill.usage().queued_messages, 2);
+        assert!(COUNT_SPILL_DROPPED.get() > dropped);
+        assert_eq!(drain(&spill).await, ["third", "fifth"]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn skips_corrupt_records_when_dropping_the_oldest() {
+        let dir = temp_dir();
+        let len = record("first").encode().len() as u64;
+        let mut config = config(&dir, 2 * len, None);
+        config.when_full = Some(SpillFullPolicy::DropOldest);
+        let spill = Arc::new(SpillBuffer::open(&config));
+        push_all(&spill, &["first", "third"]).await;
+        corrupt_record(&segment_files(&dir).pop().unwrap(), 0);
+
+        assert!(spill.try_append(&record("fifth").encode()));
+        assert_eq!(drain(&spill).await, ["third", "fifth"]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::metrics::{
+    BrokerLabels, KafkaTopicLabels, KAFKA_BROKER_OUTBUF_MESSAGES, KAFKA_BROKER_REQUEST_TIMEOUTS,
+    KAFKA_BROKER_RTT_AVG, KAFKA_BROKER_RTT_P99, KAFKA_BROKER_TX_ERRORS, KAFKA_BROKER_TX_RETRIES,
+    KAFKA_BROKER_UP, KAFKA_BROKER_WAITRESP_MESSAGES, KAFKA_QUEUE_BYTES, KAFKA_QUEUE_MESSAGES,
+    KAFKA_SENT_BYTES, KAFKA_SENT_MESSAGES, KAFKA_TOPIC_BATCH_MESSAGES, KAFKA_TOPIC_BATCH_SIZE,
+    KAFKA_TOPIC_QUEUE_MESSAGES, KAFKA_TOPIC_SENT_MESSAGES,
+};
+use rdkafka::client::ClientContext;
+use rdkafka::statistics::Statistics;
+
+// Context of the kafka producer, librdkafka only calls it with statistics if `statistics.interval.ms` is configured
+#[derive(Clone, Default)]
+pub struct StatsContext;
+
+impl ClientContext for StatsContext {
+    fn stats(&self, statistics: Statistics) {
+        record(&statistics);
+    }
+}
+
+fn record(statistics: &Statistics) {
+    KAFKA_QUEUE_MESSAGES.set(statistics.msg_cnt as i64);
+    KAFKA_QUEUE_BYTES.set(statistics.msg_size as i64);
+    KAFKA_SENT_MESSAGES.set(statistics.txmsgs);
+    KAFKA_SENT_BYTES.set(statistics.txmsg_bytes);
+
+    // Brokers and topics can disappear, so only the ones from the latest statistics are exported
+    KAFKA_BROKER_UP.clear();
+    KAFKA_BROKER_RTT_AVG.clear();
+    KAFKA_BROKER_RTT_P99.clear();
+    KAFKA_BROKER_OUTBUF_MESSAGES.clear();
+    KAFKA_BROKER_WAITRESP_MESSAGES.clear();
+    KAFKA_BROKER_TX_ERRORS.clear();
+    KAFKA_BROKER_TX_RETRIES.clear();
+    KAFKA_BROKER_REQUEST_TIMEOUTS.clear();
+    for broker in statistics.brokers.values() {
+        if broker.source == "internal" {
+            continue;
+        }
+        let labels = BrokerLabels {
+            broker: broker.name.clone(),
+        };
+        KAFKA_BROKER_UP
+            .get_or_create(&labels)
+            .set((broker.state == "UP") as i64);
+        if let Some(rtt) = broker.rtt.as_ref() {
+            // librdkafka reports times in microseconds
+            KAFKA_BROKER_RTT_AVG
+                .get_or_create(&labels)
+                .set(rtt.avg as f64 / 1_000_000.0);
+            KAFKA_BROKER_RTT_P99
+                .get_or_create(&labels)
+                .set(rtt.p99 as f64 / 1_000_000.0);
+        }
+        KAFKA_BROKER_OUTBUF_MESSAGES
+            .get_or_create(&labels)
+            .set(broker.outbuf_msg_cnt);
+        KAFKA_BROKER_WAITRESP_MESSAGES
+            .get_or_create(&labels)
+            .set(broker.waitresp_msg_cnt);
+        KAFKA_BROKER_TX_ERRORS
+            .get_or_create(&labels)
+            .set(broker.txerrs as i64);
+        KAFKA_BROKER_TX_RETRIES
+            .get_or_create(&labels)
+            .set(broker.txretries as i64);
+        KAFKA_BROKER_REQUEST_TIMEOUTS
+            .get_or_create(&labels)
+            .set(broker.req_timeouts as i64);
+    }
+
+    KAFKA_TOPIC_BATCH_SIZE.clear();
+    KAFKA_TOPIC_BATCH_MESSAGES.clear();
+    KAFKA_TOPIC_QUEUE_MESSAGES.clear();
+    KAFKA_TOPIC_SENT_MESSAGES.clear();
+    for topic in statistics.topics.values() {
+        let labels = KafkaTopicLabels {
+            topic: topic.topic.clone(),
+        };
+        KAFKA_TOPIC_BATCH_SIZE
+            .get_or_create(&labels)
+            .set(topic.batchsize.avg);
+        KAFKA_TOPIC_BATCH_MESSAGES
+            .get_or_create(&labels)
+            .set(topic.batchcnt.avg);
+        KAFKA_TOPIC_QUEUE_MESSAGES.get_or_create(&labels).set(
+            topic
+                .partitions
+                .values()
+                .map(|partition| partition.msgq_cnt + partition.xmit_msgq_cnt)
+                .sum(),
+        );
+        KAFKA_TOPIC_SENT_MESSAGES.get_or_create(&labels).set(
+            topic
+                .partitions
+                .values()
+                .map(|partition| partition.txmsgs as i64)
+                .sum(),
+        );
+    }
+}
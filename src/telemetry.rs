@@ -0,0 +1,124 @@
+use crate::config::TracingConfig;
+use crate::connection::MqttMessage;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::{SpanKind, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+static DEFAULT_SERVICE_NAME: &str = "mqtt-kafka-forwarding";
+static TRACER_NAME: &str = "forwarder";
+static TRACE_HEADERS: [&str; 2] = ["traceparent", "tracestate"];
+
+// JSON pointer to a traceparent in the payload, used if the message has no traceparent user property
+static TRACEPARENT_POINTER: OnceLock<String> = OnceLock::new();
+
+// Without an endpoint no spans are exported, but a traceparent from MQTT is still passed on to kafka
+pub fn init(config: Option<&TracingConfig>) -> Option<SdkTracerProvider> {
+    let config = config?;
+    if let Some(pointer) = config.traceparent_pointer.as_ref() {
+        if !pointer.starts_with('/') {
+            panic!("JSON pointer '{pointer}' for the traceparent must start with '/'");
+        }
+        let _ = TRACEPARENT_POINTER.set(pointer.clone());
+    }
+    let endpoint = config.endpoint.as_ref()?;
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .unwrap_or_else(|err| panic!("Could not create OTLP exporter: {err}"));
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(
+                    config
+                        .service_name
+                        .clone()
+                        .unwrap_or(DEFAULT_SERVICE_NAME.to_string()),
+                )
+                .build(),
+        )
+        .build();
+    global::set_tracer_provider(provider.clone());
+    log::info!("Exporting traces to {}", endpoint);
+    Some(provider)
+}
+
+pub fn shutdown(provider: SdkTracerProvider) {
+    if let Err(err) = provider.shutdown() {
+        log::error!("Could not export remaining traces: {}", err);
+    }
+}
+
+// Context of the trace the message belongs to, empty if it does not carry a traceparent
+pub fn extract(publish: &MqttMessage) -> Context {
+    let mut carrier = HashMap::new();
+    if let Some(properties) = publish.properties.as_ref() {
+        for (name, value) in properties.user_properties.iter() {
+            if TRACE_HEADERS.contains(&name.as_str()) {
+                carrier.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    if !carrier.contains_key("traceparent")
+        && let Some(pointer) = TRACEPARENT_POINTER.get()
+        && let Ok(payload) = serde_json::from_slice::<Value>(&publish.payload)
+        && let Some(Value::String(traceparent)) = payload.pointer(pointer)
+    {
+        carrier.insert("traceparent".to_string(), traceparent.clone());
+    }
+    if carrier.is_empty() {
+        return Context::new();
+    }
+    TraceContextPropagator::new().extract(&carrier)
+}
+
+pub fn start_span(
+    name: &'static str,
+    kind: SpanKind,
+    parent: &Context,
+    attributes: Vec<KeyValue>,
+) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(name)
+        .with_kind(kind)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, parent);
+    parent.with_span(span)
+}
+
+pub fn end_span(context: &Context, attributes: Vec<KeyValue>) {
+    let span = context.span();
+    span.set_attributes(attributes);
+    span.end();
+}
+
+// Replaces any traceparent copied from the MQTT message with the one of the given context
+pub fn inject(context: &Context, headers: &[(String, Vec<u8>)]) -> Vec<(String, Vec<u8>)> {
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(context, &mut carrier);
+    if carrier.is_empty() {
+        return headers.to_vec();
+    }
+    let mut injected = headers
+        .iter()
+        .filter(|(name, _)| !TRACE_HEADERS.contains(&name.as_str()))
+        .cloned()
+        .collect::<Vec<(String, Vec<u8>)>>();
+    for name in TRACE_HEADERS {
+        if let Some(value) = carrier.remove(name)
+            && !value.is_empty()
+        {
+            injected.push((name.to_string(), value.into_bytes()));
+        }
+    }
+    injected
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+static MAX_KAFKA_TOPIC_LENGTH: usize = 249;
+
+// An MQTT topic filter where single-level segments can be named, e.g. `devices/{device}/{kind}`
+#[derive(Clone, Debug)]
+pub struct TopicPattern {
+    pub filter: String,
+    names: HashMap<String, usize>,
+    segments: usize,
+    multi_level: bool,
+}
+
+impl TopicPattern {
+    pub fn parse(pattern: &str) -> TopicPattern {
+        let mut names = HashMap::new();
+        let segments = pattern
+            .split('/')
+            .enumerate()
+            .map(|(index, segment)| match placeholder(segment) {
+                Some(name) => {
+                    names.insert(name.to_string(), index);
+                    "+"
+                }
+                None => segment,
+            })
+            .collect::<Vec<&str>>();
+        TopicPattern {
+            filter: segments.join("/"),
+            names,
+            segments: segments.len(),
+            multi_level: segments.last() == Some(&"#"),
+        }
+    }
+}
+
+fn placeholder(segment: &str) -> Option<&str> {
+    segment.strip_prefix('{')?.strip_suffix('}')
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Segment(usize),
+}
+
+// A string with placeholders that are filled from the segments of an MQTT topic,
+// either by index (`{1}`) or by a name defined in the topic pattern (`{device}`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopicTemplate {
+    template: String,
+    parts: Vec<TemplatePart>,
+}
+
+impl TopicTemplate {
+    pub fn parse(template: &str, pattern: &TopicPattern) -> Result<TopicTemplate, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed placeholder in '{template}'"))?
+                + start;
+            let name = &rest[start + 1..end];
+            let index = match name.parse::<usize>() {
+                Ok(index) => index,
+                Err(_) => *pattern.names.get(name).ok_or_else(|| {
+                    format!(
+                        "Placeholder '{{{name}}}' is not defined in '{}'",
+                        pattern.filter
+                    )
+                })?,
+            };
+            if index >= pattern.segments && !pattern.multi_level {
+                return Err(format!(
+                    "Placeholder '{{{name}}}' is out of range for '{}'",
+                    pattern.filter
+                ));
+            }
+            parts.push(TemplatePart::Segment(index));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+        Ok(TopicTemplate {
+            template: template.to_string(),
+            parts,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.template
+    }
+
+    pub fn is_static(&self) -> bool {
+        self.parts
+            .iter()
+            .all(|part| matches!(part, TemplatePart::Literal(_)))
+    }
+
+    // Returns None if the topic does not have a segment referenced by the template
+    pub fn render(&self, topic: &str, map_segment: impl Fn(&str) -> String) -> Option<String> {
+        let segments = topic.split('/').collect::<Vec<&str>>();
+        let mut result = String::new();
+        for part in self.parts.iter() {
+            match part {
+                TemplatePart::Literal(literal) => result.push_str(literal),
+                TemplatePart::Segment(index) => {
+                    result.push_str(&map_segment(segments.get(*index)?))
+                }
+            }
+        }
+        Some(result)
+    }
+}
+
+// Replaces all characters Kafka does not allow in topic names
+pub fn sanitize_kafka_topic(segment: &str) -> String {
+    segment
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub fn is_valid_kafka_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() <= MAX_KAFKA_TOPIC_LENGTH
+        && topic != "."
+        && topic != ".."
+        && sanitize_kafka_topic(topic) == topic
+}
+
+// Matches a topic against a pattern where `*` matches any number of characters
+pub fn matches_wildcard(pattern: &str, topic: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = topic.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
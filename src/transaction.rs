@@ -0,0 +1,203 @@
+use crate::config::TransactionConfig;
+use crate::fatal::Fatal;
+use crate::kafka::{is_retryable, KafkaProducer};
+use rdkafka::error::KafkaError;
+use rdkafka::message::{Headers, OwnedHeaders};
+use rdkafka::producer::{FutureRecord, Producer};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+static MAX_ATTEMPTS: u32 = 5;
+static DEFAULT_MAX_BATCH_SIZE: usize = 100;
+static DEFAULT_MAX_BATCH_DELAY: Duration = Duration::from_millis(100);
+static TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct TransactionalRecord {
+    topic: String,
+    key: Option<String>,
+    payload: Vec<u8>,
+    headers: OwnedHeaders,
+    reply: oneshot::Sender<Result<(), KafkaError>>,
+}
+
+// Collects messages into transactions, a message counts as sent once its transaction is committed
+#[derive(Clone)]
+pub struct Transactions {
+    sender: mpsc::Sender<TransactionalRecord>,
+}
+
+impl Transactions {
+    pub fn start(
+        producer: KafkaProducer,
+        config: &TransactionConfig,
+        fatal: &Fatal,
+    ) -> Transactions {
+        let max_batch_size = config
+            .max_batch_size
+            .unwrap_or(DEFAULT_MAX_BATCH_SIZE)
+            .max(1);
+        let max_batch_delay = config
+            .max_batch_delay_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_MAX_BATCH_DELAY);
+        let (sender, receiver) = mpsc::channel(max_batch_size * 2);
+        // A failed transaction stops the forwarder, MQTT redelivers the unacknowledged messages after the restart
+        fatal.spawn(run_batches(
+            producer,
+            receiver,
+            max_batch_size,
+            max_batch_delay,
+        ));
+        Transactions { sender }
+    }
+
+    pub async fn send(
+        &self,
+        topic: &str,
+        key: Option<&str>,
+        payload: &[u8],
+        headers: OwnedHeaders,
+    ) -> Result<(), KafkaError> {
+        let (reply, receiver) = oneshot::channel();
+        let record = TransactionalRecord {
+            topic: topic.to_string(),
+            key: key.map(str::to_string),
+            payload: payload.to_vec(),
+            headers,
+            reply,
+        };
+        if self.sender.send(record).await.is_err() {
+            panic!("Kafka transaction batcher stopped. Aborting");
+        }
+        receiver
+            .await
+            .unwrap_or_else(|_| panic!("Kafka transaction batcher stopped. Aborting"))
+    }
+}
+
+async fn run_batches(
+    producer: KafkaProducer,
+    mut receiver: mpsc::Receiver<TransactionalRecord>,
+    max_batch_size: usize,
+    max_batch_delay: Duration,
+) {
+    while let Some(first) = receiver.recv().await {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + max_batch_delay;
+        while batch.len() < max_batch_size {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(record)) => batch.push(record),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        commit_batch(&producer, batch).await;
+    }
+}
+
+async fn commit_batch(producer: &KafkaProducer, mut batch: Vec<TransactionalRecord>) {
+    let mut attempts = 0;
+    while !batch.is_empty() {
+        attempts += 1;
+        if let Err(err) = producer.begin_transaction() {
+            fatal(err);
+        }
+        let results = send_batch(producer, &batch).await;
+        if results.iter().all(Result::is_ok) {
+            let committing = producer.clone();
+            let commit = tokio::task::spawn_blocking(move || {
+                committing.commit_transaction(TRANSACTION_TIMEOUT)
+            })
+            .await
+            .expect("Kafka transaction commit panicked");
+            match commit {
+                Ok(()) => {
+                    log::debug!("Committed kafka transaction with {} messages", batch.len());
+                    for record in batch {
+                        let _ = record.reply.send(Ok(()));
+                    }
+                    return;
+                }
+                Err(err) => {
+                    log::error!("Failed to commit kafka transaction: {}", err);
+                    abort(producer, &err).await;
+                    if attempts >= MAX_ATTEMPTS {
+                        for record in batch {
+                            let _ = record.reply.send(Err(err.clone()));
+                        }
+                        return;
+                    }
+                }
+            }
+            continue;
+        }
+
+        // Messages that can never be sent are handed back to the caller, the others are retried in a new transaction
+        let err = results
+            .iter()
+            .find_map(|result| result.as_ref().err())
+            .cloned()
+            .expect("Failed message in batch");
+        abort(producer, &err).await;
+        let mut remaining = Vec::with_capacity(batch.len());
+        for (record, result) in batch.into_iter().zip(results) {
+            match result {
+                Err(err) if !is_retryable(&err) || attempts >= MAX_ATTEMPTS => {
+                    let _ = record.reply.send(Err(err));
+                }
+                _ => remaining.push(record),
+            }
+        }
+        batch = remaining;
+    }
+}
+
+async fn send_batch(
+    producer: &KafkaProducer,
+    batch: &[TransactionalRecord],
+) -> Vec<Result<(), KafkaError>> {
+    let mut deliveries = Vec::with_capacity(batch.len());
+    for record in batch.iter() {
+        let mut future_record = FutureRecord::to(&record.topic).payload(&record.payload);
+        if let Some(key) = record.key.as_ref() {
+            future_record = future_record.key(key);
+        }
+        if record.headers.count() > 0 {
+            future_record = future_record.headers(record.headers.clone());
+        }
+        deliveries.push(producer.send_result(future_record).map_err(|(err, _)| err));
+    }
+    let mut results = Vec::with_capacity(deliveries.len());
+    for delivery in deliveries {
+        results.push(match delivery {
+            Ok(future) => match future.await {
+                Ok(Ok(_delivery)) => Ok(()),
+                Ok(Err((err, _message))) => Err(err),
+                Err(_canceled) => Err(KafkaError::Canceled),
+            },
+            Err(err) => Err(err),
+        });
+    }
+    results
+}
+
+async fn abort(producer: &KafkaProducer, cause: &KafkaError) {
+    if let KafkaError::Transaction(err) = cause
+        && err.is_fatal()
+    {
+        fatal(cause.clone());
+    }
+    let aborting = producer.clone();
+    let result =
+        tokio::task::spawn_blocking(move || aborting.abort_transaction(TRANSACTION_TIMEOUT))
+            .await
+            .expect("Kafka transaction abort panicked");
+    if let Err(err) = result {
+        fatal(err);
+    }
+}
+
+// The transactional producer can not be used anymore, MQTT redelivers all unacknowledged messages after the restart
+fn fatal(err: KafkaError) -> ! {
+    panic!("Kafka transaction failed: {}. Aborting", err)
+}
@@ -0,0 +1,42 @@
+use crate::config::PayloadFormat;
+use serde_json::Value;
+
+// Decodes a received payload to JSON so it can be transformed, None if it is JSON already
+pub fn decode(format: PayloadFormat, payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let value = match format {
+        PayloadFormat::Json => {
+            serde_json::from_slice::<Value>(payload)
+                .map_err(|err| format!("Payload is not valid JSON: {err}"))?;
+            return Ok(None);
+        }
+        PayloadFormat::Cbor => ciborium::from_reader::<Value, _>(payload)
+            .map_err(|err| format!("Payload is not valid CBOR: {err}"))?,
+        PayloadFormat::Msgpack => rmp_serde::from_slice::<Value>(payload)
+            .map_err(|err| format!("Payload is not valid MessagePack: {err}"))?,
+        // Rejected when the forwarding is created
+        PayloadFormat::Avro => unreachable!("Avro is not supported as input format"),
+    };
+    Ok(Some(
+        serde_json::to_vec(&value).expect("Could not serialize JSON"),
+    ))
+}
+
+// Encodes a JSON payload before it is sent, None if it stays JSON
+pub fn encode(format: PayloadFormat, payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let value = serde_json::from_slice::<Value>(payload)
+        .map_err(|err| format!("Payload is not valid JSON: {err}"))?;
+    match format {
+        PayloadFormat::Json => Ok(None),
+        PayloadFormat::Cbor => {
+            let mut encoded = Vec::new();
+            ciborium::into_writer(&value, &mut encoded)
+                .map_err(|err| format!("Could not encode payload as CBOR: {err}"))?;
+            Ok(Some(encoded))
+        }
+        PayloadFormat::Msgpack => rmp_serde::to_vec(&value)
+            .map(Some)
+            .map_err(|err| format!("Could not encode payload as MessagePack: {err}")),
+        // Encoded by the AvroEncoder of the forwarding
+        PayloadFormat::Avro => unreachable!("Avro is not transcoded"),
+    }
+}
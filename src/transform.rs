@@ -0,0 +1,385 @@
+use crate::config::{CastType, ErrorPolicy, TimestampFormat, TransformConfig};
+use crate::template::{TopicPattern, TopicTemplate};
+use serde_json::{Map, Number, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static DEFAULT_SEPARATOR: &str = ".";
+// 2^63, the first float above i64::MAX
+static I64_LIMIT: f64 = 9_223_372_036_854_775_808.0;
+
+#[derive(Clone, Debug)]
+enum Transform {
+    Add(String, Value),
+    Rename(String, String),
+    Drop(String),
+    Topic(String),
+    Segment(String, TopicTemplate),
+    Timestamp(String, TimestampFormat),
+    Flatten(String, String),
+    Cast(String, CastType),
+}
+
+// The transforms of a forwarding, applied in order to its JSON payload
+#[derive(Clone, Debug)]
+pub struct Transforms {
+    transforms: Vec<Transform>,
+    pub on_error: ErrorPolicy,
+}
+
+impl Transforms {
+    // None if the forwarding has no transforms
+    pub fn new(
+        configs: Option<&Vec<TransformConfig>>,
+        on_error: Option<ErrorPolicy>,
+        pattern: &TopicPattern,
+    ) -> Result<Option<Transforms>, String> {
+        let Some(configs) = configs.filter(|configs| !configs.is_empty()) else {
+            return Ok(None);
+        };
+        let transforms = configs
+            .iter()
+            .map(|config| {
+                Ok(match config {
+                    TransformConfig::Add { field, value } => {
+                        Transform::Add(pointer(field)?, value.clone())
+                    }
+                    TransformConfig::Rename { from, to } => {
+                        Transform::Rename(pointer(from)?, pointer(to)?)
+                    }
+                    TransformConfig::Drop { field } => Transform::Drop(pointer(field)?),
+                    TransformConfig::Topic { field } => Transform::Topic(pointer(field)?),
+                    TransformConfig::Segment { field, template } => Transform::Segment(
+                        pointer(field)?,
+                        TopicTemplate::parse(template, pattern)?,
+                    ),
+                    TransformConfig::Timestamp { field, format } => Transform::Timestamp(
+                        pointer(field)?,
+                        format.unwrap_or(TimestampFormat::Millis),
+                    ),
+                    TransformConfig::Flatten { field, separator } => {
+                        let field = match field.as_deref() {
+                            None | Some("") => String::new(),
+                            Some(field) => pointer(field)?,
+                        };
+                        Transform::Flatten(
+                            field,
+                            separator.clone().unwrap_or(DEFAULT_SEPARATOR.to_string()),
+                        )
+                    }
+                    TransformConfig::Cast { field, to } => Transform::Cast(pointer(field)?, *to),
+                })
+            })
+            .collect::<Result<Vec<Transform>, String>>()?;
+        Ok(Some(Transforms {
+            transforms,
+            on_error: on_error.unwrap_or(ErrorPolicy::Drop),
+        }))
+    }
+
+    pub fn needs_dead_letter_topic(&self) -> bool {
+        self.on_error == ErrorPolicy::DeadLetter
+    }
+
+    pub fn apply(
+        &self,
+        payload: &[u8],
+        mqtt_topic: &str,
+        received_at: SystemTime,
+    ) -> Result<Vec<u8>, String> {
+        let mut value = serde_json::from_slice::<Value>(payload)
+            .map_err(|err| format!("Payload is not valid JSON: {err}"))?;
+        for transform in self.transforms.iter() {
+            match transform {
+                Transform::Add(field, added) => set(&mut value, field, added.clone())?,
+                Transform::Rename(from, to) => {
+                    if let Some(moved) = remove(&mut value, from) {
+                        set(&mut value, to, moved)?;
+                    }
+                }
+                Transform::Drop(field) => {
+                    remove(&mut value, field);
+                }
+                Transform::Topic(field) => set(&mut value, field, Value::from(mqtt_topic))?,
+                Transform::Segment(field, template) => {
+                    let segment = template
+                        .render(mqtt_topic, str::to_string)
+                        .ok_or_else(|| format!("Topic {mqtt_topic} has no segment for {field}"))?;
+                    set(&mut value, field, Value::from(segment))?
+                }
+                Transform::Timestamp(field, format) => {
+                    set(&mut value, field, timestamp(received_at, *format))?
+                }
+                Transform::Flatten(field, separator) => {
+                    if let Some(Value::Object(object)) = value.pointer_mut(field) {
+                        let mut flat = Map::new();
+                        flatten(std::mem::take(object), "", separator, &mut flat);
+                        *object = flat;
+                    }
+                }
+                Transform::Cast(field, to) => {
+                    if let Some(current) = value.pointer_mut(field) {
+                        *current = cast(current, *to).ok_or_else(|| {
+                            format!("Can not cast {current} at {field} to {to:?}")
+                        })?;
+                    }
+                }
+            }
+        }
+        serde_json::to_vec(&value).map_err(|err| format!("Could not serialize payload: {err}"))
+    }
+}
+
+fn pointer(field: &str) -> Result<String, String> {
+    if !field.starts_with('/') {
+        return Err(format!("JSON pointer '{field}' must start with '/'"));
+    }
+    Ok(field.to_string())
+}
+
+// Splits a JSON pointer into the pointer of its parent and the unescaped name of the last token
+fn split(field: &str) -> (&str, String) {
+    let (parent, last) = field.rsplit_once('/').unwrap_or(("", field));
+    (parent, last.replace("~1", "/").replace("~0", "~"))
+}
+
+// Sets a field, creating missing parent objects
+fn set(value: &mut Value, field: &str, new: Value) -> Result<(), String> {
+    let mut current = value;
+    for token in field.split('/').skip(1) {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        let Value::Object(object) = current else {
+            return Err(format!("Can not set {field}: parent is not an object"));
+        };
+        current = object
+            .entry(token)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    *current = new;
+    Ok(())
+}
+
+fn remove(value: &mut Value, field: &str) -> Option<Value> {
+    let (parent, name) = split(field);
+    value.pointer_mut(parent)?.as_object_mut()?.remove(&name)
+}
+
+fn flatten(
+    object: Map<String, Value>,
+    prefix: &str,
+    separator: &str,
+    flat: &mut Map<String, Value>,
+) {
+    for (name, value) in object {
+        let name = if prefix.is_empty() {
+            name
+        } else {
+            format!("{prefix}{separator}{name}")
+        };
+        match value {
+            Value::Object(nested) if !nested.is_empty() => flatten(nested, &name, separator, flat),
+            value => {
+                flat.insert(name, value);
+            }
+        }
+    }
+}
+
+fn cast(value: &Value, to: CastType) -> Option<Value> {
+    match (to, value) {
+        (CastType::String, Value::String(_)) => Some(value.clone()),
+        (CastType::String, Value::Number(_) | Value::Bool(_)) => {
+            Some(Value::from(value.to_string()))
+        }
+        (CastType::Integer, Value::Number(number)) => number
+            .as_i64()
+            .or_else(|| {
+                number
+                    .as_f64()
+                    // `as` would saturate floats outside of the i64 range
+                    .filter(|float| {
+                        float.fract() == 0.0 && *float >= -I64_LIMIT && *float < I64_LIMIT
+                    })
+                    .map(|float| float as i64)
+            })
+            .map(Value::from),
+        (CastType::Integer, Value::String(string)) => {
+            string.trim().parse::<i64>().ok().map(Value::from)
+        }
+        (CastType::Integer, Value::Bool(bool)) => Some(Value::from(*bool as i64)),
+        (CastType::Float, Value::Number(number)) => number
+            .as_f64()
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+        (CastType::Float, Value::String(string)) => string
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+        (CastType::Boolean, Value::Bool(_)) => Some(value.clone()),
+        (CastType::Boolean, Value::String(string)) => match string.trim() {
+            "true" => Some(Value::Bool(true)),
+            "false" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        (CastType::Boolean, Value::Number(number)) => match number.as_f64() {
+            Some(0.0) => Some(Value::Bool(false)),
+            Some(1.0) => Some(Value::Bool(true)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn timestamp(time: SystemTime, format: TimestampFormat) -> Value {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    match format {
+        TimestampFormat::Millis => Value::from(since_epoch.as_millis() as u64),
+        TimestampFormat::Seconds => Value::from(since_epoch.as_secs()),
+        TimestampFormat::Rfc3339 => Value::from(rfc3339(time)),
+    }
+}
+
+// UTC with millisecond precision, e.g. `2024-01-31T12:00:00.000Z`
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let time_of_day = seconds % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// Date of a day since the epoch in the proleptic Gregorian calendar, see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn transforms(yaml: &str) -> Transforms {
+        let configs: Vec<TransformConfig> = serde_yaml::from_str(yaml).unwrap();
+        Transforms::new(
+            Some(&configs),
+            None,
+            &TopicPattern::parse("devices/{device}/#"),
+        )
+        .unwrap()
+        .unwrap()
+    }
+
+    fn apply(transforms: &Transforms, payload: Value) -> Result<Value, String> {
+        let received_at = UNIX_EPOCH + Duration::from_millis(1_706_702_400_123);
+        transforms
+            .apply(
+                &serde_json::to_vec(&payload).unwrap(),
+                "devices/d-1/state",
+                received_at,
+            )
+            .map(|payload| serde_json::from_slice(&payload).unwrap())
+    }
+
+    #[test]
+    fn formats_rfc3339_timestamps() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_millis(1_706_702_400_123)),
+            "2024-01-31T12:00:00.123Z"
+        );
+        // Leap day and the last second of a year
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "2000-02-29T00:00:00.000Z"
+        );
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_secs(1_735_689_599)),
+            "2024-12-31T23:59:59.000Z"
+        );
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(-719_468), (0, 3, 1));
+    }
+
+    #[test]
+    fn casts_values() {
+        assert_eq!(cast(&json!(42), CastType::String), Some(json!("42")));
+        assert_eq!(cast(&json!(true), CastType::String), Some(json!("true")));
+        assert_eq!(cast(&json!(" 42 "), CastType::Integer), Some(json!(42)));
+        assert_eq!(cast(&json!(42.0), CastType::Integer), Some(json!(42)));
+        assert_eq!(cast(&json!(42.5), CastType::Integer), None);
+        assert_eq!(cast(&json!(false), CastType::Integer), Some(json!(0)));
+        assert_eq!(cast(&json!("21.5"), CastType::Float), Some(json!(21.5)));
+        assert_eq!(cast(&json!("warm"), CastType::Float), None);
+        assert_eq!(cast(&json!("true"), CastType::Boolean), Some(json!(true)));
+        assert_eq!(cast(&json!(0), CastType::Boolean), Some(json!(false)));
+        assert_eq!(cast(&json!(2), CastType::Boolean), None);
+        assert_eq!(cast(&json!(null), CastType::String), None);
+    }
+
+    #[test]
+    fn does_not_cast_integers_out_of_range() {
+        assert_eq!(cast(&json!(1e19), CastType::Integer), None);
+        assert_eq!(cast(&json!(-1e19), CastType::Integer), None);
+        assert_eq!(cast(&json!(u64::MAX), CastType::Integer), None);
+        assert_eq!(
+            cast(&json!(-9_223_372_036_854_775_808.0), CastType::Integer),
+            Some(json!(i64::MIN))
+        );
+        assert_eq!(cast(&json!("9223372036854775808"), CastType::Integer), None);
+    }
+
+    #[test]
+    fn sets_and_removes_escaped_fields() {
+        let mut value = json!({"a/b": {"c~d": 1}});
+        set(&mut value, "/new/a~1b", json!(2)).unwrap();
+        assert_eq!(value["new"]["a/b"], 2);
+        assert_eq!(remove(&mut value, "/a~1b/c~0d"), Some(json!(1)));
+        assert_eq!(remove(&mut value, "/a~1b/c~0d"), None);
+        assert_eq!(value, json!({"a/b": {}, "new": {"a/b": 2}}));
+        assert!(set(&mut json!({"a": 1}), "/a/b", json!(2)).is_err());
+    }
+
+    #[test]
+    fn adds_topic_and_timestamp_fields() {
+        let transforms = transforms(
+            "- type: add\n  field: /meta/source\n  value: {kind: mqtt}\n\
+             - type: topic\n  field: /meta/topic\n\
+             - type: timestamp\n  field: /millis\n\
+             - type: timestamp\n  field: /seconds\n  format: seconds\n\
+             - type: timestamp\n  field: /time\n  format: rfc3339\n",
+        );
+        assert_eq!(
+            apply(&transforms, json!({"temp": 21.5})),
+            Ok(json!({
+                "temp": 21.5,
+                "meta": {"source": {"kind": "mqtt"}, "topic": "devices/d-1/state"},
+                "millis": 1_706_702_400_123u64,
+                "seconds": 1_706_702_400u64,
+                "time": "2024-01-31T12:00:00.123Z",
+            }))
+        );
+        assert!(apply(&transforms, json!([1])).is_err());
+    }
+}
@@ -0,0 +1,552 @@
+// Shared setup for the integration tests: an in-process MQTT broker, a librdkafka mock cluster and the forwarder binary running against them
+#![allow(dead_code)]
+
+use mqtt_kafka_forwarding_rust::{Config, ForwarderEvent, ProduceOutcome};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, Consumer, StreamConsumer};
+use rdkafka::message::OwnedMessage;
+use rdkafka::mocking::MockCluster;
+use rdkafka::producer::{DefaultProducerContext, FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use rdkafka::Message;
+use rdkafka::{Offset, TopicPartitionList};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use rumqttd::{Broker, ConnectionSettings, RouterConfig, ServerSettings};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::{mpsc, oneshot};
+
+pub static TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .expect("Could not bind to a free port")
+        .local_addr()
+        .expect("Could not get local address")
+        .port()
+}
+
+fn wait_for_port(port: u16) {
+    let started = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        if started.elapsed() > TIMEOUT {
+            panic!("Nothing is listening on port {port}");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// Starts an MQTT broker supporting MQTT 3.1.1 and 5 and returns their ports
+pub fn start_mqtt_broker() -> (u16, u16) {
+    let v4_port = free_port();
+    let v5_port = free_port();
+    let server = |name: &str, port: u16| ServerSettings {
+        name: name.to_string(),
+        listen: ([127, 0, 0, 1], port).into(),
+        tls: None,
+        next_connection_delay_ms: 1,
+        connections: ConnectionSettings {
+            connection_timeout_ms: 60000,
+            max_payload_size: 1024 * 1024,
+            max_inflight_count: 100,
+            auth: None,
+            external_auth: None,
+            dynamic_filters: true,
+        },
+    };
+    let config = rumqttd::Config {
+        router: RouterConfig {
+            max_connections: 100,
+            max_outgoing_packet_count: 200,
+            max_segment_size: 100 * 1024 * 1024,
+            max_segment_count: 10,
+            ..Default::default()
+        },
+        v4: Some(HashMap::from([("1".to_string(), server("v4", v4_port))])),
+        v5: Some(HashMap::from([("1".to_string(), server("v5", v5_port))])),
+        ..Default::default()
+    };
+    let mut broker = Broker::new(config);
+    std::thread::spawn(move || broker.start().expect("MQTT broker stopped"));
+    wait_for_port(v4_port);
+    wait_for_port(v5_port);
+    (v4_port, v5_port)
+}
+
+// TCP proxy in front of the MQTT broker to simulate connection losses
+pub struct Proxy {
+    pub port: u16,
+    enabled: Arc<AtomicBool>,
+    connections: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Proxy {
+    pub fn start(target: u16) -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not start proxy");
+        let port = listener.local_addr().unwrap().port();
+        let enabled = Arc::new(AtomicBool::new(true));
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let (accept_enabled, accept_connections) = (enabled.clone(), connections.clone());
+        std::thread::spawn(move || {
+            for client in listener.incoming() {
+                let Ok(client) = client else { continue };
+                if !accept_enabled.load(Ordering::SeqCst) {
+                    let _ = client.shutdown(Shutdown::Both);
+                    continue;
+                }
+                let Ok(server) = TcpStream::connect(("127.0.0.1", target)) else {
+                    continue;
+                };
+                let mut connections = accept_connections.lock().unwrap();
+                connections.push(client.try_clone().unwrap());
+                connections.push(server.try_clone().unwrap());
+                pipe(client.try_clone().unwrap(), server.try_clone().unwrap());
+                pipe(server, client);
+            }
+        });
+        Proxy {
+            port,
+            enabled,
+            connections,
+        }
+    }
+
+    // Closes all connections and refuses new ones until `reconnect` is called
+    pub fn disconnect(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+        for connection in self.connections.lock().unwrap().drain(..) {
+            let _ = connection.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn reconnect(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+}
+
+fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    std::thread::spawn(move || {
+        let mut buffer = [0; 8192];
+        loop {
+            match from.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => {
+                    if to.write_all(&buffer[..read]).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = to.shutdown(Shutdown::Both);
+    });
+}
+
+pub struct Kafka {
+    pub cluster: MockCluster<'static, DefaultProducerContext>,
+}
+
+impl Kafka {
+    pub fn start() -> Kafka {
+        Kafka {
+            cluster: MockCluster::new(1).expect("Could not start kafka mock cluster"),
+        }
+    }
+
+    pub fn bootstrap_servers(&self) -> String {
+        self.cluster.bootstrap_servers()
+    }
+
+    pub fn create_topic(&self, topic: &str) {
+        self.cluster
+            .create_topic(topic, 1, 1)
+            .expect("Could not create kafka topic");
+    }
+
+    // Waits for the given number of messages on the topic, reading it from the beginning
+    pub async fn consume(&self, topic: &str, count: usize) -> Vec<OwnedMessage> {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", self.bootstrap_servers())
+            .set("group.id", format!("test-{}", free_port()))
+            .set("auto.offset.reset", "earliest")
+            .set("enable.auto.commit", "false")
+            .create()
+            .expect("Could not create kafka consumer");
+        consumer
+            .subscribe(&[topic])
+            .expect("Could not subscribe to kafka topic");
+        let mut messages = Vec::new();
+        while messages.len() < count {
+            match tokio::time::timeout(TIMEOUT, consumer.recv()).await {
+                Ok(Ok(message)) => messages.push(message.detach()),
+                Ok(Err(err)) => panic!("Could not consume from {topic}: {err}"),
+                Err(_) => panic!(
+                    "Received only {} of {} messages from {topic}",
+                    messages.len(),
+                    count
+                ),
+            }
+        }
+        messages
+    }
+
+    pub async fn produce(&self, topic: &str, key: &str, payload: &str) {
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", self.bootstrap_servers())
+            .create()
+            .expect("Could not create kafka producer");
+        producer
+            .send(
+                FutureRecord::to(topic).key(key).payload(payload),
+                Timeout::After(TIMEOUT),
+            )
+            .await
+            .unwrap_or_else(|(err, _)| panic!("Could not produce to {topic}: {err}"));
+    }
+
+    pub async fn wait_for_committed_offset(&self, group_id: &str, topic: &str, offset: i64) {
+        let started = Instant::now();
+        while self.committed_offset(group_id, topic) != Some(offset) {
+            if started.elapsed() > TIMEOUT {
+                panic!("Offset {offset} of {topic} was not committed by {group_id}");
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    // Offset the consumer group committed for the first partition of the topic, None if it committed nothing yet
+    pub fn committed_offset(&self, group_id: &str, topic: &str) -> Option<i64> {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", self.bootstrap_servers())
+            .set("group.id", group_id)
+            .create()
+            .expect("Could not create kafka consumer");
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition(topic, 0);
+        let committed = consumer
+            .committed_offsets(partitions, Timeout::After(TIMEOUT))
+            .expect("Could not fetch committed offsets");
+        match committed
+            .find_partition(topic, 0)
+            .map(|partition| partition.offset())
+        {
+            Some(Offset::Offset(offset)) => Some(offset),
+            _ => None,
+        }
+    }
+}
+
+pub fn payload(message: &OwnedMessage) -> &str {
+    std::str::from_utf8(message.payload().unwrap_or_default()).expect("Payload is not UTF-8")
+}
+
+pub fn key(message: &OwnedMessage) -> Option<&str> {
+    message
+        .key()
+        .map(|key| std::str::from_utf8(key).expect("Key is not UTF-8"))
+}
+
+pub fn header<'a>(message: &'a OwnedMessage, name: &str) -> Option<&'a str> {
+    use rdkafka::message::Headers;
+    message.headers().and_then(|headers| {
+        headers
+            .iter()
+            .find(|header| header.key == name)
+            .and_then(|header| header.value)
+            .map(|value| std::str::from_utf8(value).expect("Header is not UTF-8"))
+    })
+}
+
+// Config of a forwarder under test, assembled from YAML snippets
+pub struct ConfigBuilder {
+    mqtt_port: u16,
+    client_id: String,
+    sections: String,
+    forwardings: String,
+}
+
+impl ConfigBuilder {
+    pub fn new(mqtt_port: u16) -> ConfigBuilder {
+        ConfigBuilder {
+            mqtt_port,
+            client_id: format!("test-{}", free_port()),
+            sections: String::new(),
+            forwardings: String::new(),
+        }
+    }
+
+    pub fn client_id(mut self, client_id: &str) -> ConfigBuilder {
+        self.client_id = client_id.to_string();
+        self
+    }
+
+    // `settings` are further indented keys of the kafka section
+    pub fn kafka(self, kafka: &Kafka, settings: &str) -> ConfigBuilder {
+        let bootstrap = kafka.bootstrap_servers();
+        let (host, port) = bootstrap
+            .split(',')
+            .next()
+            .and_then(|server| server.rsplit_once(':'))
+            .expect("Invalid bootstrap servers");
+        self.section(&format!(
+            "kafka:\n  bootstrap_server: {host}\n  port: {port}\n{settings}"
+        ))
+    }
+
+    // Top-level sections, e.g. `sinks` or `ordering`
+    pub fn section(mut self, yaml: &str) -> ConfigBuilder {
+        self.sections.push_str(yaml);
+        self
+    }
+
+    // The indented list entries of the forwarding section
+    pub fn forwardings(mut self, yaml: &str) -> ConfigBuilder {
+        self.forwardings.push_str(yaml);
+        self
+    }
+
+    pub fn yaml(&self) -> String {
+        let forwardings = if self.forwardings.is_empty() {
+            "  []\n"
+        } else {
+            &self.forwardings
+        };
+        format!(
+            "mqtt:\n  host: 127.0.0.1\n  port: {}\n  client_id: {}\n{}forwarding:\n{forwardings}",
+            self.mqtt_port, self.client_id, self.sections
+        )
+    }
+
+    pub fn build(&self) -> Config {
+        serde_yaml::from_str(&self.yaml()).expect("Invalid config")
+    }
+}
+
+// Config for the given MQTT broker and kafka cluster, `forwardings` is the indented list of forwardings
+pub fn config(mqtt_port: u16, kafka: &Kafka, client_id: &str, forwardings: &str) -> String {
+    ConfigBuilder::new(mqtt_port)
+        .client_id(client_id)
+        .kafka(kafka, "")
+        .forwardings(forwardings)
+        .yaml()
+}
+
+// Waits for the outcome of the next forwarded message
+pub async fn next_outcome(events: &mut Receiver<ForwarderEvent>) -> ProduceOutcome {
+    loop {
+        let event = tokio::time::timeout(TIMEOUT, events.recv())
+            .await
+            .expect("No event from the forwarder")
+            .expect("Could not receive event");
+        if let ForwarderEvent::Forwarded { outcome, .. } = event {
+            return outcome;
+        }
+    }
+}
+
+// Runs the forwarder binary with a config built from the given forwardings, killed when dropped
+pub struct Forwarder {
+    child: Child,
+    api_port: u16,
+    config_path: PathBuf,
+    log_path: PathBuf,
+}
+
+impl Forwarder {
+    pub async fn start(mqtt_port: u16, kafka: &Kafka, forwardings: &str) -> Forwarder {
+        Forwarder::start_with(
+            ConfigBuilder::new(mqtt_port)
+                .kafka(kafka, "")
+                .forwardings(forwardings),
+        )
+        .await
+    }
+
+    pub async fn start_with(config: ConfigBuilder) -> Forwarder {
+        let api_port = free_port();
+        let config_path = std::env::temp_dir().join(format!("forwarder-test-{api_port}.yaml"));
+        let log_path = std::env::temp_dir().join(format!("forwarder-test-{api_port}.log"));
+        write_config(&config_path, api_port, config);
+        let child = Command::new(env!("CARGO_BIN_EXE_forwarder"))
+            .env("CONFIG_FILE", &config_path)
+            .env(
+                "RUST_LOG",
+                std::env::var("RUST_LOG").unwrap_or("warn".into()),
+            )
+            .stdout(Stdio::null())
+            .stderr(File::create(&log_path).expect("Could not create log file"))
+            .spawn()
+            .expect("Could not start forwarder");
+        let forwarder = Forwarder {
+            child,
+            api_port,
+            config_path,
+            log_path,
+        };
+        forwarder.wait_ready().await;
+        forwarder
+    }
+
+    // Replaces the config file and sends SIGHUP, the client id and API port stay the same
+    pub fn reload(&self, config: ConfigBuilder) {
+        write_config(&self.config_path, self.api_port, config);
+        let status = Command::new("kill")
+            .args(["-HUP", &self.child.id().to_string()])
+            .status()
+            .expect("Could not send SIGHUP");
+        assert!(status.success(), "Could not send SIGHUP");
+    }
+
+    pub fn logs(&self) -> String {
+        std::fs::read_to_string(&self.log_path).expect("Could not read log file")
+    }
+
+    // Ready once connected to MQTT and subscribed
+    pub async fn wait_ready(&self) {
+        self.wait_for_status("/health/ready", 200).await;
+    }
+
+    pub async fn wait_for_status(&self, path: &str, status: u16) {
+        let started = Instant::now();
+        while self.get(path).await.map(|(status, _)| status) != Some(status) {
+            if started.elapsed() > TIMEOUT {
+                panic!("{path} did not respond with {status}");
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    // Metrics are updated after messages are produced, so they are polled until they have the expected value
+    pub async fn wait_for_metric(&self, name: &str, expected: f64) {
+        let started = Instant::now();
+        loop {
+            let value = self.metric(name).await;
+            if value == expected {
+                return;
+            }
+            if started.elapsed() > TIMEOUT {
+                panic!("Metric {name} is {value} instead of {expected}");
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    // Sum of all samples of a metric
+    pub async fn metric(&self, name: &str) -> f64 {
+        let (_, body) = self.get("/metrics").await.expect("Could not get metrics");
+        body.lines()
+            .filter(|line| {
+                line.strip_prefix(name)
+                    .is_some_and(|rest| rest.starts_with(['{', ' ']))
+            })
+            .filter_map(|line| line.rsplit(' ').next()?.parse::<f64>().ok())
+            .sum()
+    }
+
+    pub async fn get(&self, path: &str) -> Option<(u16, String)> {
+        let port = self.api_port;
+        let path = path.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+            write!(stream, "GET {path} HTTP/1.0\r\nHost: localhost\r\n\r\n").ok()?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).ok()?;
+            let status = response.split(' ').nth(1)?.parse().ok()?;
+            let body = response.split_once("\r\n\r\n")?.1.to_string();
+            Some((status, body))
+        })
+        .await
+        .ok()?
+    }
+}
+
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if std::thread::panicking() {
+            eprint!(
+                "{}",
+                std::fs::read_to_string(&self.log_path).unwrap_or_default()
+            );
+        }
+        let _ = std::fs::remove_file(&self.config_path);
+        let _ = std::fs::remove_file(&self.log_path);
+    }
+}
+
+fn write_config(path: &Path, api_port: u16, config: ConfigBuilder) {
+    let config = config
+        .client_id(&format!("forwarder-{api_port}"))
+        .section(&format!("api:\n  port: {api_port}\n"));
+    std::fs::write(path, config.yaml()).expect("Could not write config");
+}
+
+pub async fn mqtt_client(port: u16) -> AsyncClient {
+    let mut options = MqttOptions::new(format!("test-{}", free_port()), "127.0.0.1", port);
+    options.set_keep_alive(Duration::from_secs(5));
+    let (client, mut eventloop) = AsyncClient::new(options, 100);
+    tokio::spawn(async move {
+        loop {
+            if eventloop.poll().await.is_err() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    });
+    client
+}
+
+// Connects to the MQTT broker and returns the topic and payload of every message received for the filter
+pub async fn mqtt_subscriber(port: u16, filter: &str) -> mpsc::UnboundedReceiver<(String, String)> {
+    let mut options = MqttOptions::new(format!("test-{}", free_port()), "127.0.0.1", port);
+    options.set_keep_alive(Duration::from_secs(5));
+    let (client, mut eventloop) = AsyncClient::new(options, 100);
+    client
+        .subscribe(filter, QoS::AtLeastOnce)
+        .await
+        .expect("Could not subscribe to MQTT");
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let (subscribed, on_subscribed) = oneshot::channel();
+    let mut subscribed = Some(subscribed);
+    tokio::spawn(async move {
+        // Keeps the client alive as long as the event loop
+        let _client = client;
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::SubAck(_))) => {
+                    if let Some(subscribed) = subscribed.take() {
+                        let _ = subscribed.send(());
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let topic = publish.topic.clone();
+                    let payload = String::from_utf8_lossy(&publish.payload).into_owned();
+                    if sender.send((topic, payload)).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => (),
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    });
+    tokio::time::timeout(TIMEOUT, on_subscribed)
+        .await
+        .expect("Could not subscribe to MQTT")
+        .expect("MQTT subscriber stopped");
+    receiver
+}
+
+pub async fn publish(client: &AsyncClient, topic: &str, payload: impl AsRef<[u8]>) {
+    client
+        .publish(topic, QoS::AtLeastOnce, false, payload.as_ref().to_vec())
+        .await
+        .expect("Could not publish to MQTT");
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::{config, mqtt_client, payload, publish, start_mqtt_broker, Kafka, TIMEOUT};
+use mqtt_kafka_forwarding_rust::{
+    Config, Forwarder, ForwarderEvent, ForwardingError, ProduceOutcome,
+};
+use tokio::sync::broadcast::Receiver;
+
+async fn next_event(events: &mut Receiver<ForwarderEvent>) -> ForwarderEvent {
+    tokio::time::timeout(TIMEOUT, events.recv())
+        .await
+        .expect("No event from the forwarder")
+        .expect("Could not receive event")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn forwards_messages_of_forwardings_added_at_runtime() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("embedded");
+    let config: Config =
+        serde_yaml::from_str(&config(mqtt_port, &kafka, "embedded", "  []\n")).unwrap();
+    let forwarder = Forwarder::builder(config).without_api().start().await;
+    let mut events = forwarder.subscribe();
+
+    forwarder
+        .add_forwarding(
+            serde_yaml::from_str(
+                "name: embedded\nmqtt:\n  topic: embedded/#\nkafka:\n  topic: embedded\n",
+            )
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(forwarder.forwardings().await.len(), 1);
+    while next_event(&mut events).await != ForwarderEvent::MqttSubscribed {}
+    let client = mqtt_client(mqtt_port).await;
+    publish(&client, "embedded/device", "hello").await;
+
+    let messages = kafka.consume("embedded", 1).await;
+    assert_eq!(payload(&messages[0]), "hello");
+    assert_eq!(
+        next_event(&mut events).await,
+        ForwarderEvent::Forwarded {
+            forwarding: "embedded".to_string(),
+            mqtt_topic: "embedded/device".to_string(),
+            kafka_topic: "embedded".to_string(),
+            outcome: ProduceOutcome::Delivered,
+        }
+    );
+
+    forwarder.stop();
+    assert_eq!(next_event(&mut events).await, ForwarderEvent::Stopped);
+    forwarder.stopped().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rejects_json_pointer_keys_for_binary_payloads() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    let config: Config =
+        serde_yaml::from_str(&config(mqtt_port, &kafka, "embedded", "  []\n")).unwrap();
+    let forwarder = Forwarder::builder(config).without_api().start().await;
+
+    let result = forwarder
+        .add_forwarding(
+            serde_yaml::from_str(
+                "name: cbor\nmqtt:\n  topic: cbor/#\nkafka:\n  topic: cbor\ninput_format: cbor\noutput_format: json\nkey:\n  strategy: json_pointer\n  pointer: /device\n",
+            )
+            .unwrap(),
+        )
+        .await;
+    match result {
+        Err(ForwardingError::Invalid(error)) => assert!(error.contains("json_pointer"), "{error}"),
+        result => panic!("Unexpected result {result:?}"),
+    }
+    assert!(forwarder.forwardings().await.is_empty());
+
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+}
@@ -0,0 +1,561 @@
+mod common;
+
+use apache_avro::types::Value as AvroValue;
+use apache_avro::{from_avro_datum, Schema};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use base64::prelude::*;
+use common::{free_port, mqtt_client, next_outcome, publish, start_mqtt_broker, ConfigBuilder};
+use mqtt_kafka_forwarding_rust::{Config, Forwarder, ProduceOutcome};
+use prost_reflect::prost::Message;
+use prost_reflect::prost_types::field_descriptor_proto::{Label, Type};
+use prost_reflect::prost_types::{
+    DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+};
+use prost_reflect::{DescriptorPool, DynamicMessage, Value as ProtobufValue};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+static READING_SCHEMA: &str = r#"{
+  "type": "record",
+  "name": "Reading",
+  "fields": [
+    {"name": "device", "type": "string"},
+    {"name": "temperature", "type": "double"},
+    {"name": "unit", "type": ["null", "string"], "default": null}
+  ]
+}"#;
+
+// Forwards to a file sink, `extra` follows the sinks: further sinks or top-level sections
+fn config(mqtt_port: u16, path: &Path, extra: &str, forwarding: &str) -> Config {
+    ConfigBuilder::new(mqtt_port)
+        .section(&format!(
+            "sinks:\n  - name: archive\n    type: file\n    path: {}\n{extra}",
+            path.display()
+        ))
+        .forwardings(&format!(
+            "  - name: readings\n    mqtt:\n      topic: readings/#\n    kafka:\n      topic: readings\n    sink: archive\n{forwarding}"
+        ))
+        .build()
+}
+
+fn records(path: &Path) -> Vec<Value> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn payloads(path: &Path) -> Vec<Vec<u8>> {
+    records(path)
+        .iter()
+        .map(|record| match record["payload_base64"].as_str() {
+            Some(payload) => BASE64_STANDARD.decode(payload).unwrap(),
+            None => record["payload"].as_str().unwrap().as_bytes().to_vec(),
+        })
+        .collect()
+}
+
+// Descriptors of `test.Reading { string device = 1; double temperature = 2; }`
+fn reading_descriptors() -> FileDescriptorSet {
+    let field = |name: &str, number, r#type: Type| FieldDescriptorProto {
+        name: Some(name.to_string()),
+        json_name: Some(name.to_string()),
+        number: Some(number),
+        label: Some(Label::Optional as i32),
+        r#type: Some(r#type as i32),
+        ..Default::default()
+    };
+    FileDescriptorSet {
+        file: vec![FileDescriptorProto {
+            name: Some("reading.proto".to_string()),
+            package: Some("test".to_string()),
+            syntax: Some("proto3".to_string()),
+            message_type: vec![DescriptorProto {
+                name: Some("Reading".to_string()),
+                field: vec![
+                    field("device", 1, Type::String),
+                    field("temperature", 2, Type::Double),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+    }
+}
+
+fn write_reading_descriptors(dir: &Path) -> PathBuf {
+    std::fs::create_dir_all(dir).unwrap();
+    let path = dir.join("reading.pb");
+    std::fs::write(&path, reading_descriptors().encode_to_vec()).unwrap();
+    path
+}
+
+fn reading(device: &str, temperature: f64) -> Vec<u8> {
+    let pool = DescriptorPool::from_file_descriptor_set(reading_descriptors()).unwrap();
+    let mut message = DynamicMessage::new(pool.get_message_by_name("test.Reading").unwrap());
+    message.set_field_by_name("device", ProtobufValue::String(device.to_string()));
+    message.set_field_by_name("temperature", ProtobufValue::F64(temperature));
+    message.encode_to_vec()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn encodes_json_payloads_as_avro() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let app = Router::new()
+        .route(
+            "/subjects/readings-value/versions/latest",
+            get(|State(requests): State<Arc<AtomicUsize>>| async move {
+                requests.fetch_add(1, Ordering::SeqCst);
+                Json(json!({"subject": "readings-value", "version": 3, "id": 42, "schema": READING_SCHEMA}))
+            }),
+        )
+        .with_state(requests.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let dir = std::env::temp_dir().join(format!("forwarder-avro-{}", free_port()));
+    let path = dir.join("readings.jsonl");
+
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        &path,
+        &format!("schema_registry:\n  url: http://127.0.0.1:{port}\n"),
+        "    output_format: avro\n    on_format_error: pass_through\n",
+    ))
+    .without_api()
+    .start()
+    .await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    for payload in [
+        r#"{"device": "d-1", "temperature": 21}"#,
+        r#"{"device": "d-2"}"#,
+        r#"{"device": "d-3", "temperature": 19.5, "unit": "C"}"#,
+    ] {
+        publish(&client, "readings/device", payload).await;
+        assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    }
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+
+    let schema = Schema::parse_str(READING_SCHEMA).unwrap();
+    let decode = |payload: &[u8]| {
+        assert_eq!(payload[0], 0, "magic byte");
+        assert_eq!(u32::from_be_bytes(payload[1..5].try_into().unwrap()), 42);
+        from_avro_datum(&schema, &mut &payload[5..], None).unwrap()
+    };
+    let payloads = payloads(&path);
+    assert_eq!(
+        decode(&payloads[0]),
+        AvroValue::Record(vec![
+            ("device".into(), AvroValue::String("d-1".into())),
+            ("temperature".into(), AvroValue::Double(21.0)),
+            (
+                "unit".into(),
+                AvroValue::Union(0, Box::new(AvroValue::Null))
+            ),
+        ])
+    );
+    // The temperature is missing, so the payload is passed through
+    assert_eq!(payloads[1], br#"{"device": "d-2"}"#);
+    assert_eq!(
+        decode(&payloads[2]),
+        AvroValue::Record(vec![
+            ("device".into(), AvroValue::String("d-3".into())),
+            ("temperature".into(), AvroValue::Double(19.5)),
+            (
+                "unit".into(),
+                AvroValue::Union(1, Box::new(AvroValue::String("C".into())))
+            ),
+        ])
+    );
+    assert_eq!(requests.load(Ordering::SeqCst), 1, "schema is cached");
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn registers_schema_files_and_caches_failed_lookups() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let registered = Arc::new(Mutex::new(Vec::new()));
+    let misses = Arc::new(AtomicUsize::new(0));
+    let app = Router::new()
+        .route(
+            "/subjects/readings-value/versions",
+            post(
+                |State(registered): State<Arc<Mutex<Vec<Value>>>>, body: String| async move {
+                    registered
+                        .lock()
+                        .unwrap()
+                        .push(serde_json::from_str::<Value>(&body).unwrap());
+                    Json(json!({"id": 7}))
+                },
+            ),
+        )
+        .with_state(registered.clone())
+        .fallback({
+            let misses = misses.clone();
+            move || async move {
+                misses.fetch_add(1, Ordering::SeqCst);
+                StatusCode::NOT_FOUND
+            }
+        });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let dir = std::env::temp_dir().join(format!("forwarder-register-{}", free_port()));
+    let path = dir.join("readings.jsonl");
+    std::fs::create_dir_all(&dir).unwrap();
+    let schema_file = dir.join("reading.avsc");
+    std::fs::write(&schema_file, READING_SCHEMA).unwrap();
+
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        &path,
+        &format!("schema_registry:\n  url: http://127.0.0.1:{port}\n"),
+        &format!(
+            "    output_format: avro\n    avro:\n      schema_file: {}\n      register: true\n\
+             \x20 - name: unknown\n    mqtt:\n      topic: unknown/#\n    kafka:\n      topic: unknown\n    sink: archive\n\
+             \x20   output_format: avro\n    on_format_error: pass_through\n",
+            schema_file.display()
+        ),
+    ))
+    .without_api()
+    .start()
+    .await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(
+        &client,
+        "readings/device",
+        r#"{"device": "d-1", "temperature": 21}"#,
+    )
+    .await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    for _ in 0..3 {
+        publish(&client, "unknown/device", r#"{"device": "d-2"}"#).await;
+    }
+    for _ in 0..3 {
+        assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    }
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+
+    let payloads = payloads(&path);
+    assert_eq!(payloads[0][0], 0, "magic byte");
+    assert_eq!(u32::from_be_bytes(payloads[0][1..5].try_into().unwrap()), 7);
+    assert_eq!(
+        *registered.lock().unwrap(),
+        vec![json!({"schema": READING_SCHEMA})]
+    );
+    // Not known to the registry, so passed through
+    assert_eq!(payloads[1..], vec![br#"{"device": "d-2"}"#.to_vec(); 3]);
+    assert_eq!(misses.load(Ordering::SeqCst), 1, "failed lookup is cached");
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn applies_the_error_policy_while_the_schema_registry_is_unreachable() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let dir = std::env::temp_dir().join(format!("forwarder-unreachable-{}", free_port()));
+    let path = dir.join("readings.jsonl");
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        &path,
+        &format!(
+            "schema_registry:\n  url: http://127.0.0.1:{}\n",
+            free_port()
+        ),
+        "    output_format: avro\n    on_format_error: pass_through\n",
+    ))
+    .without_api()
+    .start()
+    .await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(
+        &client,
+        "readings/device",
+        r#"{"device": "d-1", "temperature": 21}"#,
+    )
+    .await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+
+    assert_eq!(
+        payloads(&path),
+        vec![br#"{"device": "d-1", "temperature": 21}"#.to_vec()]
+    );
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn converts_protobuf_payloads_to_json() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let dir = std::env::temp_dir().join(format!("forwarder-proto-{}", free_port()));
+    let path = dir.join("readings.jsonl");
+    let descriptor_set = write_reading_descriptors(&dir);
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        &path,
+        "",
+        &format!(
+            "    protobuf:\n      descriptor_set: {}\n      message: test.Reading\n      convert: to_json\n",
+            descriptor_set.display()
+        ),
+    ))
+    .without_api()
+    .start()
+    .await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(&client, "readings/device", reading("d-1", 21.5)).await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    // Not a valid protobuf message, dropped
+    publish(&client, "readings/device", [0xff, 0xff]).await;
+    publish(&client, "readings/device", reading("d-2", 19.0)).await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+
+    let records = records(&path);
+    assert_eq!(records.len(), 2);
+    assert_eq!(
+        serde_json::from_str::<Value>(records[0]["payload"].as_str().unwrap()).unwrap(),
+        json!({"device": "d-1", "temperature": 21.5})
+    );
+    assert_eq!(
+        records[0]["headers"]["forwarding.protobuf.type"],
+        "test.Reading"
+    );
+    assert_eq!(
+        serde_json::from_str::<Value>(records[1]["payload"].as_str().unwrap()).unwrap(),
+        json!({"device": "d-2", "temperature": 19.0})
+    );
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn converts_json_payloads_to_protobuf() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let dir = std::env::temp_dir().join(format!("forwarder-proto-{}", free_port()));
+    let path = dir.join("readings.jsonl");
+    let descriptor_set = write_reading_descriptors(&dir);
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        &path,
+        "",
+        &format!(
+            "    protobuf:\n      descriptor_set: {}\n      message: test.Reading\n      convert: from_json\n",
+            descriptor_set.display()
+        ),
+    ))
+    .without_api()
+    .start()
+    .await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(
+        &client,
+        "readings/device",
+        r#"{"device": "d-1", "temperature": 21.5}"#,
+    )
+    .await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+
+    assert_eq!(payloads(&path), vec![reading("d-1", 21.5)]);
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wraps_payloads_in_an_envelope() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let dir = std::env::temp_dir().join(format!("forwarder-envelope-{}", free_port()));
+    let path = dir.join("readings.jsonl");
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        &path,
+        "",
+        "    envelope:\n      fields: [qos, retain, forwarding]\n",
+    ))
+    .without_api()
+    .start()
+    .await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    let reading = br#"{"temperature": 21.50, "device": "d-1"}"#;
+    for payload in [&reading[..], b"warm", &[0xff, 0x00]] {
+        publish(&client, "readings/device", payload).await;
+        assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    }
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+
+    let wrapped = payloads(&path);
+    // JSON payloads are embedded verbatim
+    let verbatim = [&br#""payload":"#[..], reading].concat();
+    assert!(wrapped[0]
+        .windows(verbatim.len())
+        .any(|window| window == verbatim));
+    let mut envelopes = wrapped
+        .iter()
+        .map(|payload| serde_json::from_slice::<Value>(payload).unwrap())
+        .collect::<Vec<Value>>();
+    // The QoS the broker delivered the message with
+    assert!(envelopes[0]["qos"].is_u64());
+    envelopes[0].as_object_mut().unwrap().remove("qos");
+    assert_eq!(
+        envelopes[0],
+        json!({
+            "topic": "readings/device",
+            "payload": {"temperature": 21.5, "device": "d-1"},
+            "payload_encoding": "json",
+            "retain": false,
+            "forwarding": "readings",
+        })
+    );
+    assert_eq!(envelopes[1]["payload"], "warm");
+    assert_eq!(envelopes[1]["payload_encoding"], "string");
+    assert_eq!(
+        envelopes[2]["payload"],
+        BASE64_STANDARD.encode([0xff, 0x00])
+    );
+    assert_eq!(envelopes[2]["payload_encoding"], "base64");
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wraps_payloads_as_cloudevents() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let dir = std::env::temp_dir().join(format!("forwarder-cloudevents-{}", free_port()));
+    let structured = dir.join("structured.jsonl");
+    let binary = dir.join("binary.jsonl");
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        &structured,
+        &format!(
+            "  - name: binary\n    type: file\n    path: {}\n",
+            binary.display()
+        ),
+        "    envelope:\n      format: cloudevents\n      type: com.example.reading\n\
+         \x20 - name: binary-readings\n    mqtt:\n      topic: readings/#\n    kafka:\n      topic: readings\n    sink: binary\n\
+         \x20   envelope:\n      format: cloudevents\n      mode: binary\n",
+    ))
+    .without_api()
+    .start()
+    .await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(&client, "readings/device", r#"{"temperature": 21.5}"#).await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+
+    let record = &records(&structured)[0];
+    assert_eq!(
+        record["headers"]["content-type"],
+        "application/cloudevents+json; charset=UTF-8"
+    );
+    let mut event = serde_json::from_str::<Value>(record["payload"].as_str().unwrap()).unwrap();
+    let event = event.as_object_mut().unwrap();
+    let id = event.remove("id").unwrap();
+    assert!(id.as_str().is_some_and(|id| !id.is_empty()));
+    let time = event.remove("time").unwrap();
+    assert!(time.as_str().unwrap().ends_with('Z'));
+    assert_eq!(
+        Value::Object(event.clone()),
+        json!({
+            "specversion": "1.0",
+            "source": "readings/device",
+            "type": "com.example.reading",
+            "datacontenttype": "application/json",
+            "data": {"temperature": 21.5},
+        })
+    );
+
+    // Binary mode keeps the payload and moves the attributes to headers
+    let record = &records(&binary)[0];
+    assert_eq!(record["payload"], r#"{"temperature": 21.5}"#);
+    let headers = &record["headers"];
+    assert_eq!(headers["ce_specversion"], "1.0");
+    assert_eq!(headers["ce_source"], "readings/device");
+    assert_eq!(headers["ce_type"], "binary-readings");
+    assert_eq!(headers["content-type"], "application/json");
+    // Both forwardings received the same message
+    assert_eq!(headers["ce_id"], id);
+    assert_eq!(headers["ce_time"], time);
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transcodes_cbor_and_msgpack_payloads() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let dir = std::env::temp_dir().join(format!("forwarder-transcode-{}", free_port()));
+    let path = dir.join("readings.jsonl");
+    let packed = dir.join("packed.jsonl");
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        &path,
+        &format!(
+            "  - name: packed\n    type: file\n    path: {}\n",
+            packed.display()
+        ),
+        "    input_format: cbor\n    output_format: json\n    on_format_error: pass_through\n\
+         \x20 - name: packed\n    mqtt:\n      topic: packed/#\n    kafka:\n      topic: packed\n    sink: packed\n\
+         \x20   input_format: json\n    output_format: msgpack\n",
+    ))
+    .without_api()
+    .start()
+    .await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    let reading = json!({"device": "sensor-1", "temperature": 21.5});
+    let mut cbor = Vec::new();
+    ciborium::into_writer(&reading, &mut cbor).unwrap();
+    publish(&client, "readings/device", &cbor).await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    // Not CBOR, forwarded unchanged because of `pass_through`
+    publish(&client, "readings/device", [0xff, 0xff]).await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    publish(&client, "packed/device", reading.to_string()).await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    // Not JSON, dropped
+    publish(&client, "packed/device", "warm").await;
+    publish(&client, "packed/device", reading.to_string()).await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+
+    let forwarded = payloads(&path);
+    assert_eq!(
+        serde_json::from_slice::<Value>(&forwarded[0]).unwrap(),
+        reading
+    );
+    assert_eq!(forwarded[1], [0xff, 0xff]);
+    let forwarded = payloads(&packed);
+    assert_eq!(forwarded.len(), 2);
+    for payload in forwarded {
+        assert_eq!(rmp_serde::from_slice::<Value>(&payload).unwrap(), reading);
+    }
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,505 @@
+mod common;
+
+use base64::prelude::*;
+use common::{
+    header, key, mqtt_client, mqtt_subscriber, payload, publish, start_mqtt_broker, ConfigBuilder,
+    Forwarder, Kafka, Proxy, TIMEOUT,
+};
+use rdkafka::types::{RDKafkaApiKey, RDKafkaRespErr};
+use serde_json::Value;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn routes_messages_to_the_kafka_topic_of_their_forwarding() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("sensors");
+    kafka.create_topic("alerts");
+    let _forwarder = Forwarder::start(
+        mqtt_port,
+        &kafka,
+        "  - name: sensors\n    mqtt:\n      topic: sensors/#\n    kafka:\n      topic: sensors\n\
+         \x20 - name: alerts\n    mqtt:\n      topic: alerts/+\n    kafka:\n      topic: alerts\n",
+    )
+    .await;
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(&client, "other/topic", "ignored").await;
+    publish(&client, "sensors/a/temperature", "21.5").await;
+    let sensors = kafka.consume("sensors", 1).await;
+    assert_eq!(payload(&sensors[0]), "21.5");
+    assert_eq!(key(&sensors[0]), Some("sensors/a/temperature"));
+
+    publish(&client, "alerts/fire", "alarm").await;
+    let alerts = kafka.consume("alerts", 1).await;
+    assert_eq!(payload(&alerts[0]), "alarm");
+    assert_eq!(key(&alerts[0]), Some("alerts/fire"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wraps_payloads_as_json() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("wrapped");
+    let _forwarder = Forwarder::start(
+        mqtt_port,
+        &kafka,
+        "  - name: wrapped\n    mqtt:\n      topic: devices/#\n    kafka:\n      topic: wrapped\n    wrap_as_json: true\n",
+    )
+    .await;
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(&client, "devices/1", "hello").await;
+
+    let messages = kafka.consume("wrapped", 1).await;
+    let wrapped: Value = serde_json::from_str(payload(&messages[0])).unwrap();
+    assert_eq!(wrapped["topic"], "devices/1");
+    assert_eq!(
+        BASE64_STANDARD
+            .decode(wrapped["payload"].as_str().unwrap())
+            .unwrap(),
+        b"hello"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uses_the_configured_key() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("keyed");
+    let _forwarder = Forwarder::start(
+        mqtt_port,
+        &kafka,
+        "  - name: keyed\n    mqtt:\n      topic: devices/#\n    kafka:\n      topic: keyed\n    key:\n      strategy: json_pointer\n      pointer: /device\n",
+    )
+    .await;
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(&client, "devices/1", r#"{"device": "d-42"}"#).await;
+    kafka.consume("keyed", 1).await;
+    publish(&client, "devices/2", "not json").await;
+
+    let messages = kafka.consume("keyed", 2).await;
+    assert_eq!(key(&messages[0]), Some("d-42"));
+    // Falls back to the MQTT topic
+    assert_eq!(key(&messages[1]), Some("devices/2"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn acknowledges_forwarded_messages() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("acks");
+    let forwarder = Forwarder::start(
+        mqtt_port,
+        &kafka,
+        "  - name: acks\n    mqtt:\n      topic: acks/#\n    kafka:\n      topic: acks\n",
+    )
+    .await;
+    let client = mqtt_client(mqtt_port).await;
+
+    // One after another, as messages processed in parallel are acknowledged in the order they are done
+    for i in 0..3 {
+        publish(&client, "acks/device", &i.to_string()).await;
+        kafka.consume("acks", i + 1).await;
+    }
+
+    let messages = kafka.consume("acks", 3).await;
+    assert_eq!(
+        messages.iter().map(payload).collect::<Vec<&str>>(),
+        ["0", "1", "2"]
+    );
+    forwarder
+        .wait_for_metric("forwarding_mqtt_acks_total", 3.0)
+        .await;
+    let (_, metrics) = forwarder.get("/metrics").await.unwrap();
+    assert!(metrics.contains("forwarding_mqtt_acks_total{forwarding=\"acks\"} 3"));
+    forwarder
+        .wait_for_metric("forwarding_kafka_published_total", 3.0)
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reconnects_after_losing_the_mqtt_connection() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let proxy = Proxy::start(mqtt_port);
+    let kafka = Kafka::start();
+    kafka.create_topic("reconnect");
+    let forwarder = Forwarder::start(
+        proxy.port,
+        &kafka,
+        "  - name: reconnect\n    mqtt:\n      topic: reconnect/#\n    kafka:\n      topic: reconnect\n",
+    )
+    .await;
+    let client = mqtt_client(mqtt_port).await;
+    let reconnects = forwarder.metric("forwarding_mqtt_reconnects_total").await;
+
+    proxy.disconnect();
+    forwarder.wait_for_status("/health/ready", 503).await;
+    // Messages published while the forwarder is disconnected are kept in its session
+    publish(&client, "reconnect/device", "while disconnected").await;
+    proxy.reconnect();
+    forwarder.wait_ready().await;
+    kafka.consume("reconnect", 1).await;
+    publish(&client, "reconnect/device", "after reconnect").await;
+
+    let messages = kafka.consume("reconnect", 2).await;
+    assert_eq!(
+        messages.iter().map(payload).collect::<Vec<&str>>(),
+        ["while disconnected", "after reconnect"]
+    );
+    assert!(forwarder.metric("forwarding_mqtt_reconnects_total").await > reconnects);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delivers_messages_once_the_kafka_broker_is_back() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("outage");
+    let forwarder = Forwarder::start(
+        mqtt_port,
+        &kafka,
+        "  - name: outage\n    mqtt:\n      topic: outage/#\n    kafka:\n      topic: outage\n",
+    )
+    .await;
+    let client = mqtt_client(mqtt_port).await;
+
+    kafka.cluster.broker_down(1).unwrap();
+    publish(&client, "outage/device", "during outage").await;
+    forwarder
+        .wait_for_metric("forwarding_mqtt_received_total", 1.0)
+        .await;
+    kafka.cluster.broker_up(1).unwrap();
+
+    let messages = kafka.consume("outage", 1).await;
+    assert_eq!(payload(&messages[0]), "during outage");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sends_messages_rejected_by_kafka_to_the_dead_letter_topic() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("rejected");
+    kafka.create_topic("rejected-dlq");
+    let forwarder = Forwarder::start(
+        mqtt_port,
+        &kafka,
+        "  - name: rejected\n    mqtt:\n      topic: rejected/#\n    kafka:\n      topic: rejected\n      dead_letter_topic: rejected-dlq\n",
+    )
+    .await;
+    let client = mqtt_client(mqtt_port).await;
+
+    kafka.cluster.request_errors(
+        RDKafkaApiKey::Produce,
+        &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_MSG_SIZE_TOO_LARGE],
+    );
+    publish(&client, "rejected/device", "too large").await;
+
+    let messages = kafka.consume("rejected-dlq", 1).await;
+    assert_eq!(payload(&messages[0]), "too large");
+    assert_eq!(
+        header(&messages[0], "forwarding.error.code"),
+        Some("MessageSizeTooLarge")
+    );
+    assert_eq!(
+        header(&messages[0], "forwarding.kafka.topic"),
+        Some("rejected")
+    );
+    forwarder
+        .wait_for_metric("forwarding_kafka_dead_lettered_total", 1.0)
+        .await;
+    forwarder
+        .wait_for_metric("forwarding_mqtt_acks_total", 1.0)
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn drops_messages_rejected_by_kafka_without_a_dead_letter_topic() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("dropped");
+    let forwarder = Forwarder::start(
+        mqtt_port,
+        &kafka,
+        "  - name: dropped\n    mqtt:\n      topic: dropped/#\n    kafka:\n      topic: dropped\n",
+    )
+    .await;
+    let client = mqtt_client(mqtt_port).await;
+
+    kafka.cluster.request_errors(
+        RDKafkaApiKey::Produce,
+        &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_MSG_SIZE_TOO_LARGE],
+    );
+    publish(&client, "dropped/device", "too large").await;
+    forwarder
+        .wait_for_metric("forwarding_kafka_dropped_total", 1.0)
+        .await;
+    forwarder
+        .wait_for_metric("forwarding_mqtt_acks_total", 1.0)
+        .await;
+
+    // The forwarder keeps running
+    publish(&client, "dropped/device", "next").await;
+    let messages = kafka.consume("dropped", 1).await;
+    assert_eq!(payload(&messages[0]), "next");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transforms_json_payloads() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("transformed");
+    kafka.create_topic("transformed-dlq");
+    let forwarder = Forwarder::start(
+        mqtt_port,
+        &kafka,
+        "  - name: transformed\n    mqtt:\n      topic: devices/{device}/#\n    kafka:\n      topic: transformed\n      dead_letter_topic: transformed-dlq\n\
+         \x20   transforms:\n      - type: rename\n        from: /temp\n        to: /temperature\n      - type: cast\n        field: /temperature\n        to: float\n\
+         \x20     - type: segment\n        field: /meta/device\n        template: '{device}'\n      - type: drop\n        field: /debug\n      - type: flatten\n\
+         \x20     - type: add\n        field: /source\n        value: mqtt\n      - type: topic\n        field: /mqtt_topic\n\
+         \x20     - type: timestamp\n        field: /received_at\n        format: seconds\n\
+         \x20   on_transform_error: dead_letter\n",
+    )
+    .await;
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(
+        &client,
+        "devices/d-1/state",
+        r#"{"temp": "21.5", "debug": true}"#,
+    )
+    .await;
+    let messages = kafka.consume("transformed", 1).await;
+    let mut transformed: Value = serde_json::from_str(payload(&messages[0])).unwrap();
+    let received_at = transformed
+        .as_object_mut()
+        .unwrap()
+        .remove("received_at")
+        .and_then(|received_at| received_at.as_u64())
+        .expect("Timestamp was not added");
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    assert!(received_at.abs_diff(now) < 60);
+    assert_eq!(
+        transformed,
+        serde_json::json!({
+            "temperature": 21.5,
+            "meta.device": "d-1",
+            "source": "mqtt",
+            "mqtt_topic": "devices/d-1/state",
+        })
+    );
+
+    publish(&client, "devices/d-1/state", r#"{"temp": "warm"}"#).await;
+    let messages = kafka.consume("transformed-dlq", 1).await;
+    assert_eq!(payload(&messages[0]), r#"{"temp": "warm"}"#);
+    assert_eq!(
+        header(&messages[0], "forwarding.error.code"),
+        Some("TransformFailed")
+    );
+    forwarder
+        .wait_for_metric("forwarding_transform_errors_total", 1.0)
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn acknowledges_messages_once_their_transaction_is_committed() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("committed");
+    let forwarder = Forwarder::start_with(
+        ConfigBuilder::new(mqtt_port)
+            .kafka(
+                &kafka,
+                "  transactions:\n    transactional_id: forwarding-test\n    max_batch_size: 3\n    max_batch_delay_ms: 60000\n",
+            )
+            .forwardings(
+                "  - name: committed\n    mqtt:\n      topic: committed/#\n    kafka:\n      topic: committed\n",
+            ),
+    )
+    .await;
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(&client, "committed/device", "first").await;
+    publish(&client, "committed/device", "second").await;
+    // The transaction is only committed once the batch is full
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(forwarder.metric("forwarding_mqtt_acks_total").await, 0.0);
+
+    publish(&client, "committed/device", "third").await;
+    forwarder
+        .wait_for_metric("forwarding_mqtt_acks_total", 3.0)
+        .await;
+    // The consumer only reads committed messages
+    let mut payloads = kafka
+        .consume("committed", 3)
+        .await
+        .iter()
+        .map(|message| payload(message).to_string())
+        .collect::<Vec<String>>();
+    payloads.sort();
+    assert_eq!(payloads, ["first", "second", "third"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn commits_reverse_forwarded_records_after_the_broker_acknowledged_them() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let proxy = Proxy::start(mqtt_port);
+    let kafka = Kafka::start();
+    kafka.create_topic("commands");
+    let forwarder = Forwarder::start_with(
+        ConfigBuilder::new(proxy.port).kafka(&kafka, "").section(
+            "reverse_forwarding:\n  - name: commands\n    kafka:\n      topics: [commands]\n      group_id: commands\n\
+             \x20     config:\n        auto.offset.reset: earliest\n        auto.commit.interval.ms: '100'\n\
+             \x20   mqtt:\n      topic: devices/{key}/commands\n",
+        ),
+    )
+    .await;
+    let mut commands = mqtt_subscriber(mqtt_port, "devices/+/commands").await;
+
+    kafka.produce("commands", "d-1", "on").await;
+    let command = tokio::time::timeout(TIMEOUT, commands.recv())
+        .await
+        .unwrap();
+    assert_eq!(
+        command,
+        Some(("devices/d-1/commands".to_string(), "on".to_string()))
+    );
+    kafka
+        .wait_for_committed_offset("commands", "commands", 1)
+        .await;
+
+    // Without a connection to the broker the record is consumed but not acknowledged
+    proxy.disconnect();
+    forwarder.wait_for_status("/health/ready", 503).await;
+    kafka.produce("commands", "d-2", "off").await;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(kafka.committed_offset("commands", "commands"), Some(1));
+    assert_eq!(
+        forwarder.metric("forwarding_reverse_published_total").await,
+        1.0
+    );
+
+    proxy.reconnect();
+    let command = tokio::time::timeout(TIMEOUT, commands.recv())
+        .await
+        .unwrap();
+    assert_eq!(
+        command,
+        Some(("devices/d-2/commands".to_string(), "off".to_string()))
+    );
+    kafka
+        .wait_for_committed_offset("commands", "commands", 2)
+        .await;
+    forwarder
+        .wait_for_metric("forwarding_reverse_published_total", 2.0)
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_the_order_of_messages_with_the_same_key_under_backpressure() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("ordered");
+    // Every message waits for the slow broker before the next one of the same key is sent
+    kafka
+        .cluster
+        .broker_round_trip_time(-1, Duration::from_millis(10))
+        .unwrap();
+    let forwarder = Forwarder::start_with(
+        ConfigBuilder::new(mqtt_port)
+            .kafka(&kafka, "")
+            .section("ordering:\n  mode: strict\n  workers: 4\n  shard_by: kafka_key\n")
+            .forwardings(
+                "  - name: ordered\n    mqtt:\n      topic: ordered/#\n    kafka:\n      topic: ordered\n    key:\n      strategy: fixed\n      value: same\n",
+            ),
+    )
+    .await;
+    let client = mqtt_client(mqtt_port).await;
+
+    let count = 300;
+    for index in 0..count {
+        publish(
+            &client,
+            &format!("ordered/d-{}", index % 5),
+            index.to_string(),
+        )
+        .await;
+    }
+    // The queue of the worker holds 100 messages, the forwarder stops reading from MQTT while it is full
+    let started = Instant::now();
+    loop {
+        let acks = forwarder.metric("forwarding_mqtt_acks_total").await;
+        let received = forwarder.metric("forwarding_mqtt_received_total").await;
+        assert!(
+            received - acks <= 110.0,
+            "{received} messages received but only {acks} acknowledged"
+        );
+        if acks == count as f64 {
+            break;
+        }
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "Only {acks} messages acknowledged"
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let messages = kafka.consume("ordered", count).await;
+    assert_eq!(
+        messages.iter().map(payload).collect::<Vec<&str>>(),
+        (0..count)
+            .map(|index| index.to_string())
+            .collect::<Vec<String>>()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reloads_the_config_on_sighup() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let kafka = Kafka::start();
+    kafka.create_topic("before");
+    kafka.create_topic("after");
+    let config = |topic: &str, kafka_settings: &str| {
+        ConfigBuilder::new(mqtt_port)
+            .kafka(&kafka, kafka_settings)
+            .forwardings(&format!(
+                "  - name: {topic}\n    mqtt:\n      topic: {topic}/#\n    kafka:\n      topic: {topic}\n"
+            ))
+    };
+    let forwarder = Forwarder::start_with(config("before", "")).await;
+    let client = mqtt_client(mqtt_port).await;
+    publish(&client, "before/device", "first").await;
+    kafka.consume("before", 1).await;
+
+    // A duplicate forwarding rejects the whole reload
+    forwarder.reload(config("before", "").forwardings(
+        "  - name: before\n    mqtt:\n      topic: other/#\n    kafka:\n      topic: after\n",
+    ));
+    forwarder
+        .wait_for_metric("forwarding_config_reload_failed", 1.0)
+        .await;
+    publish(&client, "before/device", "second").await;
+    kafka.consume("before", 2).await;
+
+    // The new forwarding and kafka producer are used, the metrics section is ignored
+    forwarder.reload(
+        config("after", "  config:\n    client.id: reloaded\n")
+            .section("metrics:\n  topic_labels: true\n"),
+    );
+    forwarder
+        .wait_for_metric("forwarding_config_reload_failed", 0.0)
+        .await;
+    publish(&client, "before/device", "unsubscribed").await;
+    publish(&client, "after/device", "third").await;
+    let messages = kafka.consume("after", 1).await;
+    assert_eq!(payload(&messages[0]), "third");
+    forwarder
+        .wait_for_metric("forwarding_kafka_published_total", 3.0)
+        .await;
+    assert!(forwarder
+        .logs()
+        .contains("Changes to the metrics section require a restart and are ignored"));
+}
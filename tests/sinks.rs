@@ -0,0 +1,228 @@
+mod common;
+
+use axum::{extract::State, http::StatusCode, routing::post, Router};
+use common::{
+    free_port, mqtt_client, next_outcome, publish, start_mqtt_broker, ConfigBuilder, TIMEOUT,
+};
+use mqtt_kafka_forwarding_rust::{
+    ComponentHealth, Config, Forwarder, ProduceOutcome, Sink, SinkFuture, SinkMessage, Status,
+};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// Config without a kafka section
+fn config(mqtt_port: u16, sinks: &str, forwardings: &str) -> Config {
+    ConfigBuilder::new(mqtt_port)
+        .section(&format!("sinks:\n{sinks}"))
+        .forwardings(forwardings)
+        .build()
+}
+
+// Takes a while to deliver a message and fails for the payload `fail`
+struct SlowSink {
+    started: mpsc::UnboundedSender<()>,
+}
+
+impl Sink for SlowSink {
+    fn produce<'a>(&'a self, message: &'a SinkMessage<'a>) -> SinkFuture<'a, ProduceOutcome> {
+        Box::pin(async move {
+            if message.payload == b"fail" {
+                panic!("Could not deliver message");
+            }
+            let _ = self.started.send(());
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            ProduceOutcome::Delivered
+        })
+    }
+
+    fn flush(&self) -> SinkFuture<'_, Result<(), String>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn health(&self) -> SinkFuture<'_, ComponentHealth> {
+        Box::pin(async {
+            ComponentHealth {
+                status: Status::Up,
+                details: json!({}),
+            }
+        })
+    }
+}
+
+async fn slow_forwarder(mqtt_port: u16) -> (Forwarder, mpsc::UnboundedReceiver<()>) {
+    let (started, receiver) = mpsc::unbounded_channel();
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        "",
+        "  - name: slow\n    mqtt:\n      topic: slow/#\n    kafka:\n      topic: slow\n    sink: slow\n",
+    ))
+    .sink("slow", Arc::new(SlowSink { started }))
+    .without_api()
+    .start()
+    .await;
+    (forwarder, receiver)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_messages_to_a_rotating_file() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let dir = std::env::temp_dir().join(format!("forwarder-sink-{}", free_port()));
+    let path = dir.join("messages.jsonl");
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        &format!(
+            "  - name: archive\n    type: file\n    path: {}\n    max_size: 150\n    max_files: 1\n",
+            path.display()
+        ),
+        "  - name: files\n    mqtt:\n      topic: files/#\n    kafka:\n      topic: files\n    sink: archive\n",
+    ))
+    .without_api()
+    .start()
+    .await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    for payload in ["first", "second", "third"] {
+        publish(&client, "files/device", payload).await;
+        assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    }
+
+    let lines = |path: &std::path::Path| {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<Value>>()
+    };
+    let current = lines(&path);
+    let rotated = lines(&dir.join("messages.jsonl.1"));
+    assert_eq!(current.len() + rotated.len(), 2, "oldest file is removed");
+    assert_eq!(current.last().unwrap()["payload"], "third");
+    assert_eq!(current.last().unwrap()["topic"], "files");
+    assert_eq!(current.last().unwrap()["mqtt_topic"], "files/device");
+    assert_eq!(current.last().unwrap()["key"], "files/device");
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn posts_messages_to_a_webhook() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let app = Router::new()
+        .route(
+            "/messages",
+            post(
+                |State(received): State<Arc<Mutex<Vec<Value>>>>, body: String| async move {
+                    let message: Value = serde_json::from_str(&body).unwrap();
+                    if message["payload"] == "invalid" {
+                        return StatusCode::BAD_REQUEST;
+                    }
+                    received.lock().unwrap().push(message);
+                    StatusCode::OK
+                },
+            ),
+        )
+        .with_state(received.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        &format!(
+            "  - name: webhook\n    type: http\n    url: http://127.0.0.1:{port}/messages\n"
+        ),
+        "  - name: hooks\n    mqtt:\n      topic: hooks/#\n    kafka:\n      topic: hooks\n    sink: webhook\n",
+    ))
+    .without_api()
+    .start()
+    .await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(&client, "hooks/device", "hello").await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    publish(&client, "hooks/device", "invalid").await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Dropped);
+
+    let received = received.lock().unwrap().clone();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["payload"], "hello");
+    assert_eq!(received[0]["forwarding"], "hooks");
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn forwards_only_allowed_kafka_topics() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let dir = std::env::temp_dir().join(format!("forwarder-allowed-{}", free_port()));
+    let path = dir.join("messages.jsonl");
+    let forwarder = Forwarder::builder(config(
+        mqtt_port,
+        &format!("  - name: archive\n    type: file\n    path: {}\n", path.display()),
+        "  - name: static\n    mqtt:\n      topic: static/#\n    kafka:\n      topic: static\n      allowed_topics: []\n    sink: archive\n\
+         \x20 - name: templated\n    mqtt:\n      topic: devices/+/data\n    kafka:\n      topic: iot.{1}\n      allowed_topics: ['iot.sensor-*']\n    sink: archive\n",
+    ))
+    .without_api()
+    .start()
+    .await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    // An empty list allows every topic
+    publish(&client, "static/device", "first").await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    publish(&client, "devices/sensor-1/data", "second").await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    // Not allowed, not forwarded
+    publish(&client, "devices/camera-1/data", "third").await;
+    publish(&client, "devices/sensor-2/data", "fourth").await;
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    forwarder.stop();
+    forwarder.stopped().await.unwrap();
+
+    let topics = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["topic"].clone())
+        .collect::<Vec<Value>>();
+    assert_eq!(topics, ["static", "iot.sensor-1", "iot.sensor-2"]);
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn forwards_received_messages_before_stopping() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let (forwarder, mut started) = slow_forwarder(mqtt_port).await;
+    let mut events = forwarder.subscribe();
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(&client, "slow/device", "hello").await;
+    tokio::time::timeout(TIMEOUT, started.recv())
+        .await
+        .expect("Message was not produced");
+    forwarder.stop();
+    assert_eq!(next_outcome(&mut events).await, ProduceOutcome::Delivered);
+    tokio::time::timeout(TIMEOUT, forwarder.stopped())
+        .await
+        .expect("Forwarder did not stop")
+        .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stops_with_the_error_of_a_failing_sink() {
+    let (mqtt_port, _) = start_mqtt_broker();
+    let (forwarder, _started) = slow_forwarder(mqtt_port).await;
+    let client = mqtt_client(mqtt_port).await;
+
+    publish(&client, "slow/device", "fail").await;
+    let result = tokio::time::timeout(TIMEOUT, forwarder.stopped())
+        .await
+        .expect("Forwarder did not stop");
+    assert_eq!(result, Err("Could not deliver message".to_string()));
+}